@@ -0,0 +1,106 @@
+//! The download progress bar for the CLI.
+//!
+//! On a terminal the bar redraws itself in place. When stdout is a pipe or a
+//! log file, a plain status line is printed every few seconds instead, so logs
+//! stay readable.
+
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use libvorpal::{Progress, ProgressObserver};
+
+const BAR_WIDTH: usize = 30;
+const BAR_FILLED: char = '#';
+const BAR_EMPTY: char = '-';
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+pub struct ProgressBar {
+    tty: bool,
+    last_log: Mutex<Option<Instant>>,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        ProgressBar { tty: io::stdout().is_terminal(), last_log: Mutex::new(None) }
+    }
+
+    fn draw(&self, progress: &Progress) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\r{}", render_bar(progress));
+        let _ = stdout.flush();
+    }
+
+    fn log(&self, progress: &Progress) {
+        let mut last_log = self.last_log.lock().unwrap_or_else(|e| e.into_inner());
+        let due = last_log.is_none_or(|at| at.elapsed() >= LOG_INTERVAL);
+        if due {
+            *last_log = Some(Instant::now());
+            println!("Vorpal: {}", render_status(progress));
+        }
+    }
+}
+
+impl ProgressObserver for ProgressBar {
+    fn on_progress(&self, progress: &Progress) {
+        match self.tty {
+            true => self.draw(progress),
+            false => self.log(progress),
+        }
+    }
+
+    fn on_finish(&self, progress: &Progress) {
+        match self.tty {
+            true => {
+                self.draw(progress);
+                println!();
+            },
+            false => println!("Vorpal: {}", render_status(progress)),
+        }
+    }
+}
+
+/// [#########---------------------]  30% 1.95 GB / 6.50 GB  24.10 MB/s  ETA 3m 9s
+pub fn render_bar(progress: &Progress) -> String {
+    let bar = match progress.fraction() {
+        Some(fraction) => {
+            let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+            let empty = BAR_WIDTH - filled.min(BAR_WIDTH);
+            format!("[{}{}] {:>3.0}%", BAR_FILLED.to_string().repeat(filled.min(BAR_WIDTH)), BAR_EMPTY.to_string().repeat(empty), fraction * 100.0)
+        },
+        None => format!("[{}]", " ".repeat(BAR_WIDTH)),
+    };
+    format!("{} {}", bar, render_status(progress))
+}
+
+/// 1.95 GB / 6.50 GB  24.10 MB/s  ETA 3m 9s
+pub fn render_status(progress: &Progress) -> String {
+    let size = match progress.total {
+        Some(total) => format!("{} / {}", format_bytes(progress.done as f64), format_bytes(total as f64)),
+        None => format_bytes(progress.done as f64),
+    };
+    let eta = match progress.eta() {
+        Some(eta) => format_duration(eta),
+        None => "?".to_string(),
+    };
+    format!("{}  {}/s  ETA {}", size, format_bytes(progress.bytes_per_sec), eta)
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        s if s >= 3600 => format!("{}h {}m", s / 3600, (s % 3600) / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
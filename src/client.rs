@@ -0,0 +1,464 @@
+//! A reusable client for the Civitai API.
+//!
+//! CivitaiClient owns a pooled reqwest::Client, so connections are reused
+//! across queries and downloads. The base URL can be changed to point vorpal
+//! at a mirror or a local mock server.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use futures_util::{stream, Stream};
+use reqwest::{StatusCode, Url};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION, RANGE};
+
+use crate::download::{self, DownloadOptions};
+use crate::error::Result;
+use crate::filename;
+use crate::hash::sha256_file;
+use crate::search::SearchParams;
+use crate::{ModelFile, ModelVersion, PageMetadata, QueryItem, QueryResponse, VorpalError};
+
+const DEFAULT_BASE_URL: &str = "https://civitai.com";
+const API_MODELS_PATH: &str = "/api/v1/models";
+const API_VERSIONS_PATH: &str = "/api/v1/model-versions";
+const DOWNLOAD_PATH: &str = "/api/download/models/";
+const DEFAULT_USER_AGENT: &str = concat!("vorpal/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
+/// A handle to the Civitai API. Cloning is cheap, and clones share the same
+/// connection pool.
+///
+/// Example:
+///     let client = CivitaiClient::builder()
+///         .base_url("http://localhost:8080")
+///         .connect_timeout(Duration::from_secs(5))
+///         .build()?;
+///     let items = client.get_query_items("cat".to_string(), 10, true).await?;
+pub struct CivitaiClient {
+    http: reqwest::Client,
+    base_url: String,
+    read_timeout: Option<Duration>,
+    api_token: Option<String>,
+}
+
+/// A stream of QueryItems spanning as many pages as needed. See CivitaiClient::query_stream().
+pub type QueryStream = Pin<Box<dyn Stream<Item = Result<QueryItem>> + Send>>;
+
+/// Where query_stream() is in the result set
+struct PageState {
+    client: CivitaiClient,
+    buffer: VecDeque<QueryItem>,
+    next: Option<(String, Vec<(&'static str, String)>)>,
+}
+
+#[derive(Debug, Clone)]
+/// Builder for CivitaiClient. Every option has a default, so
+/// CivitaiClient::builder().build() is equivalent to CivitaiClient::new().
+pub struct CivitaiClientBuilder {
+    base_url: String,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    user_agent: String,
+    headers: Vec<(String, String)>,
+    api_token: Option<String>,
+}
+
+impl Default for CivitaiClientBuilder {
+    fn default() -> Self {
+        CivitaiClientBuilder {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            connect_timeout: None,
+            read_timeout: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Vec::new(),
+            api_token: None,
+        }
+    }
+}
+
+impl CivitaiClientBuilder {
+    /// Root URL of the Civitai instance, without the /api suffix.
+    /// Defaults to https://civitai.com
+    pub fn base_url(mut self, url: impl Into<String>) -> Self {
+        self.base_url = url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// How long to wait for a connection to be established
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait for an API response, or for the next chunk of a download
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn user_agent(mut self, agent: impl Into<String>) -> Self {
+        self.user_agent = agent.into();
+        self
+    }
+
+    /// Add a header that is sent with every request
+    pub fn default_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Civitai API key, sent as a bearer token with API and download requests.
+    /// The token is only sent to the base URL, never to third party hosts.
+    pub fn api_token(mut self, token: impl Into<String>) -> Self {
+        self.api_token = Some(token.into());
+        self
+    }
+
+    /// Errors:
+    ///     - If a header name or value is invalid
+    ///     - If the underlying HTTP client cannot be initialized
+    pub fn build(self) -> Result<CivitaiClient> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| VorpalError::Client(format!("{}: {}", name, e)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| VorpalError::Client(format!("{}: {}", name, e)))?;
+            headers.insert(name, value);
+        }
+        let mut builder = reqwest::Client::builder()
+            .user_agent(self.user_agent)
+            .default_headers(headers);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        let http = builder.build()
+            .map_err(|e| VorpalError::Client(e.to_string()))?;
+        Ok(CivitaiClient {
+            http,
+            base_url: self.base_url,
+            read_timeout: self.read_timeout,
+            api_token: self.api_token,
+        })
+    }
+}
+
+impl CivitaiClient {
+    /// A client for https://civitai.com with default settings
+    pub fn new() -> Result<CivitaiClient> {
+        CivitaiClientBuilder::default().build()
+    }
+
+    pub fn builder() -> CivitaiClientBuilder {
+        CivitaiClientBuilder::default()
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Start a GET request, adding the API token if the url belongs to Civitai
+    fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        let request = self.http.get(url);
+        match &self.api_token {
+            Some(token) if self.is_own_url(url) => request.bearer_auth(token),
+            _ => request,
+        }
+    }
+
+    /// Whether url has the same scheme, host and port as the base url. Only then
+    /// is it safe to send the API token to it.
+    fn is_own_url(&self, url: &str) -> bool {
+        let (Ok(url), Ok(base)) = (Url::parse(url), Url::parse(&self.base_url)) else { return false };
+        url.scheme() == base.scheme()
+            && url.host_str().is_some_and(|host| base.host_str().is_some_and(|base| host.eq_ignore_ascii_case(base)))
+            && url.port_or_known_default() == base.port_or_known_default()
+    }
+
+    async fn get_raw_civitai_json(&self, url: &str, params: &[(&str, String)]) -> Result<String> {
+        let mut request = self.get(url).query(params);
+        if let Some(timeout) = self.read_timeout {
+            request = request.timeout(timeout);
+        }
+        let res = request.send().await.map_err(VorpalError::Connection)?;
+        let res = check_status(res)?;
+        res.text().await.map_err(VorpalError::Connection)
+    }
+
+    async fn get_query_page(&self, url: &str, params: &[(&str, String)]) -> Result<QueryResponse> {
+        let raw = self.get_raw_civitai_json(url, params).await?;
+        parse_civitai_json(&raw)
+    }
+
+    fn models_url(&self) -> String {
+        format!("{}{}", self.base_url, API_MODELS_PATH)
+    }
+
+    /// Query Civitai for models. Returns a Vector of QueryItems
+    /// Only one page is fetched, so at most 100 items are returned. Use query_stream()
+    /// for more.
+    /// Args:
+    ///     search - the keyword to query
+    ///     count - the amount of results to display
+    ///     safe - enter query as 'safe'
+    ///
+    /// Errors:
+    ///     - If Civitai cannot be reached or responds with an error status
+    ///     - If the response is not valid JSON
+    ///     - If the query has no results
+    pub async fn get_query_items(&self, search: String, count: u8, safe: bool) -> Result<Vec<QueryItem>> {
+        self.search(&SearchParams::new().query(search).limit(count).safe(safe)).await
+    }
+
+    /// Search Civitai for models matching a set of filters. Only the first page is fetched.
+    ///
+    /// Errors:
+    ///     - If Civitai cannot be reached or responds with an error status
+    ///     - If the response is not valid JSON
+    ///     - If the search has no results
+    pub async fn search(&self, params: &SearchParams) -> Result<Vec<QueryItem>> {
+        let items = self.get_query_page(&self.models_url(), &params.to_query_pairs()).await?.get_items();
+        if items.is_empty() { return Err(VorpalError::NoResults) }
+        Ok(items)
+    }
+
+    /// Query Civitai for models, following the next page cursor until the results run out.
+    /// This is search_stream() for a plain keyword search.
+    /// Args:
+    ///     search - the keyword to query
+    ///     page_size - how many items to request per page (at most 100)
+    ///     safe - enter query as 'safe'
+    ///
+    /// Example:
+    ///     let items: Vec<QueryItem> = client.query_stream("cat".to_string(), 100, true)
+    ///         .take(1000)
+    ///         .try_collect()
+    ///         .await?;
+    pub fn query_stream(&self, search: String, page_size: u8, safe: bool) -> QueryStream {
+        self.search_stream(&SearchParams::new().query(search).limit(page_size).safe(safe))
+    }
+
+    /// Search Civitai for models matching a set of filters, following the next page cursor
+    /// until the results run out. Pages are fetched lazily as the stream is polled, so use
+    /// take() to bound the search. An error ends the stream after it is yielded.
+    pub fn search_stream(&self, params: &SearchParams) -> QueryStream {
+        let state = PageState {
+            client: self.clone(),
+            buffer: VecDeque::new(),
+            next: Some((self.models_url(), params.to_query_pairs())),
+        };
+        Box::pin(stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(item) = state.buffer.pop_front() {
+                    return Some((Ok(item), state));
+                }
+                let (url, params) = state.next.take()?;
+                let page = match state.client.get_query_page(&url, &params).await {
+                    Ok(page) => page,
+                    Err(e) => return Some((Err(e), state)),
+                };
+                state.next = next_page(&page.metadata, &url, &params);
+                if page.items.is_empty() { state.next = None }
+                state.buffer.extend(page.items);
+            }
+        }))
+    }
+
+    /// Fetch a single model (with all of its versions) by the model Id.
+    /// This is the number after /models/ in a Civitai model page url.
+    ///
+    /// Errors:
+    ///     - If Civitai cannot be reached or responds with an error status
+    ///     - If there is no model with that Id
+    ///     - If the response is not valid JSON
+    pub async fn get_model(&self, id: u32) -> Result<QueryItem> {
+        let url = format!("{}{}/{}", self.base_url, API_MODELS_PATH, id);
+        let raw = self.get_raw_civitai_json(&url, &[]).await?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    /// Fetch a single model version by its Id. This is the Id used in download links,
+    /// and the modelVersionId in a Civitai model page url.
+    ///
+    /// Errors:
+    ///     - If Civitai cannot be reached or responds with an error status
+    ///     - If there is no version with that Id
+    ///     - If the response is not valid JSON
+    pub async fn get_model_version(&self, id: u32) -> Result<ModelVersion> {
+        let url = format!("{}{}/{}", self.base_url, API_VERSIONS_PATH, id);
+        let raw = self.get_raw_civitai_json(&url, &[]).await?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    /// Find the model version a file belongs to, given its hash. Civitai accepts
+    /// SHA256, AutoV2, BLAKE3 and CRC32 hashes. The parent model's name and type
+    /// are included in the returned version.
+    ///
+    /// Errors:
+    ///     - If Civitai cannot be reached or responds with an error status
+    ///     - If no file on Civitai has that hash
+    ///     - If the response is not valid JSON
+    pub async fn get_model_version_by_hash(&self, hash: &str) -> Result<ModelVersion> {
+        let url = format!("{}{}/by-hash/{}", self.base_url, API_VERSIONS_PATH, hash);
+        let raw = self.get_raw_civitai_json(&url, &[]).await?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    /// Find the model version a local file belongs to, by hashing it (SHA256)
+    /// and looking the hash up on Civitai.
+    ///
+    /// Errors:
+    ///     - If the file cannot be read
+    ///     - Any error from get_model_version_by_hash()
+    pub async fn get_model_version_by_file(&self, path: &Path) -> Result<ModelVersion> {
+        let owned = path.to_path_buf();
+        let hash = tokio::task::spawn_blocking(move || sha256_file(&owned))
+            .await
+            .map_err(|e| VorpalError::FileRead {
+                path: path.display().to_string(),
+                source: std::io::Error::other(e),
+            })??;
+        self.get_model_version_by_hash(&hash).await
+    }
+
+    /// Find only the url of the first model from a Civitai query
+    /// The most recent model version and file will be used
+    pub async fn get_model_file_url(&self, search: String) -> Result<String> {
+        let count = 1;
+        let safe = false;
+        let queryitem = &self.get_query_items(search, count, safe).await?[0];
+        queryitem.get_download_url()
+    }
+
+    /// Return the first query item from a Civitai query. Intended for quickly downloading
+    /// a model from a simple search.
+    /// Args:
+    ///     search - The search term to get models from Civitai
+    ///     safe - Enter search as 'safe' (no NSFW)
+    pub async fn get_first_query_item(&self, search: String, safe: bool) -> Result<QueryItem> {
+        let count = 1;
+        let query = self.get_query_items(search, count, safe).await?;
+        Ok(query[0].clone())
+    }
+
+    /// Download a Civitai model given the Id (of the model version).
+    /// The download URL is built from the client's base URL.
+    ///
+    /// Errors:
+    ///     - If reqwest cannot establish connection
+    ///     - If the server responds with an error status
+    ///     - If the connection drops, or the file cannot be written (the .part file and
+    ///       its .part.json are kept so the download can be resumed)
+    ///     - If the finished file cannot be renamed into place
+    pub async fn download_civitai_model_by_id(&self, id: String, path: String) -> Result<()> {
+        let url = format!("{}{}{}", self.base_url, DOWNLOAD_PATH, id);
+        self.download_file_by_url(url, path).await
+    }
+
+    /// Download a file given a url and path.
+    /// The file is downloaded to <path>.part first and renamed when complete. If a .part
+    /// file from an earlier, interrupted download exists, only the rest of the file is
+    /// requested (when the server supports it). <path>.part.json records what the server
+    /// said about the file, so a changed file is not resumed.
+    ///
+    /// Errors:
+    ///     - If reqwest cannot establish connection
+    ///     - If the server responds with an error status
+    ///     - If the file needs an API token and none (or a bad one) was given
+    ///     - If the server sends a web page (Content-Type text/html) instead of a file
+    ///     - If the connection drops, a chunk takes longer than the read timeout to
+    ///       arrive, or the file cannot be written (the .part file and its .part.json
+    ///       are kept so the download can be resumed, unless it was downloaded in
+    ///       segments)
+    ///     - If the finished file cannot be renamed into place
+    pub async fn download_file_by_url(&self, url: String, path: String) -> Result<()> {
+        self.download_file_with_options(url, path, &DownloadOptions::default()).await?;
+        Ok(())
+    }
+
+    /// Download a file given a url and path, as download_file_by_url() does.
+    /// Returns the SHA256 of the downloaded file.
+    ///
+    /// Errors:
+    ///     - Any error from download_file_by_url()
+    ///     - If options has an expected SHA256 and the file does not match it
+    pub async fn download_file_with_options(&self, url: String, path: String, options: &DownloadOptions) -> Result<String> {
+        download::download(|| self.get(&url), &url, Path::new(&path), self.read_timeout, options).await
+    }
+
+    /// Download a file into dir, named as the server's Content-Disposition header says,
+    /// or else after the last segment of the (redirected) url. The name is sanitized,
+    /// so the file always ends up directly in dir. Returns where the file was saved.
+    ///
+    /// Errors:
+    ///     - Any error from download_file_with_options()
+    pub async fn download_file_to_dir(&self, url: String, dir: &Path, options: &DownloadOptions) -> Result<PathBuf> {
+        // Only the headers are wanted, so ask for as little of the body as possible
+        let res = self.get(&url).header(RANGE, "bytes=0-0").send().await.map_err(VorpalError::Fetch)?;
+        let res = match res.status() {
+            StatusCode::RANGE_NOT_SATISFIABLE => res,
+            _ => check_status(res)?,
+        };
+        let name = res.headers().get(CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(filename::from_content_disposition)
+            .or_else(|| filename::from_url(res.url().as_str()))
+            .unwrap_or_else(|| filename::DEFAULT_FILENAME.to_string());
+        drop(res);
+
+        let path = dir.join(name);
+        self.download_file_with_options(url, path.display().to_string(), options).await?;
+        Ok(path)
+    }
+
+    /// Download a ModelFile, checking it against the SHA256 Civitai published for it.
+    /// The file must also be about the size Civitai reports, and in the format it
+    /// reports (or its extension suggests).
+    /// Files without a published hash are downloaded unchecked. Pickle files are
+    /// scanned for dangerous imports before they are moved into place.
+    /// Returns the SHA256 of the downloaded file.
+    ///
+    /// Errors:
+    ///     - Any error from download_file_with_options()
+    ///     - If the file is not the size or format Civitai reports
+    ///     - If the file is a pickle that imports code outside pickle::ALLOWED_IMPORTS
+    pub async fn download_model_file(&self, file: &ModelFile, path: String, options: &DownloadOptions) -> Result<String> {
+        let mut options = options.clone()
+            .expected_size(file.get_size_bytes())
+            .scan_pickle(file.is_pickle());
+        if let Some(sha256) = file.get_sha256() {
+            options = options.expected_sha256(sha256);
+        }
+        if let Some(format) = file.get_format() {
+            options = options.expected_format(format);
+        }
+        self.download_file_with_options(file.get_url(), path, &options).await
+    }
+}
+
+/// Work out where the page after this one is. A cursor is preferred, since it keeps
+/// requests going to the client's base url; otherwise the page url Civitai gave is used.
+fn next_page(metadata: &PageMetadata, url: &str, params: &[(&'static str, String)]) -> Option<(String, Vec<(&'static str, String)>)> {
+    if let Some(cursor) = &metadata.next_cursor {
+        let mut params: Vec<_> = params.iter().filter(|(k, _)| *k != "cursor").cloned().collect();
+        params.push(("cursor", cursor.clone()));
+        return Some((url.to_string(), params));
+    }
+    metadata.next_page.clone().map(|page| (page, Vec::new()))
+}
+
+fn parse_civitai_json(raw: &str) -> Result<QueryResponse> {
+    Ok(serde_json::from_str(raw)?)
+}
+
+pub(crate) fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+    let url = res.url().to_string();
+    match status {
+        s if s.is_success() => Ok(res),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(VorpalError::AuthRequired { url }),
+        StatusCode::NOT_FOUND => Err(VorpalError::NotFound { url }),
+        s => Err(VorpalError::HttpStatus { status: s.as_u16(), url }),
+    }
+}
@@ -0,0 +1,331 @@
+//! The vorpal configuration file.
+//!
+//! Settings live in a TOML file at the platform config directory, which is
+//! $XDG_CONFIG_HOME/vorpal/config.toml (usually ~/.config/vorpal/config.toml)
+//! on Linux. A missing file is not an error; every setting is optional.
+//!
+//! Settings at the top of the file are the defaults. A `[profiles.<name>]` table
+//! holds the same settings, and overrides the defaults when that profile is
+//! picked:
+//!
+//!     let config = Config::load()?;
+//!     let profile = config.get_profile(Some("comfy-workstation"))?;
+//!
+//! set_value, get_value and list_values edit and read the file itself, keeping
+//! its comments and layout.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use toml_edit::{DocumentMut, Item, Table};
+
+use crate::error::Result;
+use crate::filename::{FilenameTemplate, OnCollision};
+use crate::layout::Layout;
+use crate::{FileFormat, VorpalError};
+
+const CONFIG_DIR: &str = "vorpal";
+const CONFIG_FILE: &str = "config.toml";
+const PROFILES_KEY: &str = "profiles";
+/// Settings that hold true or false
+const BOOL_KEYS: [&str; 1] = ["safe"];
+/// Settings that hold a number
+const INTEGER_KEYS: [&str; 1] = ["jobs"];
+/// Settings that hold a string
+const STRING_KEYS: [&str; 9] = [
+    "directory", "layout", "api_token", "format", "precision", "size",
+    "filename_template", "on_collision", "file_type",
+];
+/// Owner read and write, since the file may hold the API token
+#[cfg(unix)]
+const PRIVATE_MODE: u32 = 0o600;
+/// Settings that map names to strings, set one entry at a time (aliases.sdxl)
+const TABLE_KEYS: [&str; 2] = ["type_dirs", "aliases"];
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+/// One set of settings: the defaults, or a profile.
+///
+/// Example:
+///     directory = "~/stable-diffusion-webui"
+///     layout = "webui"
+///     safe = true
+///     api_token = "0123456789abcdef"
+///     jobs = 8
+///     format = "safetensors"
+///     precision = "fp16"
+///     filename_template = "{model}-{version}-{versionId}.{ext}"
+///     on_collision = "rename"
+///
+///     [type_dirs]
+///     DoRA = "models/Lora"
+///
+///     [aliases]
+///     sdxl = "~/stable-diffusion-webui/models/Stable-diffusion/sdxl"
+pub struct Profile {
+    /// Where to download to, as given to --directory
+    pub directory: Option<String>,
+    /// Folder structure to sort downloads into by model type
+    pub layout: Option<Layout>,
+    /// Leave NSFW models out of searches
+    pub safe: Option<bool>,
+    /// Civitai API key, sent as a bearer token
+    pub api_token: Option<String>,
+    /// How many downloads to run at once
+    pub jobs: Option<usize>,
+    /// Preferred file format, precision, size and type, as --format, --precision,
+    /// --size and --file-type
+    pub format: Option<FileFormat>,
+    pub precision: Option<String>,
+    pub size: Option<String>,
+    pub file_type: Option<String>,
+    /// How to name downloaded files
+    pub filename_template: Option<FilenameTemplate>,
+    /// What to do when a download would replace an existing file
+    pub on_collision: Option<OnCollision>,
+    /// Folders for model types, overriding the layout's
+    pub type_dirs: BTreeMap<String, PathBuf>,
+    /// Names for download directories, used as -d @name
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl Profile {
+    /// These settings, with every setting that over has laid on top. Map entries
+    /// are merged one by one.
+    pub fn merge(mut self, over: &Profile) -> Profile {
+        fn pick<T: Clone>(base: Option<T>, over: &Option<T>) -> Option<T> {
+            over.clone().or(base)
+        }
+        self.directory = pick(self.directory, &over.directory);
+        self.layout = pick(self.layout, &over.layout);
+        self.safe = pick(self.safe, &over.safe);
+        self.api_token = pick(self.api_token, &over.api_token);
+        self.jobs = pick(self.jobs, &over.jobs);
+        self.format = pick(self.format, &over.format);
+        self.precision = pick(self.precision, &over.precision);
+        self.size = pick(self.size, &over.size);
+        self.file_type = pick(self.file_type, &over.file_type);
+        self.filename_template = pick(self.filename_template, &over.filename_template);
+        self.on_collision = pick(self.on_collision, &over.on_collision);
+        self.type_dirs.extend(over.type_dirs.clone());
+        self.aliases.extend(over.aliases.clone());
+        self
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+/// Settings read from config.toml: the defaults, and any named profiles.
+///
+/// Example:
+///     layout = "webui"
+///     directory = "~/stable-diffusion-webui"
+///
+///     [profiles.comfy-workstation]
+///     layout = "comfyui"
+///     directory = "/opt/ComfyUI"
+///     jobs = 8
+pub struct Config {
+    #[serde(flatten)]
+    pub defaults: Profile,
+    pub profiles: BTreeMap<String, Profile>,
+    /// Where the config was loaded from, to point to in errors
+    #[serde(skip)]
+    source: Option<PathBuf>,
+}
+
+impl Config {
+    /// Location of the config file, if the platform has a config directory
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    /// Load the config file from its default location.
+    /// Returns the default Config if the file does not exist.
+    pub fn load() -> Result<Config> {
+        match Config::path() {
+            Some(path) => Config::load_from(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Load a config file from a specific path.
+    /// Returns the default Config if the file does not exist.
+    ///
+    /// Errors:
+    ///     - If the file exists but cannot be read
+    ///     - If the file is not valid TOML, or has a setting of the wrong type
+    pub fn load_from(path: &Path) -> Result<Config> {
+        let raw = match fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(config_error(path, e)),
+        };
+        let mut config: Config = toml::from_str(&raw).map_err(|e| config_error(path, e))?;
+        config.source = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// The settings to use: the defaults, overridden by the named profile if there is one
+    ///
+    /// Errors:
+    ///     - VorpalError::Config if there is no profile by that name
+    pub fn get_profile(&self, name: Option<&str>) -> Result<Profile> {
+        let Some(name) = name else { return Ok(self.defaults.clone()) };
+        match self.profiles.get(name) {
+            Some(profile) => Ok(self.defaults.clone().merge(profile)),
+            None => {
+                let path = self.source.clone().unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+                let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                Err(config_error(&path, format!("There is no profile named '{}'. Profiles: {}", name, known.join(", "))))
+            },
+        }
+    }
+}
+
+/// Set key to value in the config file at path, in the named profile if one is
+/// given. The file is created if it does not exist. Keys are setting names, or
+/// type_dirs.<TYPE> and aliases.<NAME> for single entries.
+///
+/// Errors:
+///     - VorpalError::Config if the key is not a setting, the value is not valid
+///       for it, or the file is not valid TOML
+///     - If the file cannot be read or written
+pub fn set_value(path: &Path, profile: Option<&str>, key: &str, value: &str) -> Result<()> {
+    let mut document = read_document(path)?;
+    let keys = setting_path(profile, key).map_err(|e| config_error(path, e))?;
+    let value = parse_value(key, value).map_err(|e| config_error(path, e))?;
+
+    let (last, tables) = keys.split_last().expect("a setting path is never empty");
+    let mut table = document.as_table_mut();
+    for name in tables {
+        table = table.entry(name)
+            .or_insert_with(|| {
+                let mut table = Table::new();
+                table.set_implicit(true);
+                Item::Table(table)
+            })
+            .as_table_mut()
+            .ok_or_else(|| config_error(path, format!("'{}' is not a table", name)))?;
+    }
+    table.insert(last, Item::Value(value));
+
+    // Check the edited file before saving it, so a bad value cannot break the config
+    let edited = document.to_string();
+    toml::from_str::<Config>(&edited).map_err(|e| config_error(path, e))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|source| VorpalError::FileCreate { path: dir.display().to_string(), source })?;
+    }
+    write_private(path, edited.as_bytes()).map_err(|source| VorpalError::FileWrite { path: path.display().to_string(), source })
+}
+
+/// Write contents to path, readable only by its owner, since the config may hold
+/// the API token. A file that is already stricter than that stays so.
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let mode = fs::metadata(path).map(|m| m.permissions().mode() & PRIVATE_MODE).unwrap_or(PRIVATE_MODE);
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)?;
+    // mode only applies to new files, so an existing one is tightened here, while it is empty
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+/// The value of key in the config file at path, in the named profile if one is
+/// given, or None if it is not set. Strings are returned without quotes.
+///
+/// Errors:
+///     - VorpalError::Config if the key is not a setting, or the file is not valid TOML
+///     - If the file cannot be read
+pub fn get_value(path: &Path, profile: Option<&str>, key: &str) -> Result<Option<String>> {
+    let document = read_document(path)?;
+    let keys = setting_path(profile, key).map_err(|e| config_error(path, e))?;
+    let mut item = document.as_item();
+    for name in &keys {
+        match item.get(name) {
+            Some(next) => item = next,
+            None => return Ok(None),
+        }
+    }
+    Ok(item.as_value().map(|value| match value.as_str() {
+        Some(s) => s.to_string(),
+        None => value.to_string().trim().to_string(),
+    }))
+}
+
+/// Every setting in the config file at path, as dotted keys and TOML values,
+/// in the order they are in the file
+///
+/// Errors:
+///     - VorpalError::Config if the file is not valid TOML
+///     - If the file cannot be read
+pub fn list_values(path: &Path) -> Result<Vec<(String, String)>> {
+    fn walk(table: &Table, prefix: &str, values: &mut Vec<(String, String)>) {
+        for (key, item) in table.iter() {
+            let key = format!("{}{}", prefix, key);
+            match item {
+                Item::Table(table) => walk(table, &format!("{}.", key), values),
+                Item::Value(value) => values.push((key, value.to_string().trim().to_string())),
+                _ => (),
+            }
+        }
+    }
+    let mut values = Vec::new();
+    walk(read_document(path)?.as_table(), "", &mut values);
+    Ok(values)
+}
+
+/// The config file as an editable document. A missing file is an empty document.
+fn read_document(path: &Path) -> Result<DocumentMut> {
+    let raw = match fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(source) => return Err(VorpalError::FileRead { path: path.display().to_string(), source }),
+    };
+    raw.parse().map_err(|e| config_error(path, e))
+}
+
+/// The table keys leading to key, inside the profile's table if there is one
+fn setting_path(profile: Option<&str>, key: &str) -> std::result::Result<Vec<String>, String> {
+    let parts: Vec<&str> = key.split('.').collect();
+    let known = match parts.as_slice() {
+        [name] => BOOL_KEYS.contains(name) || INTEGER_KEYS.contains(name) || STRING_KEYS.contains(name),
+        [table, entry] => TABLE_KEYS.contains(table) && !entry.is_empty(),
+        _ => false,
+    };
+    if !known {
+        let tables: Vec<String> = TABLE_KEYS.iter().map(|table| format!("{}.<NAME>", table)).collect();
+        let expected = [BOOL_KEYS.as_slice(), INTEGER_KEYS.as_slice(), STRING_KEYS.as_slice()].concat();
+        return Err(format!("Unknown setting '{}'. Expected one of: {}, {}", key, expected.join(", "), tables.join(", ")))
+    }
+    let mut keys: Vec<String> = match profile {
+        Some(profile) => vec![PROFILES_KEY.to_string(), profile.to_string()],
+        None => Vec::new(),
+    };
+    keys.extend(parts.iter().map(|part| part.to_string()));
+    Ok(keys)
+}
+
+/// value as the TOML type key holds
+fn parse_value(key: &str, value: &str) -> std::result::Result<toml_edit::Value, String> {
+    if BOOL_KEYS.contains(&key) {
+        let value: bool = value.parse().map_err(|_| format!("{} must be true or false", key))?;
+        return Ok(value.into())
+    }
+    if INTEGER_KEYS.contains(&key) {
+        let value: i64 = value.parse().map_err(|_| format!("{} must be a number", key))?;
+        return Ok(value.into())
+    }
+    Ok(value.into())
+}
+
+fn config_error(path: &Path, e: impl std::fmt::Display) -> VorpalError {
+    VorpalError::Config { path: path.display().to_string(), message: e.to_string() }
+}
@@ -0,0 +1,727 @@
+//! Convert PyTorch pickle checkpoints (.ckpt, .pt) to safetensors without running them.
+//!
+//! torch.load runs the checkpoint's pickle, which can call anything it names.
+//! Here the pickle is read by a restricted unpickler instead: it only knows the
+//! handful of functions on pickle::ALLOWED_IMPORTS, and never calls any of them.
+//! It only records which storage each tensor is a view of. Any other import is
+//! an error. The tensors are then copied straight out of the zip's storage entries.
+//!
+//!     let metadata = convert::civitai_metadata(&version);
+//!     convert::convert_checkpoint(Path::new("model.ckpt"), Path::new("model.safetensors"), &metadata)?;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use crate::error::Result;
+use crate::pickle::*;
+use crate::safetensors::{encode_header, TensorInfo};
+use crate::{ModelVersion, VorpalError};
+
+const PICKLE_ENTRY: &str = "data.pkl";
+const STORAGE_DIRECTORY: &str = "data/";
+const BYTEORDER_ENTRY: &str = "byteorder";
+const STATE_DICT_KEY: &str = "state_dict";
+const PERSISTENT_STORAGE: &str = "storage";
+/// Matches the safetensors files written by the Python library
+const FORMAT_KEY: &str = "format";
+const FORMAT_PYTORCH: &str = "pt";
+/// Far more opcodes than a checkpoint's pickle has. Each one adds at most one
+/// value, so this also bounds how much memory a crafted pickle can make us use.
+const MAX_PICKLE_OPS: u64 = 10_000_000;
+
+/// Storage classes, as "module.name", and their safetensors dtype and width in bytes
+const STORAGE_DTYPES: [(&str, &str, u64); 10] = [
+    ("torch.HalfStorage", "F16", 2),
+    ("torch.FloatStorage", "F32", 4),
+    ("torch.DoubleStorage", "F64", 8),
+    ("torch.BFloat16Storage", "BF16", 2),
+    ("torch.LongStorage", "I64", 8),
+    ("torch.IntStorage", "I32", 4),
+    ("torch.ShortStorage", "I16", 2),
+    ("torch.CharStorage", "I8", 1),
+    ("torch.ByteStorage", "U8", 1),
+    ("torch.BoolStorage", "BOOL", 1),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+/// A tensor storage the pickle refers to. Its bytes are the zip entry data/<key>.
+struct Storage {
+    key: String,
+    dtype: &'static str,
+    item_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A view into a storage, as rebuilt by torch._utils._rebuild_tensor
+struct Tensor {
+    storage: Storage,
+    /// In elements, not bytes
+    offset: u64,
+    shape: Vec<u64>,
+    stride: Vec<u64>,
+}
+
+// The shapes, strides and offsets come from the file, so the sizes worked out
+// from them are None if they overflow
+impl Tensor {
+    fn numel(&self) -> Option<u64> {
+        self.shape.iter().try_fold(1u64, |numel, size| numel.checked_mul(*size))
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.numel()?.checked_mul(self.storage.item_size)
+    }
+
+    fn is_contiguous(&self) -> bool {
+        let mut expected: u64 = 1;
+        for (size, stride) in self.shape.iter().zip(&self.stride).rev() {
+            if *size != 1 && *stride != expected { return false }
+            match expected.checked_mul(*size) {
+                Some(next) => expected = next,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// One past the last storage element the tensor reads
+    fn storage_end(&self) -> Option<u64> {
+        if self.numel()? == 0 { return Some(self.offset) }
+        self.shape.iter().zip(&self.stride)
+            .try_fold(self.offset.checked_add(1)?, |end, (size, stride)| end.checked_add((size - 1).checked_mul(*stride)?))
+    }
+
+    /// One past the last storage byte the tensor reads
+    fn storage_byte_end(&self) -> Option<u64> {
+        self.storage_end()?.checked_mul(self.storage.item_size)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A value on the unpickler's stack. Anything the converter does not need
+/// to look inside of is an Object. Copying a value never copies what is in it,
+/// so the memo can hand out the same value any number of times.
+enum Value {
+    Mark,
+    None,
+    Int(i64),
+    Str(Rc<str>),
+    /// A tuple, list or dict, by its index in the Arena
+    Container(usize),
+    /// An allowlisted import, as "module.name"
+    Global(String),
+    Storage(Storage),
+    Tensor(Tensor),
+    Object,
+}
+
+#[derive(Debug)]
+enum Container {
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+}
+
+#[derive(Debug, Default)]
+/// Every tuple, list and dict the unpickler builds. The stack and the memo refer
+/// to them by index, so a dict that is memoized and then filled by SETITEMS is
+/// filled wherever it is used, as in Python.
+struct Arena {
+    containers: Vec<Container>,
+}
+
+impl Arena {
+    fn add(&mut self, container: Container) -> Value {
+        self.containers.push(container);
+        Value::Container(self.containers.len() - 1)
+    }
+
+    /// The items of value, if it is a tuple or list
+    fn items(&self, value: &Value) -> Option<&[Value]> {
+        match value {
+            Value::Container(id) => match &self.containers[*id] {
+                Container::Tuple(items) | Container::List(items) => Some(items),
+                Container::Dict(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The entries of value, if it is a dict
+    fn dict(&self, value: &Value) -> Option<&[(Value, Value)]> {
+        match value {
+            Value::Container(id) => match &self.containers[*id] {
+                Container::Dict(entries) => Some(entries),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn list_mut(&mut self, value: Option<&Value>) -> Option<&mut Vec<Value>> {
+        match value {
+            Some(Value::Container(id)) => match &mut self.containers[*id] {
+                Container::List(items) => Some(items),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn dict_mut(&mut self, value: Option<&Value>) -> Option<&mut Vec<(Value, Value)>> {
+        match value {
+            Some(Value::Container(id)) => match &mut self.containers[*id] {
+                Container::Dict(entries) => Some(entries),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Convert the PyTorch zip checkpoint at src to a safetensors file at dest,
+/// returning how many tensors were written. metadata is stored in the header,
+/// next to "format": "pt".
+///
+/// Errors:
+///     - VorpalError::Convert if src is not a PyTorch zip checkpoint, imports
+///       anything outside pickle::ALLOWED_IMPORTS, or has no tensors, or if dest
+///       is src
+///     - If src cannot be read or dest cannot be written
+pub fn convert_checkpoint(src: &Path, dest: &Path, metadata: &BTreeMap<String, String>) -> Result<usize> {
+    let src_name = src.display().to_string();
+    let invalid = |message: String| VorpalError::Convert { path: src_name.clone(), message };
+    // Creating dest would truncate the checkpoint before it is read
+    if let (Ok(src_path), Ok(dest_path)) = (fs::canonicalize(src), fs::canonicalize(dest)) {
+        if src_path == dest_path {
+            return Err(invalid(format!("the output {} is the checkpoint itself", dest.display())))
+        }
+    }
+    let file = File::open(src).map_err(|source| VorpalError::FileRead { path: src_name.clone(), source })?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))
+        .map_err(|e| invalid(format!("not a PyTorch zip checkpoint ({}). Older checkpoints cannot be converted.", e)))?;
+
+    let pickle_name = archive.file_names()
+        .filter(|name| name.ends_with(PICKLE_ENTRY))
+        .min_by_key(|name| name.len())
+        .map(str::to_string)
+        .ok_or_else(|| invalid(format!("the zip file has no {}", PICKLE_ENTRY)))?;
+    let prefix = pickle_name.trim_end_matches(PICKLE_ENTRY).to_string();
+
+    if let Ok(mut entry) = archive.by_name(&format!("{}{}", prefix, BYTEORDER_ENTRY)) {
+        let mut byteorder = String::new();
+        entry.read_to_string(&mut byteorder).map_err(|e| invalid(e.to_string()))?;
+        if byteorder.trim() != "little" {
+            return Err(invalid(format!("the tensors are stored {} endian", byteorder.trim())))
+        }
+    }
+
+    let (root, arena) = {
+        let entry = archive.by_name(&pickle_name).map_err(|e| invalid(e.to_string()))?;
+        unpickle(&mut BufReader::new(entry)).map_err(|e| invalid(format!("{}: {}", pickle_name, e)))?
+    };
+    let state_dict = match arena.dict(&root) {
+        Some(entries) => entries.iter()
+            .find(|(key, value)| matches!(key, Value::Str(key) if &**key == STATE_DICT_KEY) && arena.dict(value).is_some())
+            .map(|(_, value)| value)
+            .unwrap_or(&root),
+        None => return Err(invalid("the checkpoint is not a dictionary of tensors".to_string())),
+    };
+    let mut tensors = BTreeMap::new();
+    let mut budget = MAX_PICKLE_OPS;
+    collect_tensors(state_dict, "", &arena, &mut HashSet::new(), &mut budget, &mut tensors)
+        .map_err(|e| invalid(format!("{}: {}", pickle_name, e)))?;
+    if tensors.is_empty() {
+        return Err(invalid("the checkpoint has no tensors".to_string()))
+    }
+
+    let mut header_tensors = Vec::new();
+    let mut offset: u64 = 0;
+    for (name, tensor) in &tensors {
+        let end = tensor.byte_len()
+            .and_then(|len| offset.checked_add(len))
+            .ok_or_else(|| invalid(format!("{} is too large", name)))?;
+        header_tensors.push((name.clone(), TensorInfo {
+            dtype: tensor.storage.dtype.to_string(),
+            shape: tensor.shape.clone(),
+            data_offsets: [offset, end],
+        }));
+        offset = end;
+    }
+    let mut metadata = metadata.clone();
+    metadata.insert(FORMAT_KEY.to_string(), FORMAT_PYTORCH.to_string());
+    let header = encode_header(&header_tensors, &metadata)?;
+
+    let dest_name = dest.display().to_string();
+    let out = File::create(dest).map_err(|source| VorpalError::FileCreate { path: dest_name.clone(), source })?;
+    let mut writer = BufWriter::new(out);
+    let written = (|| -> Result<()> {
+        let write_error = |source| VorpalError::FileWrite { path: dest_name.clone(), source };
+        writer.write_all(&header).map_err(write_error)?;
+        // Tensors sharing a storage are usually next to each other, so keep the last one read
+        let mut storage: Option<(String, Vec<u8>)> = None;
+        for (name, tensor) in &tensors {
+            let entry_name = format!("{}{}{}", prefix, STORAGE_DIRECTORY, tensor.storage.key);
+            if storage.as_ref().is_none_or(|(key, _)| *key != entry_name) {
+                let mut entry = archive.by_name(&entry_name)
+                    .map_err(|_| invalid(format!("{} has no storage {}", name, entry_name)))?;
+                // The entry's size is read from the zip, so it is not trusted to preallocate
+                let mut bytes = Vec::new();
+                entry.read_to_end(&mut bytes).map_err(|e| invalid(format!("{}: {}", entry_name, e)))?;
+                storage = Some((entry_name, bytes));
+            }
+            let bytes = &storage.as_ref().expect("storage was just read").1;
+            if tensor.storage_byte_end().is_none_or(|end| end > bytes.len() as u64) {
+                return Err(invalid(format!("{} reads past the end of its storage", name)))
+            }
+            write_tensor(&mut writer, tensor, bytes).map_err(write_error)?;
+        }
+        writer.flush().map_err(write_error)
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(dest);
+        return Err(e)
+    }
+    Ok(tensors.len())
+}
+
+/// Header metadata describing where a converted model came from
+pub fn civitai_metadata(version: &ModelVersion) -> BTreeMap<String, String> {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("civitai_model_id".to_string(), version.get_model_id());
+    metadata.insert("civitai_version_id".to_string(), version.get_id());
+    metadata.insert("civitai_version_name".to_string(), version.get_name());
+    if let Some(name) = version.get_model_name() {
+        metadata.insert("civitai_model_name".to_string(), name);
+    }
+    if let Some(base_model) = version.get_base_model() {
+        metadata.insert("civitai_base_model".to_string(), base_model);
+    }
+    let trained_words = version.get_trained_words();
+    if !trained_words.is_empty() {
+        metadata.insert("civitai_trained_words".to_string(), trained_words);
+    }
+    metadata
+}
+
+/// Every tensor in a (possibly nested) dictionary, keyed by its dotted path.
+/// A dictionary inside itself is not followed again. budget is how many more
+/// entries may be looked at, since shared dictionaries can nest exponentially.
+///
+/// Errors:
+///     - If the dictionaries have more entries than budget
+fn collect_tensors(
+    value: &Value,
+    prefix: &str,
+    arena: &Arena,
+    open: &mut HashSet<usize>,
+    budget: &mut u64,
+    tensors: &mut BTreeMap<String, Tensor>,
+) -> io::Result<()> {
+    let (Value::Container(id), Some(entries)) = (value, arena.dict(value)) else { return Ok(()) };
+    if !open.insert(*id) { return Ok(()) }
+    for (key, value) in entries {
+        *budget = budget.checked_sub(1).ok_or_else(|| malformed("too many nested entries for a checkpoint"))?;
+        let key = match key {
+            Value::Str(key) => key.to_string(),
+            Value::Int(key) => key.to_string(),
+            _ => continue,
+        };
+        let name = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+        match value {
+            Value::Tensor(tensor) => { tensors.insert(name, tensor.clone()); },
+            Value::Container(_) => collect_tensors(value, &name, arena, open, budget, tensors)?,
+            _ => (),
+        }
+    }
+    open.remove(id);
+    Ok(())
+}
+
+/// Write a tensor's elements in row major order. The tensor must fit in storage
+/// (storage_byte_end is checked first), so none of this can overflow.
+fn write_tensor<W: Write>(writer: &mut W, tensor: &Tensor, storage: &[u8]) -> io::Result<()> {
+    let item = tensor.storage.item_size as usize;
+    let numel = tensor.numel().unwrap_or(0);
+    if tensor.is_contiguous() {
+        let start = tensor.offset as usize * item;
+        return writer.write_all(&storage[start..start + numel as usize * item])
+    }
+    let mut index = vec![0u64; tensor.shape.len()];
+    for _ in 0..numel {
+        let element = tensor.offset + index.iter().zip(&tensor.stride).map(|(i, stride)| i * stride).sum::<u64>();
+        let start = element as usize * item;
+        writer.write_all(&storage[start..start + item])?;
+        for dim in (0..index.len()).rev() {
+            index[dim] += 1;
+            if index[dim] < tensor.shape[dim] { break }
+            index[dim] = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Rebuild the data structure of a checkpoint pickle without calling anything in it
+///
+/// Errors:
+///     - If the pickle imports something outside ALLOWED_IMPORTS
+///     - If the stream ends early, or has an unsupported opcode or impossible stack
+fn unpickle<R: BufRead>(reader: &mut R) -> io::Result<(Value, Arena)> {
+    let mut stack: Vec<Value> = Vec::new();
+    let mut memo: HashMap<u64, Value> = HashMap::new();
+    let mut arena = Arena::default();
+
+    for _ in 0..MAX_PICKLE_OPS {
+        let op = read_u8(reader)?;
+        match op {
+            STOP => return Ok((pop(&mut stack)?, arena)),
+            MARK => stack.push(Value::Mark),
+            POP => { pop(&mut stack)?; },
+            POP_MARK => { pop_mark(&mut stack)?; },
+            DUP => stack.push(top(&stack)?),
+            INT => {
+                let line = read_line(reader)?;
+                stack.push(match line.as_str() {
+                    // True and False, which nothing here looks at
+                    "01" | "00" => Value::Object,
+                    _ => Value::Int(line.parse().map_err(|_| malformed("bad INT"))?),
+                });
+            },
+            LONG => {
+                let line = read_line(reader)?;
+                stack.push(Value::Int(line.trim_end_matches('L').parse().map_err(|_| malformed("bad LONG"))?));
+            },
+            FLOAT => {
+                read_line(reader)?;
+                stack.push(Value::Object);
+            },
+            BININT => stack.push(Value::Int(read_uint(reader, 4)? as u32 as i32 as i64)),
+            BININT1 => stack.push(Value::Int(read_u8(reader)? as i64)),
+            BININT2 => stack.push(Value::Int(read_uint(reader, 2)? as i64)),
+            BINFLOAT => {
+                skip(reader, 8)?;
+                stack.push(Value::Object);
+            },
+            LONG1 => {
+                let len = read_u8(reader)? as u64;
+                stack.push(read_long(reader, len)?);
+            },
+            LONG4 => {
+                let len = read_uint(reader, 4)?;
+                stack.push(read_long(reader, len)?);
+            },
+            NONE => stack.push(Value::None),
+            NEWTRUE | NEWFALSE => stack.push(Value::Object),
+            EMPTY_DICT => stack.push(arena.add(Container::Dict(Vec::new()))),
+            EMPTY_LIST => stack.push(arena.add(Container::List(Vec::new()))),
+            EMPTY_TUPLE => stack.push(arena.add(Container::Tuple(Vec::new()))),
+            EMPTY_SET => stack.push(Value::Object),
+            STRING | UNICODE => {
+                let line = read_line(reader)?;
+                stack.push(Value::Str(line.trim_matches(|c| c == '\'' || c == '"').into()));
+            },
+            SHORT_BINSTRING | SHORT_BINUNICODE => {
+                let len = read_u8(reader)? as u64;
+                stack.push(read_str(reader, len)?);
+            },
+            BINSTRING | BINUNICODE => {
+                let len = read_uint(reader, 4)?;
+                stack.push(read_str(reader, len)?);
+            },
+            BINUNICODE8 => {
+                let len = read_uint(reader, 8)?;
+                stack.push(read_str(reader, len)?);
+            },
+            SHORT_BINBYTES => {
+                let len = read_u8(reader)? as u64;
+                skip(reader, len)?;
+                stack.push(Value::Object);
+            },
+            BINBYTES => {
+                let len = read_uint(reader, 4)?;
+                skip(reader, len)?;
+                stack.push(Value::Object);
+            },
+            BINBYTES8 | BYTEARRAY8 => {
+                let len = read_uint(reader, 8)?;
+                skip(reader, len)?;
+                stack.push(Value::Object);
+            },
+            APPEND => {
+                let item = pop(&mut stack)?;
+                if let Some(list) = arena.list_mut(stack.last()) {
+                    list.push(item);
+                }
+            },
+            APPENDS => {
+                let items = pop_mark(&mut stack)?;
+                if let Some(list) = arena.list_mut(stack.last()) {
+                    list.extend(items);
+                }
+            },
+            SETITEM => {
+                let value = pop(&mut stack)?;
+                let key = pop(&mut stack)?;
+                if let Some(dict) = arena.dict_mut(stack.last()) {
+                    dict.push((key, value));
+                }
+            },
+            SETITEMS => {
+                let items = pop_mark(&mut stack)?;
+                if items.len() % 2 != 0 {
+                    return Err(malformed("SETITEMS with an odd number of items"))
+                }
+                if let Some(dict) = arena.dict_mut(stack.last()) {
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        dict.push((key, value));
+                    }
+                }
+            },
+            ADDITEMS => { pop_mark(&mut stack)?; },
+            // BUILD sets an object's attributes, like an OrderedDict's _metadata
+            BUILD => {
+                pop(&mut stack)?;
+                top(&stack)?;
+            },
+            TUPLE => {
+                let items = pop_mark(&mut stack)?;
+                stack.push(arena.add(Container::Tuple(items)));
+            },
+            TUPLE1 | TUPLE2 | TUPLE3 => {
+                let count = (op - TUPLE1 + 1) as usize;
+                if stack.len() < count || stack[stack.len() - count..].iter().any(|v| matches!(v, Value::Mark)) {
+                    return Err(malformed("stack underflow"))
+                }
+                let items = stack.split_off(stack.len() - count);
+                stack.push(arena.add(Container::Tuple(items)));
+            },
+            LIST => {
+                let items = pop_mark(&mut stack)?;
+                stack.push(arena.add(Container::List(items)));
+            },
+            DICT => {
+                let items = pop_mark(&mut stack)?;
+                let mut dict = Vec::new();
+                let mut items = items.into_iter();
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    dict.push((key, value));
+                }
+                stack.push(arena.add(Container::Dict(dict)));
+            },
+            FROZENSET => {
+                pop_mark(&mut stack)?;
+                stack.push(Value::Object);
+            },
+            GLOBAL => {
+                let module = read_line(reader)?;
+                let name = read_line(reader)?;
+                stack.push(global(&module, &name)?);
+            },
+            STACK_GLOBAL => {
+                let name = pop(&mut stack)?;
+                let module = pop(&mut stack)?;
+                match (module, name) {
+                    (Value::Str(module), Value::Str(name)) => stack.push(global(&module, &name)?),
+                    _ => return Err(malformed("STACK_GLOBAL with names that are not strings")),
+                }
+            },
+            INST => {
+                let module = read_line(reader)?;
+                let name = read_line(reader)?;
+                let callable = global(&module, &name)?;
+                let args = pop_mark(&mut stack)?;
+                stack.push(reduce(callable, &args, &mut arena)?);
+            },
+            OBJ => {
+                let mut args = pop_mark(&mut stack)?;
+                if args.is_empty() {
+                    return Err(malformed("OBJ without a class"))
+                }
+                let callable = args.remove(0);
+                stack.push(reduce(callable, &args, &mut arena)?);
+            },
+            REDUCE | NEWOBJ => {
+                let args = pop(&mut stack)?;
+                let callable = pop(&mut stack)?;
+                let args = arena.items(&args).ok_or_else(|| malformed("call arguments are not a tuple"))?.to_vec();
+                stack.push(reduce(callable, &args, &mut arena)?);
+            },
+            NEWOBJ_EX => {
+                pop(&mut stack)?;
+                let args = pop(&mut stack)?;
+                let callable = pop(&mut stack)?;
+                let args = arena.items(&args).ok_or_else(|| malformed("call arguments are not a tuple"))?.to_vec();
+                stack.push(reduce(callable, &args, &mut arena)?);
+            },
+            BINPERSID => {
+                let pid = pop(&mut stack)?;
+                stack.push(persistent_load(&pid, &arena)?);
+            },
+            GET => {
+                let index = read_line(reader)?.trim().parse().map_err(|_| malformed("bad memo index"))?;
+                stack.push(memo_get(&memo, index)?);
+            },
+            BINGET => {
+                let index = read_u8(reader)? as u64;
+                stack.push(memo_get(&memo, index)?);
+            },
+            LONG_BINGET => {
+                let index = read_uint(reader, 4)?;
+                stack.push(memo_get(&memo, index)?);
+            },
+            PUT => {
+                let index = read_line(reader)?.trim().parse().map_err(|_| malformed("bad memo index"))?;
+                memo.insert(index, top(&stack)?);
+            },
+            BINPUT => {
+                let index = read_u8(reader)? as u64;
+                memo.insert(index, top(&stack)?);
+            },
+            LONG_BINPUT => {
+                let index = read_uint(reader, 4)?;
+                memo.insert(index, top(&stack)?);
+            },
+            MEMOIZE => {
+                let index = memo.len() as u64;
+                memo.insert(index, top(&stack)?);
+            },
+            PROTO => { read_u8(reader)?; },
+            FRAME => { read_uint(reader, 8)?; },
+            _ => return Err(malformed(&format!("unsupported opcode 0x{:02x}", op))),
+        }
+    }
+    Err(malformed("too many opcodes for a checkpoint"))
+}
+
+/// An import, if it is on ALLOWED_IMPORTS
+fn global(module: &str, name: &str) -> io::Result<Value> {
+    let full = format!("{}.{}", module, name);
+    if !ALLOWED_IMPORTS.contains(&full.as_str()) {
+        return Err(malformed(&format!("refusing to import {}", full)))
+    }
+    Ok(Value::Global(full))
+}
+
+/// What calling callable with args would give, for the calls the converter understands
+fn reduce(callable: Value, args: &[Value], arena: &mut Arena) -> io::Result<Value> {
+    let Value::Global(callable) = callable else {
+        return Err(malformed("call of something that is not an import"))
+    };
+    let value = match callable.as_str() {
+        "collections.OrderedDict" => arena.add(Container::Dict(Vec::new())),
+        "torch._utils._rebuild_tensor" | "torch._utils._rebuild_tensor_v2" => rebuild_tensor(args, arena)?,
+        "torch._utils._rebuild_parameter" | "torch._utils._rebuild_parameter_with_state" =>
+            args.first().cloned().ok_or_else(|| malformed("_rebuild_parameter without a tensor"))?,
+        "torch.Size" => match args.first() {
+            Some(size) => size.clone(),
+            None => arena.add(Container::Tuple(Vec::new())),
+        },
+        _ => Value::Object,
+    };
+    Ok(value)
+}
+
+/// (storage, storage_offset, size, stride, ...) to a Tensor
+fn rebuild_tensor(args: &[Value], arena: &Arena) -> io::Result<Value> {
+    let ints = |value: Option<&Value>| -> io::Result<Vec<u64>> {
+        match value.and_then(|value| arena.items(value)) {
+            Some(items) => items.iter()
+                .map(|item| match item {
+                    Value::Int(n) if *n >= 0 => Ok(*n as u64),
+                    _ => Err(malformed("tensor size or stride is not a list of sizes")),
+                })
+                .collect(),
+            None => Err(malformed("tensor size or stride is not a list of sizes")),
+        }
+    };
+    let (Some(Value::Storage(storage)), Some(Value::Int(offset))) = (args.first(), args.get(1)) else {
+        return Err(malformed("_rebuild_tensor without a storage and offset"))
+    };
+    let shape = ints(args.get(2))?;
+    let stride = ints(args.get(3))?;
+    if shape.len() != stride.len() || *offset < 0 {
+        return Err(malformed("tensor size and stride do not fit together"))
+    }
+    Ok(Value::Tensor(Tensor { storage: storage.clone(), offset: *offset as u64, shape, stride }))
+}
+
+/// ('storage', storage class, key, location, numel) to a Storage
+fn persistent_load(pid: &Value, arena: &Arena) -> io::Result<Value> {
+    let Some(items) = arena.items(pid) else {
+        return Err(malformed("persistent id is not a tuple"))
+    };
+    match items {
+        [Value::Str(kind), Value::Global(class), Value::Str(key), ..] if &**kind == PERSISTENT_STORAGE => {
+            let (_, dtype, item_size) = STORAGE_DTYPES.iter()
+                .find(|(name, ..)| name == class)
+                .ok_or_else(|| malformed(&format!("unsupported storage type {}", class)))?;
+            Ok(Value::Storage(Storage { key: key.to_string(), dtype, item_size: *item_size }))
+        },
+        _ => Err(malformed("persistent id is not a tensor storage")),
+    }
+}
+
+/// A little endian two's complement integer of len bytes
+fn read_long<R: Read>(reader: &mut R, len: u64) -> io::Result<Value> {
+    if len > 8 {
+        skip(reader, len)?;
+        return Ok(Value::Object)
+    }
+    let bytes = read_bytes(reader, len)?;
+    let mut value: i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (8 * i);
+    }
+    if len > 0 && len < 8 && bytes[len as usize - 1] & 0x80 != 0 {
+        value -= 1 << (8 * len);
+    }
+    Ok(Value::Int(value))
+}
+
+/// Read a string argument. Long strings (docstrings, metadata) cannot be tensor
+/// names or storage keys, so are skipped.
+fn read_str<R: Read>(reader: &mut R, len: u64) -> io::Result<Value> {
+    if len > MAX_NAME_LENGTH {
+        skip(reader, len)?;
+        return Ok(Value::Object)
+    }
+    Ok(Value::Str(String::from_utf8_lossy(&read_bytes(reader, len)?).into()))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into())
+    }
+    Ok(bytes)
+}
+
+fn memo_get(memo: &HashMap<u64, Value>, index: u64) -> io::Result<Value> {
+    memo.get(&index).cloned().ok_or_else(|| malformed("memo index that was never set"))
+}
+
+fn pop(stack: &mut Vec<Value>) -> io::Result<Value> {
+    match stack.pop() {
+        Some(Value::Mark) | None => Err(malformed("stack underflow")),
+        Some(value) => Ok(value),
+    }
+}
+
+fn top(stack: &[Value]) -> io::Result<Value> {
+    match stack.last() {
+        Some(Value::Mark) | None => Err(malformed("stack underflow")),
+        Some(value) => Ok(value.clone()),
+    }
+}
+
+/// Pop everything down to the last MARK, returning it in pushed order
+fn pop_mark(stack: &mut Vec<Value>) -> io::Result<Vec<Value>> {
+    let mark = stack.iter().rposition(|v| matches!(v, Value::Mark)).ok_or_else(|| malformed("no MARK on the stack"))?;
+    let items = stack.split_off(mark + 1);
+    stack.pop();
+    Ok(items)
+}
@@ -0,0 +1,109 @@
+//! Turning a --directory argument into a path.
+//!
+//! Directories given on the command line or in MODEL_DIRECTORY may use `~`,
+//! `$VAR` and `${VAR}`, even where no shell expands them (quoted arguments,
+//! config files, service environments). They may also name an alias from the
+//! config file, optionally followed by a subfolder:
+//!
+//!     let dir = directory::expand("@sdxl/styles", &config.aliases)?;
+//!     directory::prepare(&dir, create_dir)?;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::Result;
+use crate::VorpalError;
+
+const ALIAS_PREFIX: char = '@';
+const HOME: char = '~';
+
+/// Expand an alias, then `~` and environment variables, in raw. An alias is
+/// `@name` or `@name/subfolder`, and its value may itself use `~` and variables.
+///
+/// Errors:
+///     - VorpalError::InvalidDirectory if the alias is not in aliases
+///     - VorpalError::InvalidDirectory if a variable is not set, or there is no home directory
+pub fn expand(raw: &str, aliases: &BTreeMap<String, String>) -> Result<PathBuf> {
+    let invalid = |message: String| VorpalError::InvalidDirectory { directory: raw.to_string(), message };
+    let raw_path = match raw.strip_prefix(ALIAS_PREFIX) {
+        Some(alias) => {
+            let (name, rest) = match alias.find(['/', '\\']) {
+                Some(i) => (&alias[..i], &alias[i + 1..]),
+                None => (alias, ""),
+            };
+            let Some(value) = aliases.get(name) else {
+                let known: Vec<String> = aliases.keys().map(|name| format!("{}{}", ALIAS_PREFIX, name)).collect();
+                return Err(invalid(format!("there is no alias '{}' in the config file. Known aliases: {}", name, known.join(", "))))
+            };
+            match rest.is_empty() {
+                true => value.clone(),
+                false => Path::new(value).join(rest).display().to_string(),
+            }
+        },
+        None => raw.to_string(),
+    };
+
+    let expanded = expand_vars(&raw_path).map_err(&invalid)?;
+    match expanded.strip_prefix(HOME) {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            let home = dirs::home_dir().ok_or_else(|| invalid("there is no home directory to expand '~' to".to_string()))?;
+            Ok(home.join(rest.trim_start_matches(['/', '\\'])))
+        },
+        _ => Ok(PathBuf::from(expanded)),
+    }
+}
+
+/// Make sure dir is a directory to download into, creating it (and its parents)
+/// if create is true
+///
+/// Errors:
+///     - VorpalError::InvalidDirectory if dir does not exist and create is false,
+///       or if it is a file
+///     - VorpalError::FileCreate if it cannot be created
+pub fn prepare(dir: &Path, create: bool) -> Result<()> {
+    let invalid = |message: &str| VorpalError::InvalidDirectory { directory: dir.display().to_string(), message: message.to_string() };
+    if dir.is_dir() {
+        return Ok(())
+    }
+    if dir.exists() {
+        return Err(invalid("it is a file, not a directory"))
+    }
+    if !create {
+        return Err(invalid("it does not exist. Use --create-dir to create it"))
+    }
+    fs::create_dir_all(dir).map_err(|source| VorpalError::FileCreate { path: dir.display().to_string(), source })
+}
+
+/// Replace $VAR and ${VAR} in s with their values. A $ that does not start a
+/// variable name is kept.
+fn expand_vars(s: &str) -> std::result::Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, next) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(close) => (&braced[..close], &braced[close + 1..]),
+                None => return Err(format!("'${{' without a '}}' in '{}'", s)),
+            },
+            // Like a shell, names cannot start with a digit
+            None if after.starts_with(|c: char| c.is_ascii_digit()) => ("", after),
+            None => {
+                let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            },
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue
+        }
+        let value = env::var(name).map_err(|_| format!("the environment variable {} is not set", name))?;
+        expanded.push_str(&value);
+        rest = next;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
@@ -0,0 +1,575 @@
+//! Resumable file downloads.
+//!
+//! Data is written to `<name>.part` and only renamed to `<name>` once the whole
+//! file has arrived, so a half-finished download never looks like a model. If a
+//! download is cut off, the .part file is kept along with a small `.part.json`
+//! sidecar holding the server's ETag and the expected length. The next attempt
+//! asks the server for just the missing bytes with a Range request. If-Range
+//! makes the server send the whole file instead if it has changed since.
+//!
+//! The SHA256 of the file is worked out as it streams in, so it can be checked
+//! against the hash Civitai publishes without reading the file a second time.
+//!
+//! With DownloadOptions::segments, a large file is instead split into byte ranges
+//! that are fetched over separate connections at once, each written straight to
+//! its place in a preallocated .part file. This gets around servers that throttle
+//! each connection. If the server does not honor Range, the download falls back to
+//! a single stream. Segmented downloads are not resumed: a failed one is discarded.
+//!
+//! A 200 status does not mean the file arrived: Civitai answers some failed
+//! downloads with a web page. HTML responses are refused outright, and when the
+//! expected format and size are known the finished file's structure is checked
+//! too, so a bogus file is never left looking like a model.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures_util::{future, Stream, StreamExt};
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, RANGE};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::client::check_status;
+use crate::error::Result;
+use crate::hash::{to_hex, update_from_file};
+use crate::pickle;
+use crate::progress::{ProgressObserver, ProgressTracker, SharedObserver};
+use crate::safetensors;
+use crate::{FileFormat, VorpalError};
+
+const PART_EXTENSION: &str = "part";
+const VALIDATOR_EXTENSION: &str = "part.json";
+const QUARANTINE_EXTENSION: &str = "corrupt";
+const LOGIN_PATH: &str = "/login";
+/// Segments are never smaller than this, so small files are not split needlessly
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+const HTML_CONTENT_TYPE: &str = "text/html";
+const HTML_MARKERS: [&str; 2] = ["<!doctype html", "<html"];
+const GGUF_MAGIC: &[u8] = b"GGUF";
+/// Civitai's sizes are rounded to the KB, so a file may be this far off (as a
+/// fraction, or SIZE_SLACK bytes for small files) and still be the right one
+const SIZE_TOLERANCE: f64 = 0.01;
+const SIZE_SLACK: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// What to do with a downloaded file whose hash, size or format does not match the
+/// expected one. Pickles that fail the scan are always quarantined.
+pub enum MismatchAction {
+    /// Delete the file
+    Delete,
+    /// Leave the file where it would have gone, as if it were fine
+    Keep,
+    /// Move the file aside to <name>.corrupt so it is not picked up as a model
+    #[default]
+    Quarantine,
+}
+
+impl std::str::FromStr for MismatchAction {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "delete" => Ok(MismatchAction::Delete),
+            "keep" => Ok(MismatchAction::Keep),
+            "quarantine" => Ok(MismatchAction::Quarantine),
+            _ => Err(format!("Vorpal: Unknown mismatch action '{}'. Expected one of: delete, keep, quarantine", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Settings for a single download
+pub struct DownloadOptions {
+    expected_sha256: Option<String>,
+    expected_size: Option<u64>,
+    expected_format: Option<FileFormat>,
+    on_mismatch: MismatchAction,
+    segments: usize,
+    scan_pickle: bool,
+    allow_dangerous_pickle: bool,
+    progress: Option<SharedObserver>,
+}
+
+impl DownloadOptions {
+    pub fn new() -> Self {
+        DownloadOptions::default()
+    }
+
+    /// Fail the download if the file's SHA256 (hex, any case) is not this
+    pub fn expected_sha256(mut self, hash: impl Into<String>) -> Self {
+        self.expected_sha256 = Some(hash.into());
+        self
+    }
+
+    /// Size of the file in bytes, used for progress when the server does not send
+    /// a Content-Length. A finished file that is more than about 1% off is handled
+    /// like a hash mismatch.
+    pub fn expected_size(mut self, bytes: u64) -> Self {
+        self.expected_size = Some(bytes);
+        self
+    }
+
+    /// Check that the finished file really is in this format (a SafeTensor header
+    /// that parses, a zip or pickle for PickleTensor, the GGUF magic) before moving it
+    /// into place. A file that is not is handled like a hash mismatch.
+    pub fn expected_format(mut self, format: FileFormat) -> Self {
+        self.expected_format = Some(format);
+        self
+    }
+
+    pub fn on_mismatch(mut self, action: MismatchAction) -> Self {
+        self.on_mismatch = action;
+        self
+    }
+
+    /// Fetch the file over this many connections at once, when the server supports
+    /// byte ranges and the file is large enough. 0 and 1 mean a single stream.
+    pub fn segments(mut self, segments: usize) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    /// Scan the file with pickle::scan_file before moving it into place. A file that
+    /// imports anything outside the allowlist, or cannot be scanned, is quarantined
+    /// whatever on_mismatch says.
+    pub fn scan_pickle(mut self, scan: bool) -> Self {
+        self.scan_pickle = scan;
+        self
+    }
+
+    /// Keep pickle files even if the scan finds dangerous imports
+    pub fn allow_dangerous_pickle(mut self, allow: bool) -> Self {
+        self.allow_dangerous_pickle = allow;
+        self
+    }
+
+    /// Whether pickles are kept without being scanned, so any scan is up to the caller
+    pub fn get_allow_dangerous_pickle(&self) -> bool {
+        self.allow_dangerous_pickle
+    }
+
+    /// Report progress to this observer while the download runs
+    pub fn progress(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.progress = Some(SharedObserver(observer));
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+/// What the server said about the file when the .part file was started
+struct Validator {
+    etag: Option<String>,
+    total: Option<u64>,
+}
+
+/// Where the in-progress data for a download to path is kept
+pub fn part_path(path: &Path) -> PathBuf {
+    with_suffix(path, PART_EXTENSION)
+}
+
+/// Where a download to path is moved if its hash does not match
+pub fn quarantine_path(path: &Path) -> PathBuf {
+    with_suffix(path, QUARANTINE_EXTENSION)
+}
+
+fn validator_path(path: &Path) -> PathBuf {
+    with_suffix(path, VALIDATOR_EXTENSION)
+}
+
+/// Append an extension without replacing the existing one (model.safetensors -> model.safetensors.part)
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn read_validator(path: &Path) -> Option<Validator> {
+    let raw = fs::read_to_string(validator_path(path)).ok()?;
+    serde_json::from_str(&raw).ok()
+}
+
+fn write_validator(path: &Path, validator: &Validator) -> Result<()> {
+    let validator_path = validator_path(path);
+    let raw = serde_json::to_string(validator)?;
+    fs::write(&validator_path, raw)
+        .map_err(|source| VorpalError::FileWrite { path: validator_path.display().to_string(), source })
+}
+
+/// Remove the .part file and its sidecar. Missing files are fine.
+pub fn discard_partial(path: &Path) -> Result<()> {
+    for leftover in [part_path(path), validator_path(path)] {
+        match fs::remove_file(&leftover) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(source) => return Err(VorpalError::FileDelete { path: leftover.display().to_string(), source }),
+        }
+    }
+    Ok(())
+}
+
+/// Parse a Content-Range header ("bytes 100-199/1000") into (start, total)
+fn content_range(res: &Response) -> Option<(u64, Option<u64>)> {
+    let value = res.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    let (span, total) = range.split_once('/')?;
+    let (start, _end) = span.split_once('-')?;
+    Some((start.parse().ok()?, total.parse().ok()))
+}
+
+/// Outcome of one request in download()
+enum Attempt {
+    /// The file is in place, with this SHA256
+    Done(String),
+    /// The partial file could not be resumed and has been discarded
+    Restart,
+}
+
+/// Download url to path, resuming from a previous .part file when possible.
+/// make_request builds a fresh GET request for the url each time it is called.
+/// Returns the SHA256 of the file.
+///
+/// Errors:
+///     - If the request cannot be sent, or the server responds with an error status
+///     - If the server wants a login first
+///     - If the server sends a web page instead of the file
+///     - If the file is not the size or format in options
+///     - If the connection drops or stalls (the .part file is kept for resuming)
+///     - If the server sends fewer bytes than it promised (the .part file is kept)
+///     - If the file's SHA256 does not match options.expected_sha256
+///     - If files cannot be created, written or renamed
+pub(crate) async fn download<F>(make_request: F, url: &str, path: &Path, read_timeout: Option<Duration>, options: &DownloadOptions) -> Result<String>
+where F: Fn() -> RequestBuilder {
+    // A leftover .part file from a single stream download is resumed instead
+    if options.segments > 1 && !part_path(path).exists() {
+        if let Some(hash) = segmented(&make_request, url, path, read_timeout, options).await? {
+            return Ok(hash)
+        }
+    }
+    match attempt(&make_request, url, path, read_timeout, options, true).await? {
+        Attempt::Done(hash) => Ok(hash),
+        Attempt::Restart => match attempt(&make_request, url, path, read_timeout, options, false).await? {
+            Attempt::Done(hash) => Ok(hash),
+            // Only a resumed download restarts, but a server must not be able to loop us
+            Attempt::Restart => Err(VorpalError::HttpStatus { status: StatusCode::RANGE_NOT_SATISFIABLE.as_u16(), url: url.to_string() }),
+        },
+    }
+}
+
+async fn attempt<F>(make_request: &F, url: &str, path: &Path, read_timeout: Option<Duration>, options: &DownloadOptions, allow_resume: bool) -> Result<Attempt>
+where F: Fn() -> RequestBuilder {
+    let part = part_path(path);
+    let existing = match allow_resume {
+        true => fs::metadata(&part).map(|m| m.len()).unwrap_or(0),
+        false => 0,
+    };
+    let saved = match existing {
+        0 => None,
+        _ => read_validator(path),
+    };
+
+    let mut request = make_request();
+    if let Some(saved) = &saved {
+        request = request.header(RANGE, format!("bytes={}-", existing));
+        if let Some(etag) = &saved.etag {
+            request = request.header(IF_RANGE, etag);
+        }
+    }
+    let res = request.send().await.map_err(VorpalError::Fetch)?;
+
+    // The part file already holds everything the server has. A 416 to a request
+    // without a range is just an error status.
+    if res.status() == StatusCode::RANGE_NOT_SATISFIABLE && saved.is_some() {
+        if let Some(Validator { total: Some(total), .. }) = saved {
+            if total == existing {
+                let mut hasher = Sha256::new();
+                update_from_file(&part, &mut hasher)?;
+                return finish(path, to_hex(&hasher.finalize()), options).map(Attempt::Done)
+            }
+        }
+        discard_partial(path)?;
+        return Ok(Attempt::Restart)
+    }
+    let res = check_status(res)?;
+    // Gated downloads without a token get redirected to the login page
+    if res.url().path().starts_with(LOGIN_PATH) {
+        return Err(VorpalError::AuthRequired { url: url.to_string() })
+    }
+    check_content_type(&res)?;
+
+    let etag = res.headers().get(ETAG).and_then(|v| v.to_str().ok()).map(String::from);
+    let resumed = match (&saved, res.status(), content_range(&res)) {
+        (Some(saved), StatusCode::PARTIAL_CONTENT, Some((start, total))) => {
+            start == existing && (saved.total.is_none() || total.is_none() || saved.total == total)
+        },
+        _ => false,
+    };
+    if saved.is_some() && !resumed && res.status() == StatusCode::PARTIAL_CONTENT {
+        // A range we did not ask for, so there is no safe way to use it
+        discard_partial(path)?;
+        return Ok(Attempt::Restart)
+    }
+
+    let mut hasher = Sha256::new();
+    let (file, validator) = if resumed {
+        update_from_file(&part, &mut hasher)?;
+        let file = OpenOptions::new().append(true).open(&part);
+        (file, saved.unwrap_or_default())
+    } else {
+        let total = res.content_length();
+        (File::create(&part), Validator { etag, total })
+    };
+    let file = file.map_err(|source| VorpalError::FileCreate { path: part.display().to_string(), source })?;
+    write_validator(path, &validator)?;
+
+    let resumed_from = if resumed { existing } else { 0 };
+    let observer = options.progress.as_ref().map(|shared| shared.0.as_ref());
+    let mut tracker = ProgressTracker::new(observer, resumed_from, validator.total.or(options.expected_size));
+    write_stream(file, &part, res, read_timeout, &mut hasher, &mut tracker).await?;
+    tracker.finish();
+
+    let written = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+    if let Some(total) = validator.total {
+        if written != total {
+            return Err(VorpalError::Incomplete { path: part.display().to_string(), expected: total, actual: written })
+        }
+    }
+    finish(path, to_hex(&hasher.finalize()), options).map(Attempt::Done)
+}
+
+/// Try to download url as options.segments byte ranges at once. Returns None, with
+/// nothing written, if the server does not support ranges or the file is too small
+/// to be worth splitting.
+async fn segmented<F>(make_request: &F, url: &str, path: &Path, read_timeout: Option<Duration>, options: &DownloadOptions) -> Result<Option<String>>
+where F: Fn() -> RequestBuilder {
+    // Ask for the first byte only, to learn the size and whether ranges work at all
+    let res = make_request().header(RANGE, "bytes=0-0").send().await.map_err(VorpalError::Fetch)?;
+    if res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok(None)
+    }
+    let res = check_status(res)?;
+    if res.url().path().starts_with(LOGIN_PATH) {
+        return Err(VorpalError::AuthRequired { url: url.to_string() })
+    }
+    check_content_type(&res)?;
+    let total = match (res.status(), content_range(&res)) {
+        (StatusCode::PARTIAL_CONTENT, Some((0, Some(total)))) => total,
+        _ => return Ok(None),
+    };
+    let etag = res.headers().get(ETAG).and_then(|v| v.to_str().ok()).map(String::from);
+    let ranges = segment_ranges(total, options.segments);
+    if ranges.len() < 2 {
+        return Ok(None)
+    }
+    drop(res);
+
+    let part = part_path(path);
+    File::create(&part)
+        .and_then(|file| file.set_len(total))
+        .map_err(|source| VorpalError::FileCreate { path: part.display().to_string(), source })?;
+
+    let observer = options.progress.as_ref().map(|shared| shared.0.as_ref());
+    let tracker = Mutex::new(ProgressTracker::new(observer, 0, Some(total)));
+    let fetches = ranges.into_iter()
+        .map(|(start, end)| fetch_segment(make_request, &part, start, end, etag.as_deref(), read_timeout, &tracker));
+    // The preallocated file has holes where segments are missing, so it cannot be resumed
+    if let Err(e) = future::try_join_all(fetches).await {
+        discard_partial(path)?;
+        return Err(e)
+    }
+    tracker.into_inner().unwrap_or_else(|e| e.into_inner()).finish();
+
+    let mut hasher = Sha256::new();
+    update_from_file(&part, &mut hasher)?;
+    finish(path, to_hex(&hasher.finalize()), options).map(Some)
+}
+
+/// Split total bytes into at most count inclusive (start, end) ranges, none smaller
+/// than MIN_SEGMENT_SIZE
+fn segment_ranges(total: u64, count: usize) -> Vec<(u64, u64)> {
+    let count = (count as u64).min(total / MIN_SEGMENT_SIZE).max(1);
+    let size = total.div_ceil(count);
+    (0..count)
+        .map(|i| (i * size, ((i + 1) * size).min(total) - 1))
+        .filter(|(start, end)| start <= end)
+        .collect()
+}
+
+/// Fetch bytes start..=end and write them at the same offset in part
+async fn fetch_segment<F>(make_request: &F, part: &Path, start: u64, end: u64, etag: Option<&str>, read_timeout: Option<Duration>, tracker: &Mutex<ProgressTracker<'_>>) -> Result<()>
+where F: Fn() -> RequestBuilder {
+    let mut request = make_request().header(RANGE, format!("bytes={}-{}", start, end));
+    // If the file changed since the first request, this gets the whole new file
+    // instead of a range of it, which is caught below
+    if let Some(etag) = etag {
+        request = request.header(IF_RANGE, etag);
+    }
+    let res = check_status(request.send().await.map_err(VorpalError::Fetch)?)?;
+    let url = res.url().to_string();
+    let in_range = matches!(content_range(&res), Some((s, _)) if s == start);
+    if res.status() != StatusCode::PARTIAL_CONTENT || !in_range {
+        return Err(VorpalError::RangeIgnored { url })
+    }
+
+    let part_name = || part.display().to_string();
+    let mut file = OpenOptions::new().write(true).open(part)
+        .and_then(|mut file| file.seek(SeekFrom::Start(start)).map(|_| file))
+        .map_err(|source| VorpalError::FileWrite { path: part_name(), source })?;
+    let expected = end - start + 1;
+    let mut written = 0;
+    let stream = &mut res.bytes_stream();
+    while let Some(chunk) = next_chunk(stream, &url, read_timeout).await? {
+        if written + chunk.len() as u64 > expected {
+            return Err(VorpalError::RangeIgnored { url })
+        }
+        file.write_all(&chunk)
+            .map_err(|source| VorpalError::FileWrite { path: part_name(), source })?;
+        written += chunk.len() as u64;
+        tracker.lock().unwrap_or_else(|e| e.into_inner()).advance(chunk.len() as u64);
+    }
+    if written != expected {
+        return Err(VorpalError::Incomplete { path: part_name(), expected, actual: written })
+    }
+    file.flush()
+        .map_err(|source| VorpalError::FileWrite { path: part_name(), source })
+}
+
+/// The next chunk of a response body, or None at the end. Fails if no data
+/// arrives within read_timeout.
+async fn next_chunk<S, T>(stream: &mut S, url: &str, read_timeout: Option<Duration>) -> Result<Option<T>>
+where S: Stream<Item = reqwest::Result<T>> + Unpin {
+    let next = match read_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(next) => next,
+            Err(_) => return Err(VorpalError::Timeout { url: url.to_string() }),
+        },
+        None => stream.next().await,
+    };
+    next.transpose().map_err(VorpalError::Download)
+}
+
+/// Stream the response body onto the end of file
+async fn write_stream(mut file: File, part: &Path, res: Response, read_timeout: Option<Duration>, hasher: &mut Sha256, tracker: &mut ProgressTracker<'_>) -> Result<()> {
+    let url = res.url().to_string();
+    let stream = &mut res.bytes_stream();
+    while let Some(chunk) = next_chunk(stream, &url, read_timeout).await? {
+        hasher.update(&chunk);
+        file.write_all(&chunk)
+            .map_err(|source| VorpalError::FileWrite { path: part.display().to_string(), source })?;
+        tracker.advance(chunk.len() as u64);
+    }
+    file.flush()
+        .map_err(|source| VorpalError::FileWrite { path: part.display().to_string(), source })
+}
+
+/// Civitai answers some failed downloads (gated models, outages) with a web page
+/// and a 200 status. No model file is served as HTML, so refuse it before writing.
+fn check_content_type(res: &Response) -> Result<()> {
+    let content_type = res.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if content_type.to_lowercase().starts_with(HTML_CONTENT_TYPE) {
+        return Err(VorpalError::UnexpectedContentType { url: res.url().to_string(), content_type: content_type.to_string() })
+    }
+    Ok(())
+}
+
+/// Why the finished .part file is not the file options describe, if it is not
+fn validate(part: &Path, options: &DownloadOptions) -> Result<Option<String>> {
+    let read_error = |source| VorpalError::FileRead { path: part.display().to_string(), source };
+    let size = fs::metadata(part).map_err(read_error)?.len();
+    if let Some(expected) = options.expected_size {
+        let slack = ((expected as f64 * SIZE_TOLERANCE) as u64).max(SIZE_SLACK);
+        if size.abs_diff(expected) > slack {
+            return Ok(Some(format!("it is {} bytes, but should be about {}", size, expected)))
+        }
+    }
+
+    let mut start = Vec::new();
+    File::open(part).and_then(|file| file.take(64).read_to_end(&mut start)).map_err(read_error)?;
+    let text = String::from_utf8_lossy(&start).trim_start().to_lowercase();
+    if HTML_MARKERS.iter().any(|marker| text.starts_with(marker)) {
+        return Ok(Some("it is a web page, not a model file".to_string()))
+    }
+    let problem = match options.expected_format {
+        Some(FileFormat::SafeTensor) => match safetensors::read_header(part) {
+            Ok(_) => None,
+            Err(VorpalError::InvalidSafetensors { message, .. }) => Some(format!("it is not a SafeTensor file: {}", message)),
+            Err(e) => return Err(e),
+        },
+        Some(FileFormat::PickleTensor) if !start.starts_with(pickle::ZIP_MAGIC) && start.first() != Some(&pickle::PROTO) =>
+            Some("it is not a PyTorch zip or pickle file".to_string()),
+        Some(FileFormat::Gguf) if !start.starts_with(GGUF_MAGIC) => Some("it is not a GGUF file".to_string()),
+        _ => None,
+    };
+    Ok(problem)
+}
+
+/// Check the size, format and hash of the finished .part file (and scan it, if it
+/// is a pickle), then move it into place, or aside if it failed, and clean up the sidecar
+fn finish(path: &Path, hash: String, options: &DownloadOptions) -> Result<String> {
+    let part = part_path(path);
+    if let Some(message) = validate(&part, options)? {
+        let kept_at = reject(path, options.on_mismatch)?;
+        return Err(VorpalError::InvalidDownload { path: kept_at.map(|p| p.display().to_string()), message })
+    }
+    if let Some(expected) = &options.expected_sha256 {
+        if !expected.eq_ignore_ascii_case(&hash) {
+            let kept_at = reject(path, options.on_mismatch)?;
+            return Err(VorpalError::HashMismatch {
+                path: kept_at.map(|p| p.display().to_string()),
+                expected: expected.to_uppercase(),
+                actual: hash,
+            })
+        }
+    }
+    // A pickle that may run code is never kept under its real name, whatever on_mismatch says
+    if options.scan_pickle && !options.allow_dangerous_pickle {
+        match pickle::scan_file(&part) {
+            Ok(report) if report.is_safe() => (),
+            Ok(report) => {
+                let kept_at = reject(path, MismatchAction::Quarantine)?;
+                return Err(VorpalError::DangerousPickle {
+                    path: kept_at.map(|p| p.display().to_string()),
+                    imports: report.get_dangerous().iter().map(|i| i.to_string()).collect(),
+                })
+            },
+            Err(e) => {
+                reject(path, MismatchAction::Quarantine)?;
+                return Err(e)
+            },
+        }
+    }
+    move_file(&part, path)?;
+    discard_partial(path)?;
+    Ok(hash)
+}
+
+/// Deal with a .part file that failed verification as action says.
+/// Returns where the file was left, if it was kept.
+fn reject(path: &Path, action: MismatchAction) -> Result<Option<PathBuf>> {
+    let part = part_path(path);
+    let kept_at = match action {
+        MismatchAction::Delete => {
+            discard_partial(path)?;
+            None
+        },
+        MismatchAction::Keep => {
+            move_file(&part, path)?;
+            discard_partial(path)?;
+            Some(path.to_path_buf())
+        },
+        MismatchAction::Quarantine => {
+            let quarantine = quarantine_path(path);
+            move_file(&part, &quarantine)?;
+            discard_partial(path)?;
+            Some(quarantine)
+        },
+    };
+    Ok(kept_at)
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)
+        .map_err(|source| VorpalError::FileWrite { path: to.display().to_string(), source })
+}
@@ -0,0 +1,223 @@
+//! Errors returned by libvorpal.
+//!
+//! Every public function in the library returns a VorpalError instead of
+//! panicking, so that a bad response from Civitai or a full disk does not
+//! take down whatever program vorpal is embedded in.
+
+use std::fmt;
+use std::io;
+
+const ERR_CONNECTION: &str = "Vorpal: Error in getting JSON. This usually means that the CivitAI API is experiencing issues.";
+const ERR_HTTP_STATUS: &str = "Vorpal: The server responded with an error status.";
+const ERR_GET_JSON: &str = "Vorpal: Error in getting JSON. This is likely due to trying to parse an invalid query.";
+const ERR_NO_RESULTS: &str = "Vorpal: No results were found.";
+const ERR_NO_MATCHING_FILE: &str = "Vorpal: None of this version's files match the requested format, precision, size or type. Available files:";
+const ERR_NO_MATCHING_VERSION: &str = "Vorpal: The model has no version like this. Available versions:";
+const ERR_UNSAFE_FILE: &str = "Vorpal: Refusing to download this file, because";
+const ERR_INVALID_PICKLE: &str = "Vorpal: The file could not be scanned, so it cannot be shown to be safe.";
+const ERR_DANGEROUS_PICKLE: &str = "Vorpal: This pickle file imports code outside of what PyTorch needs to load a model. Loading it could run that code.";
+const ERR_INVALID_SAFETENSORS: &str = "Vorpal: The file is not a valid SafeTensor file. It may be corrupted or incomplete.";
+const ERR_UNEXPECTED_CONTENT_TYPE: &str = "Vorpal: The server sent a web page instead of the file. The download may need an API token, or Civitai may be having issues.";
+const ERR_INVALID_DOWNLOAD: &str = "Vorpal: The downloaded file is not the file Civitai describes, because";
+const ERR_CONVERT: &str = "Vorpal: The checkpoint could not be converted to safetensors.";
+const ERR_FETCH: &str = "Vorpal: Failed to fetch download. This could be the result of an unstable connection.";
+const ERR_INCOMPLETE: &str = "Vorpal: The download ended early. Run vorpal again to resume it.";
+const ERR_RANGE_IGNORED: &str = "Vorpal: The server stopped honoring byte ranges part way through a segmented download. Try again without --segments.";
+const ERR_HASH_MISMATCH: &str = "Vorpal: The downloaded file does not match the hash Civitai published. It is likely corrupted.";
+const ERR_TIMEOUT: &str = "Vorpal: The server stopped sending data. Is your connection stable?";
+const ERR_CLIENT: &str = "Vorpal: Failed to set up the HTTP client.";
+const ERR_AUTH_REQUIRED: &str = "Vorpal: This download requires a Civitai API token. Set CIVITAI_API_TOKEN, api_token in the config file, or use --token.";
+const ERR_NOT_FOUND: &str = "Vorpal: Civitai has no model at this address. Check the Id or url.";
+const ERR_CONFIG: &str = "Vorpal: There is a problem with the config file.";
+const ERR_INVALID_DIRECTORY: &str = "Vorpal: The download directory cannot be used, because";
+const ERR_LIBRARY: &str = "Vorpal: The library index could not be read.";
+const ERR_FILE_EXISTS: &str = "Vorpal: A different file is already at this path. Use --on-collision to skip, rename or overwrite instead.";
+const ERR_FILE_CREATE: &str = "Vorpal: Failed to create file. Is the file path clear?";
+const ERR_FILE_DOWNLOAD: &str = "Vorpal: Something went wrong while downloading the file. Is your connection stable? Run vorpal again to resume the download.";
+const ERR_FILE_READ: &str = "Vorpal: Failed to read file. Does it exist, and do you have read permission?";
+const ERR_FILE_WRITE: &str = "Vorpal: Something went wrong when writing to the file.";
+const ERR_FILE_DELETE: &str = "Vorpal: Something went wrong when deleting the file.\nThe model file is likely corrupted, and vorpal is unable to delete it.";
+
+#[derive(Debug)]
+/// Everything that can go wrong when querying or downloading from Civitai.
+pub enum VorpalError {
+    /// The request never got a response (DNS, TLS, refused connection, timeout)
+    Connection(reqwest::Error),
+    /// The server answered, but with a non-success status code
+    HttpStatus { status: u16, url: String },
+    /// The server refused the request because it needs a (valid) API token
+    AuthRequired { url: String },
+    /// The requested model, version, or file does not exist
+    NotFound { url: String },
+    /// The response body was not the JSON vorpal expected
+    JsonDecode(serde_json::Error),
+    /// The query was valid but returned no items
+    NoResults,
+    /// No file of the model version passed the FileSelector. available describes
+    /// the files there are.
+    NoMatchingFile { version: String, available: Vec<String> },
+    /// No version of the model matched the VersionSelector. available describes
+    /// the versions there are.
+    NoMatchingVersion { model: String, wanted: String, available: Vec<String> },
+    /// The only file to download is a pickle, or failed Civitai's scans
+    UnsafeFile { name: String, reason: String },
+    /// A file is not a pickle or PyTorch checkpoint, or its pickle is malformed
+    InvalidPickle { path: String, message: String },
+    /// A pickle file imports something outside pickle::ALLOWED_IMPORTS. path is where
+    /// the file was left, or None if it was deleted.
+    DangerousPickle { path: Option<String>, imports: Vec<String> },
+    /// A file's safetensors header is missing or malformed
+    InvalidSafetensors { path: String, message: String },
+    /// A checkpoint could not be converted to safetensors
+    Convert { path: String, message: String },
+    /// The download request itself could not be sent
+    Fetch(reqwest::Error),
+    /// The connection dropped part way through a download
+    Download(reqwest::Error),
+    /// The server closed the connection before sending the whole file
+    Incomplete { path: String, expected: u64, actual: u64 },
+    /// A segment of a segmented download came back without the byte range asked for
+    RangeIgnored { url: String },
+    /// The downloaded file's SHA256 is not the one Civitai published. path is where
+    /// the file was left, or None if it was deleted.
+    HashMismatch { path: Option<String>, expected: String, actual: String },
+    /// No data arrived within the client's read timeout
+    Timeout { url: String },
+    /// The HTTP client could not be configured (bad header, TLS setup)
+    Client(String),
+    /// The config file exists but is unreadable or malformed
+    Config { path: String, message: String },
+    /// The server answered with a web page (or other content that is not a file)
+    UnexpectedContentType { url: String, content_type: String },
+    /// The downloaded file's size or structure does not fit what was expected. path is
+    /// where the file was left, or None if it was deleted.
+    InvalidDownload { path: Option<String>, message: String },
+    /// The download directory could not be expanded, or does not exist
+    InvalidDirectory { directory: String, message: String },
+    /// The library index is not valid JSON, or has no place to be saved
+    Library { path: String, message: String },
+    /// A file is already where a download would go, and OnCollision::Fail was asked for
+    FileExists { path: String },
+    /// The destination file could not be created
+    FileCreate { path: String, source: io::Error },
+    /// A local file could not be opened or read
+    FileRead { path: String, source: io::Error },
+    /// Writing to the destination file failed
+    FileWrite { path: String, source: io::Error },
+    /// A .part file, its .part.json, or a rejected download could not be removed
+    FileDelete { path: String, source: io::Error },
+}
+
+impl fmt::Display for VorpalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VorpalError::Connection(e) => write!(f, "{}\n{}", ERR_CONNECTION, e),
+            VorpalError::HttpStatus { status, url } => write!(f, "{}\n{} ({})", ERR_HTTP_STATUS, status, url),
+            VorpalError::AuthRequired { url } => write!(f, "{}\n{}", ERR_AUTH_REQUIRED, url),
+            VorpalError::NotFound { url } => write!(f, "{}\n{}", ERR_NOT_FOUND, url),
+            VorpalError::JsonDecode(e) => write!(f, "{}\n{}", ERR_GET_JSON, e),
+            VorpalError::NoResults => write!(f, "{}", ERR_NO_RESULTS),
+            VorpalError::NoMatchingVersion { model, wanted, available } => {
+                write!(f, "{} ({}: {})", ERR_NO_MATCHING_VERSION, model, wanted)?;
+                for version in available {
+                    write!(f, "\n    {}", version)?;
+                }
+                Ok(())
+            },
+            VorpalError::InvalidPickle { path, message } => write!(f, "{}\n{}: {}", ERR_INVALID_PICKLE, path, message),
+            VorpalError::DangerousPickle { path, imports } => {
+                write!(f, "{}", ERR_DANGEROUS_PICKLE)?;
+                for import in imports {
+                    write!(f, "\n    {}", import)?;
+                }
+                match path {
+                    Some(path) => write!(f, "\nThe file was kept at {}", path),
+                    None => write!(f, "\nThe file was deleted"),
+                }
+            },
+            VorpalError::InvalidSafetensors { path, message } => write!(f, "{}\n{}: {}", ERR_INVALID_SAFETENSORS, path, message),
+            VorpalError::Convert { path, message } => write!(f, "{}\n{}: {}", ERR_CONVERT, path, message),
+            VorpalError::UnsafeFile { name, reason } => write!(f, "{} {}\n{}", ERR_UNSAFE_FILE, reason, name),
+            VorpalError::NoMatchingFile { version, available } => {
+                write!(f, "{} ({})", ERR_NO_MATCHING_FILE, version)?;
+                for file in available {
+                    write!(f, "\n    {}", file)?;
+                }
+                Ok(())
+            },
+            VorpalError::Fetch(e) => write!(f, "{}\n{}", ERR_FETCH, e),
+            VorpalError::Download(e) => write!(f, "{}\n{}", ERR_FILE_DOWNLOAD, e),
+            VorpalError::Incomplete { path, expected, actual } => write!(f, "{}\n{}: {} of {} bytes", ERR_INCOMPLETE, path, actual, expected),
+            VorpalError::HashMismatch { path, expected, actual } => {
+                write!(f, "{}\nExpected SHA256: {}\nActual SHA256: {}", ERR_HASH_MISMATCH, expected, actual)?;
+                match path {
+                    Some(path) => write!(f, "\nThe file was kept at {}", path),
+                    None => write!(f, "\nThe file was deleted"),
+                }
+            },
+            VorpalError::UnexpectedContentType { url, content_type } => write!(f, "{}\n{} ({})", ERR_UNEXPECTED_CONTENT_TYPE, url, content_type),
+            VorpalError::InvalidDownload { path, message } => {
+                write!(f, "{} {}", ERR_INVALID_DOWNLOAD, message)?;
+                match path {
+                    Some(path) => write!(f, "\nThe file was kept at {}", path),
+                    None => write!(f, "\nThe file was deleted"),
+                }
+            },
+            VorpalError::RangeIgnored { url } => write!(f, "{}\n{}", ERR_RANGE_IGNORED, url),
+            VorpalError::Timeout { url } => write!(f, "{}\n{}", ERR_TIMEOUT, url),
+            VorpalError::Client(msg) => write!(f, "{}\n{}", ERR_CLIENT, msg),
+            VorpalError::Config { path, message } => write!(f, "{}\n{}: {}", ERR_CONFIG, path, message),
+            VorpalError::InvalidDirectory { directory, message } => write!(f, "{} {}\n{}", ERR_INVALID_DIRECTORY, message, directory),
+            VorpalError::Library { path, message } => write!(f, "{}\n{}: {}", ERR_LIBRARY, path, message),
+            VorpalError::FileExists { path } => write!(f, "{}\n{}", ERR_FILE_EXISTS, path),
+            VorpalError::FileCreate { path, source } => write!(f, "{}\n{}: {}", ERR_FILE_CREATE, path, source),
+            VorpalError::FileRead { path, source } => write!(f, "{}\n{}: {}", ERR_FILE_READ, path, source),
+            VorpalError::FileWrite { path, source } => write!(f, "{}\n{}: {}", ERR_FILE_WRITE, path, source),
+            VorpalError::FileDelete { path, source } => write!(f, "{}\n{}: {}", ERR_FILE_DELETE, path, source),
+        }
+    }
+}
+
+impl std::error::Error for VorpalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VorpalError::Connection(e) | VorpalError::Fetch(e) | VorpalError::Download(e) => Some(e),
+            VorpalError::JsonDecode(e) => Some(e),
+            VorpalError::FileCreate { source, .. }
+            | VorpalError::FileRead { source, .. }
+            | VorpalError::FileWrite { source, .. }
+            | VorpalError::FileDelete { source, .. } => Some(source),
+            VorpalError::HttpStatus { .. }
+            | VorpalError::AuthRequired { .. }
+            | VorpalError::NotFound { .. }
+            | VorpalError::Config { .. }
+            | VorpalError::NoResults
+            | VorpalError::NoMatchingFile { .. }
+            | VorpalError::NoMatchingVersion { .. }
+            | VorpalError::UnsafeFile { .. }
+            | VorpalError::InvalidPickle { .. }
+            | VorpalError::DangerousPickle { .. }
+            | VorpalError::Convert { .. }
+            | VorpalError::InvalidSafetensors { .. }
+            | VorpalError::Incomplete { .. }
+            | VorpalError::RangeIgnored { .. }
+            | VorpalError::UnexpectedContentType { .. }
+            | VorpalError::InvalidDownload { .. }
+            | VorpalError::InvalidDirectory { .. }
+            | VorpalError::Library { .. }
+            | VorpalError::FileExists { .. }
+            | VorpalError::HashMismatch { .. }
+            | VorpalError::Timeout { .. }
+            | VorpalError::Client(_) => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for VorpalError {
+    fn from(e: serde_json::Error) -> Self {
+        VorpalError::JsonDecode(e)
+    }
+}
+
+/// Result type used throughout libvorpal
+pub type Result<T> = std::result::Result<T, VorpalError>;
@@ -0,0 +1,345 @@
+//! Turning file names from the server into safe local paths.
+//!
+//! Civitai's file names come from uploaders, and a Content-Disposition header can
+//! say anything. A name like `../../.bashrc` or `C:\Windows\win.ini` must not be
+//! able to put a file outside the model directory, so every name is reduced to a
+//! single plain path component before it is joined onto a directory:
+//!
+//!     let path = filename::join(&dir, &file.get_name());
+//!
+//! A FilenameTemplate names files after the model instead, and an OnCollision
+//! policy decides what happens when something is already at the path:
+//!
+//!     let template: FilenameTemplate = "{model}-{version}-{versionId}.{ext}".parse()?;
+//!     let path = dir.join(template.render(&version, &file));
+//!     match filename::resolve(&path, OnCollision::Rename, file.get_sha256().as_deref())? { ... }
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::Deserialize;
+use crate::error::Result;
+use crate::hash::sha256_file;
+use crate::{ModelFile, ModelVersion, VorpalError};
+
+/// Used when nothing is left of a name after sanitizing it
+pub const DEFAULT_FILENAME: &str = "download";
+/// Most file systems allow at most 255 bytes per name
+const MAX_FILENAME_BYTES: usize = 255;
+/// Longer "extensions" are just part of the name, and are not kept when truncating
+const MAX_EXTENSION_BYTES: usize = 16;
+/// Characters Windows does not allow in file names
+const RESERVED_CHARS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+/// Device names Windows will not create files under, whatever the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const REPLACEMENT: char = '_';
+/// Rendered for template fields a model does not have
+const UNKNOWN_FIELD: &str = "unknown";
+/// Separates a renamed file's stem from its number (lora_1.safetensors)
+const RENAME_SEPARATOR: char = '_';
+const TEMPLATE_FIELDS: [&str; 12] = [
+    "filename", "name", "ext", "model", "modelId", "version", "versionId",
+    "baseModel", "type", "format", "precision", "size",
+];
+
+/// Reduce a server supplied name to one safe path component. Directories are
+/// dropped (only the part after the last / or \ is kept), control and reserved
+/// characters are replaced, leading dots and trailing dots and spaces are removed,
+/// Windows device names are prefixed with an underscore, and the result fits in
+/// 255 bytes with its extension kept. Never returns an empty string, "." or "..".
+pub fn sanitize(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base.chars()
+        .map(|c| match c {
+            c if c.is_control() || RESERVED_CHARS.contains(&c) => REPLACEMENT,
+            c => c,
+        })
+        .collect();
+    let mut name = cleaned.trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string();
+    if name.is_empty() {
+        return DEFAULT_FILENAME.to_string()
+    }
+
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        name.insert(0, REPLACEMENT);
+    }
+    truncate(name)
+}
+
+/// dir joined with the sanitized name, which is always a file directly in dir
+pub fn join(dir: &Path, name: &str) -> PathBuf {
+    dir.join(sanitize(name))
+}
+
+/// The file name from a Content-Disposition header value, sanitized. An RFC 5987
+/// `filename*=UTF-8''...` is preferred over a plain `filename=`.
+pub fn from_content_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for param in value.split(';').map(str::trim) {
+        let Some((key, val)) = param.split_once('=') else { continue };
+        match key.trim().to_lowercase().as_str() {
+            "filename*" => {
+                // charset'language'percent-encoded-name. A malformed one is ignored,
+                // so a plain filename can still be used
+                let Some(encoded) = val.trim().splitn(3, '\'').nth(2) else { continue };
+                extended = Some(percent_decode(encoded));
+            },
+            "filename" => plain = Some(val.trim().trim_matches('"').replace("\\\"", "\"")),
+            _ => (),
+        }
+    }
+    extended.or(plain)
+        .filter(|name| !name.trim().is_empty())
+        .map(|name| sanitize(&name))
+}
+
+/// The last segment of a url's path, sanitized, if it has one
+pub fn from_url(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let path = path.split_once("://").map_or(path, |(_, rest)| rest.split_once('/').map_or("", |(_, path)| path));
+    let last = path.rsplit('/').next().filter(|segment| !segment.is_empty())?;
+    Some(sanitize(&percent_decode(last)))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+/// How to name a downloaded file. Fields in braces are filled in from the model:
+///
+///     {filename}  the name Civitai gives the file (the default template)
+///     {name}      that name without its extension
+///     {ext}       the file's extension, without the dot
+///     {model}, {modelId}, {version}, {versionId}, {baseModel}, {type}
+///     {format}, {precision}, {size}   from the file's metadata
+pub struct FilenameTemplate(String);
+
+impl FilenameTemplate {
+    /// The file name for file of version. Slashes in fields are replaced and the
+    /// result is sanitized, so it is always a single path component.
+    pub fn render(&self, version: &ModelVersion, file: &ModelFile) -> String {
+        let filename = file.get_name();
+        let (name, ext) = match filename.rsplit_once('.') {
+            Some((name, ext)) => (name.to_string(), ext.to_string()),
+            None => (filename.clone(), String::new()),
+        };
+        let metadata = file.get_metadata();
+        let field = |field: &str| -> Option<String> {
+            match field {
+                "filename" => Some(filename.clone()),
+                "name" => Some(name.clone()),
+                "ext" => Some(ext.clone()),
+                "model" => version.get_model_name(),
+                "modelId" => Some(version.get_model_id()),
+                "version" => Some(version.get_name()),
+                "versionId" => Some(version.get_id()),
+                "baseModel" => version.get_base_model(),
+                "type" => version.get_model_type(),
+                "format" => file.get_format().map(|f| f.to_string()),
+                "precision" => metadata.fp.clone(),
+                "size" => metadata.size.clone(),
+                _ => None,
+            }
+        };
+
+        let mut rendered = String::new();
+        let mut rest = self.0.as_str();
+        while let Some(open) = rest.find('{') {
+            // Templates are checked when parsed, so every field is closed
+            let Some(close) = rest[open..].find('}').map(|i| open + i) else { break };
+            rendered.push_str(&rest[..open]);
+            let value = field(&rest[open + 1..close]).unwrap_or_else(|| UNKNOWN_FIELD.to_string());
+            rendered.push_str(&value.replace(['/', '\\'], &REPLACEMENT.to_string()));
+            rest = &rest[close + 1..];
+        }
+        rendered.push_str(rest);
+        sanitize(&rendered)
+    }
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        FilenameTemplate("{filename}".to_string())
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}').map(|i| open + i) else {
+                return Err(format!("Vorpal: The filename template '{}' has a '{{' without a '}}'", s))
+            };
+            let field = &rest[open + 1..close];
+            if !TEMPLATE_FIELDS.contains(&field) {
+                return Err(format!("Vorpal: Unknown filename template field '{{{}}}'. Expected one of: {{{}}}", field, TEMPLATE_FIELDS.join("}, {")))
+            }
+            rest = &rest[close + 1..];
+        }
+        if s.trim().is_empty() {
+            return Err("Vorpal: The filename template cannot be empty".to_string())
+        }
+        Ok(FilenameTemplate(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for FilenameTemplate {
+    type Error = String;
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for FilenameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// What to do when a file is already where a download would go
+pub enum OnCollision {
+    /// Keep the existing file if it has the expected SHA256, and download under a
+    /// new name (as Rename does) if not
+    #[default]
+    Skip,
+    /// Download under the first free name of the form <stem>_1.<ext>
+    Rename,
+    /// Replace the existing file
+    Overwrite,
+    /// Stop with VorpalError::FileExists
+    Fail,
+}
+
+impl FromStr for OnCollision {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(OnCollision::Skip),
+            "rename" => Ok(OnCollision::Rename),
+            "overwrite" => Ok(OnCollision::Overwrite),
+            "fail" => Ok(OnCollision::Fail),
+            _ => Err(format!("Vorpal: Unknown collision policy '{}'. Expected one of: skip, rename, overwrite, fail", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where to put a file, once an OnCollision policy has been applied
+pub enum Destination {
+    /// Write the file here. It may be a renamed path, or an existing file to replace.
+    Write(PathBuf),
+    /// The file is already here, with this SHA256, so there is nothing to write
+    Existing(PathBuf, String),
+}
+
+/// Apply policy to a file that is about to be written to path. expected_sha256 is
+/// the hash the new file will have, if known; without it, Skip can never tell that
+/// an existing file is the same, so it renames.
+///
+/// Errors:
+///     - VorpalError::FileExists if the policy is Fail and something is at path
+///     - If an existing file cannot be read to hash it
+pub fn resolve(path: &Path, policy: OnCollision, expected_sha256: Option<&str>) -> Result<Destination> {
+    resolve_reserved(path, policy, expected_sha256, &HashSet::new())
+}
+
+/// As resolve, but the paths in reserved are taken too, by files that are about
+/// to be written. A reserved path is never handed out twice: Fail gives
+/// VorpalError::FileExists, and every other policy renames, since there is no
+/// file there yet to keep or replace.
+pub fn resolve_reserved(path: &Path, policy: OnCollision, expected_sha256: Option<&str>, reserved: &HashSet<PathBuf>) -> Result<Destination> {
+    if reserved.contains(path) {
+        return match policy {
+            OnCollision::Fail => Err(VorpalError::FileExists { path: path.display().to_string() }),
+            _ => Ok(Destination::Write(free_path(path, reserved))),
+        }
+    }
+    if !path.exists() {
+        return Ok(Destination::Write(path.to_path_buf()))
+    }
+    match policy {
+        OnCollision::Overwrite => Ok(Destination::Write(path.to_path_buf())),
+        OnCollision::Fail => Err(VorpalError::FileExists { path: path.display().to_string() }),
+        OnCollision::Rename => Ok(Destination::Write(free_path(path, reserved))),
+        OnCollision::Skip => {
+            if let Some(expected) = expected_sha256 {
+                let existing = sha256_file(path)?;
+                if existing.eq_ignore_ascii_case(expected) {
+                    return Ok(Destination::Existing(path.to_path_buf(), existing))
+                }
+            }
+            Ok(Destination::Write(free_path(path, reserved)))
+        },
+    }
+}
+
+/// The first of <stem>_1.<ext>, <stem>_2.<ext>... that neither exists nor is reserved.
+/// The stem is shortened as needed to keep the name within MAX_FILENAME_BYTES.
+fn free_path(path: &Path, reserved: &HashSet<PathBuf>) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+        _ => (name.clone(), String::new()),
+    };
+    (1..)
+        .map(|n| {
+            let suffix = format!("{}{}{}", RENAME_SEPARATOR, n, ext);
+            let mut stem_end = stem.len().min(MAX_FILENAME_BYTES.saturating_sub(suffix.len()));
+            while !stem.is_char_boundary(stem_end) {
+                stem_end -= 1;
+            }
+            path.with_file_name(format!("{}{}", &stem[..stem_end], suffix))
+        })
+        .find(|candidate| !candidate.exists() && !reserved.contains(candidate))
+        .expect("there is always a free name")
+}
+
+/// Cut name down to MAX_FILENAME_BYTES on a character boundary, keeping a short extension
+fn truncate(name: String) -> String {
+    if name.len() <= MAX_FILENAME_BYTES {
+        return name
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= MAX_EXTENSION_BYTES => &name[dot..],
+        _ => "",
+    };
+    let mut stem_end = MAX_FILENAME_BYTES - extension.len();
+    while !name.is_char_boundary(stem_end) {
+        stem_end -= 1;
+    }
+    format!("{}{}", &name[..stem_end], extension)
+}
+
+/// Decode %XX escapes. Invalid escapes are kept as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = match bytes[i] {
+            b'%' => s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
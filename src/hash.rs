@@ -0,0 +1,45 @@
+//! File hashing, for matching local files against the hashes Civitai publishes.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use sha2::{Digest, Sha256};
+
+use crate::error::Result;
+use crate::VorpalError;
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// SHA256 of a file as an uppercase hex string, the same format Civitai uses.
+/// The file is read in chunks, so multi-gigabyte checkpoints are fine.
+///
+/// Errors:
+///     - If the file cannot be opened or read
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    update_from_file(path, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// SHA256 of bytes in memory, in the same format as sha256_file
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+/// Feed the whole contents of a file to a hasher
+pub(crate) fn update_from_file(path: &Path, hasher: &mut Sha256) -> Result<()> {
+    let read_error = |source| VorpalError::FileRead { path: path.display().to_string(), source };
+    let mut file = File::open(path).map_err(read_error)?;
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer).map_err(read_error)?;
+        if read == 0 { break }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(())
+}
+
+/// Uppercase hex encoding of a digest
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
@@ -0,0 +1,153 @@
+//! Which folder each type of model goes in.
+//!
+//! Stable Diffusion UIs keep each type of model in its own folder, and the
+//! folders differ between UIs. A Router sends a download to the right one,
+//! given the UI's root folder as the download directory:
+//!
+//!     let router = Router::new(Layout::ComfyUi).route("DoRA", "models/loras");
+//!     let dir = router.get_dir(Path::new("/opt/ComfyUI"), &version, &file);
+//!
+//! Types without a folder in the layout (and every type, in the flat layout)
+//! are saved in the root folder itself.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::Deserialize;
+use crate::search::normalize;
+use crate::{ModelFile, ModelType, ModelVersion};
+
+/// The file type Civitai gives a VAE bundled with a checkpoint
+const VAE_FILE_TYPE: &str = "VAE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+/// A UI's folder structure
+pub enum Layout {
+    /// Everything in one folder
+    #[default]
+    Flat,
+    /// AUTOMATIC1111's stable-diffusion-webui, and Forge
+    Webui,
+    ComfyUi,
+}
+
+impl Layout {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Layout::Flat => "flat",
+            Layout::Webui => "webui",
+            Layout::ComfyUi => "comfyui",
+        }
+    }
+
+    /// The folder for model_type, relative to the UI's root, if the layout has one
+    pub fn get_subdir(&self, model_type: ModelType) -> Option<&'static str> {
+        match self {
+            Layout::Flat => None,
+            Layout::Webui => match model_type {
+                ModelType::Checkpoint => Some("models/Stable-diffusion"),
+                ModelType::TextualInversion => Some("embeddings"),
+                ModelType::Hypernetwork => Some("models/hypernetworks"),
+                ModelType::Lora | ModelType::LoCon => Some("models/Lora"),
+                ModelType::Controlnet => Some("models/ControlNet"),
+                ModelType::Vae => Some("models/VAE"),
+                ModelType::Upscaler => Some("models/ESRGAN"),
+                ModelType::MotionModule => Some("extensions/sd-webui-animatediff/model"),
+                ModelType::AestheticGradient => Some("extensions/stable-diffusion-webui-aesthetic-gradients/aesthetic_embeddings"),
+                _ => None,
+            },
+            Layout::ComfyUi => match model_type {
+                ModelType::Checkpoint => Some("models/checkpoints"),
+                ModelType::TextualInversion => Some("models/embeddings"),
+                ModelType::Hypernetwork => Some("models/hypernetworks"),
+                ModelType::Lora | ModelType::LoCon => Some("models/loras"),
+                ModelType::Controlnet => Some("models/controlnet"),
+                ModelType::Vae => Some("models/vae"),
+                ModelType::Upscaler => Some("models/upscale_models"),
+                ModelType::MotionModule => Some("models/animatediff_models"),
+                ModelType::Workflows => Some("user/default/workflows"),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match normalize(s).as_str() {
+            "flat" => Ok(Layout::Flat),
+            "webui" | "a1111" | "automatic1111" | "forge" => Ok(Layout::Webui),
+            "comfyui" | "comfy" => Ok(Layout::ComfyUi),
+            _ => Err(format!("Vorpal: Unknown layout '{}'. Expected one of: flat, webui (or a1111, forge), comfyui", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Layout {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Picks the folder for a download: a custom folder for its type if one was set,
+/// otherwise the layout's
+pub struct Router {
+    layout: Layout,
+    /// Normalized type name to folder
+    custom: BTreeMap<String, PathBuf>,
+}
+
+impl Router {
+    pub fn new(layout: Layout) -> Self {
+        Router { layout, custom: BTreeMap::new() }
+    }
+
+    /// Send models of model_type to dir instead. model_type is matched the way
+    /// --type is ("lora", "LORA" and "Lora" are the same), but need not be a type
+    /// vorpal knows. A relative dir is taken from the root folder.
+    pub fn route(mut self, model_type: &str, dir: impl Into<PathBuf>) -> Self {
+        self.custom.insert(normalize(model_type), dir.into());
+        self
+    }
+
+    /// Set every route in routes, as route does
+    pub fn routes(mut self, routes: &BTreeMap<String, PathBuf>) -> Self {
+        for (model_type, dir) in routes {
+            self = self.route(model_type, dir.clone());
+        }
+        self
+    }
+
+    pub fn get_layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// The folder under root to save file of version in. A VAE file is routed as
+    /// a VAE even when it comes with a checkpoint.
+    pub fn get_dir(&self, root: &Path, version: &ModelVersion, file: &ModelFile) -> PathBuf {
+        let model_type = match file.get_type() {
+            Some(file_type) if file_type.eq_ignore_ascii_case(VAE_FILE_TYPE) => Some(ModelType::Vae.as_str().to_string()),
+            _ => version.get_model_type(),
+        };
+        let Some(model_type) = model_type else { return root.to_path_buf() };
+
+        if let Some(dir) = self.custom.get(&normalize(&model_type)) {
+            return root.join(dir)
+        }
+        match model_type.parse().ok().and_then(|model_type| self.layout.get_subdir(model_type)) {
+            Some(subdir) => root.join(subdir),
+            None => root.to_path_buf(),
+        }
+    }
+}
@@ -11,34 +11,62 @@
 //! certain errors can come about due to Chinese characters or emojis
 //! skewing character indices.
 
-use std::fs::File;
-use std::io::Write;
-use std::fs::remove_file;
-use futures_util::StreamExt;
-use serde::Deserialize;
-use scraper;
-use serde_json;
-use reqwest::Error;
-use anyhow::Result;
-
-const ERR_CONNECTION: &str = "Vorpal: Error in getting JSON. This usually means that the CivitAI API is experiencing issues.\n";
-const ERR_GET_JSON: &str = "Vorpal: Error in getting JSON. This is likely due to trying to parse an invalid query.\n";
-const ERR_FETCH: &str = "Vorpal: Failed to fetch download. This could be the result of an unstable connection.\n";
-const ERR_FILE_CREATE: &str = "Vorpal: Failed to create file. Is the file path clear?\n";
-const ERR_FILE_DOWNLOAD: &str = "Vorpal: Something went wrong while downloading the file. Is your connection stable?\n";
-//const ERR_FILE_WRITE: &str = "Vorpal: Something went wrong when writing to the file.\n"; //TODO
-const ERR_FILE_DELETE: &str = "Vorpal: Something went wrong when deleting the file.\nThe model file is likely corrupted, and vorpal is unable to delete it.";
+use serde::{Deserialize, Serialize};
+
+mod client;
+pub mod config;
+pub mod convert;
+pub mod directory;
+pub mod download;
+pub mod error;
+pub mod filename;
+pub mod hash;
+pub mod layout;
+pub mod library;
+pub mod pickle;
+pub mod progress;
+pub mod queue;
+pub mod safetensors;
+pub mod search;
+pub mod select;
+
+pub use client::{CivitaiClient, CivitaiClientBuilder, QueryStream};
+pub use download::{DownloadOptions, MismatchAction};
+pub use progress::{Progress, ProgressObserver};
+pub use queue::{DownloadJob, DownloadQueue, JobObserver, JobOutcome, JobStatus};
+pub use error::VorpalError;
+pub use filename::{FilenameTemplate, OnCollision};
+pub use layout::{Layout, Router};
+pub use library::{Library, LibraryEntry};
+pub use search::{ModelType, Period, SearchParams, Sort};
+pub use select::{FileFormat, FileSelector, VersionSelector};
+use error::Result;
+
 const QUERY_INDENT: &str = "    ";
 const SHORT_SIZE: usize = 100;
 const DESC_CUTOFF: &str = "...";
-const MSG_NO_RESULTS: &str = "Vorpal: No results were found.\n";
-const BASE_DL_URL: &str = "https://civitai.com/api/download/models/"; 
 const NO_DESC: &str = "<No description given>";
+const MODEL_PAGE_URL: &str = "https://civitai.com/models/";
+const NO_SCAN: &str = "Not scanned";
+/// Scan results that mean a file may be dangerous
+const SCAN_FAILURES: [&str; 2] = ["Danger", "Error"];
 
 #[derive(Deserialize, Debug)]
 /// A vector of QueryItems sent from Civitai
 pub struct QueryResponse {
     pub items: Vec<QueryItem>,
+    #[serde(default)]
+    pub metadata: PageMetadata,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+/// Paging information sent alongside a page of QueryItems.
+/// Text searches page with a cursor, other listings with a page url.
+pub struct PageMetadata {
+    pub next_cursor: Option<String>,
+    pub next_page: Option<String>,
+    pub total_items: Option<u64>,
 }
 
 #[allow(dead_code)]
@@ -59,6 +87,9 @@ pub struct QueryResponse {
 pub struct QueryItem {
     name: String,
     id: u32,
+    /// Checkpoint, LORA, TextualInversion...
+    #[serde(rename = "type", default)]
+    model_type: Option<String>,
     description: Option<String>,
     creator: Creator,
     tags: Vec<String>,
@@ -98,6 +129,21 @@ pub struct ModelVersion {
     base_model: Option<String>,
     base_model_type: Option<String>,
     files: Vec<ModelFile>,
+    #[serde(default)]
+    created_at: Option<String>,
+    #[serde(default)]
+    published_at: Option<String>,
+    /// Only sent when the version is fetched on its own (not as part of a QueryItem)
+    #[serde(default)]
+    model: Option<VersionModel>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+/// The parent model of a ModelVersion, as included by the model-versions endpoint
+pub struct VersionModel {
+    name: String,
+    #[serde(rename = "type")]
+    model_type: String,
 }
 
 #[allow(dead_code)]
@@ -112,72 +158,89 @@ pub struct ModelFile {
     size_kb: f64,
     name: String,
     download_url: String,
+    #[serde(default)]
+    hashes: FileHashes,
+    /// "Model", "Pruned Model", "VAE", "Config", "Training Data"...
+    #[serde(default, rename = "type")]
+    file_type: Option<String>,
+    /// The file the uploader marked as the main download
+    #[serde(default)]
+    primary: bool,
+    #[serde(default)]
+    metadata: FileMetadata,
+    /// Civitai's scan for dangerous pickle imports: "Success", "Pending", "Danger" or "Error"
+    #[serde(default)]
+    pickle_scan_result: Option<String>,
+    #[serde(default)]
+    pickle_scan_message: Option<String>,
+    /// Civitai's virus scan: "Success", "Pending", "Danger" or "Error"
+    #[serde(default)]
+    virus_scan_result: Option<String>,
+    #[serde(default)]
+    virus_scan_message: Option<String>,
 }
 
-type JsonResult = Result<String, Error>;
-type QueryResult = Result<QueryResponse, Error>;
-
-#[tokio::main]
-async fn get_raw_civitai_json(query: String, limit: u8, safe: bool) -> JsonResult {
-    let request_url = format!("https://civitai.com/api/v1/models?limit={}&query={}&nsfw={}",
-                                        limit.to_string(),
-                                        query,
-                                        safe,
-);
-    let res = reqwest::get(&request_url).await?;
-    let body = res.text().await;
-    body
+#[derive(Deserialize, Debug, Clone, Default)]
+/// How a ModelFile was saved, as the uploader described it
+pub struct FileMetadata {
+    /// Floating point precision: "fp16", "fp32", "bf16"...
+    pub fp: Option<String>,
+    /// "pruned" or "full"
+    pub size: Option<String>,
+    /// "SafeTensor", "PickleTensor", "Diffusers"...
+    pub format: Option<String>,
 }
 
-fn parse_civitai_json(raw: JsonResult) -> QueryResult {
-    let raw_unwrapped = match raw {
-        Ok(_) => raw.unwrap(),
-        Err(e) => panic!("{}\n{}", e, ERR_CONNECTION),
-    };
-    //let raw_unwrapped = raw.unwrap();
-    let test: QueryResponse = serde_json::from_str(&raw_unwrapped).expect(ERR_GET_JSON);
-    Ok(test)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+/// Hashes Civitai computed for a ModelFile. Any of them may be missing,
+/// particularly on older uploads.
+pub struct FileHashes {
+    #[serde(rename = "SHA256")]
+    pub sha256: Option<String>,
+    #[serde(rename = "AutoV1")]
+    pub auto_v1: Option<String>,
+    #[serde(rename = "AutoV2")]
+    pub auto_v2: Option<String>,
+    #[serde(rename = "CRC32")]
+    pub crc32: Option<String>,
+    #[serde(rename = "BLAKE3")]
+    pub blake3: Option<String>,
 }
 
 /// Query Civitai for models. Returns a Vector of QueryItems
+/// This is a shortcut for CivitaiClient::get_query_items using the default client.
+/// It builds a new client on every call, so use a CivitaiClient for more than one query.
 /// Args:
 ///     search - the keyword to query
 ///     count - the amount of results to display
 ///     safe - enter query as 'safe'
-pub fn get_query_items(search: String, count: u8, safe: bool) -> Vec<QueryItem> {
-    let raw = get_raw_civitai_json(search, count, safe);
-    let parsed = parse_civitai_json(raw);
-    let items: Vec<QueryItem>;
-    match &parsed {
-        Ok(_) => items = parsed.unwrap().get_items(),
-        Err(e) => panic!("{}", e),
-    };
-    if items.len() == 0 { panic!("{}", MSG_NO_RESULTS) }
-    items
+/// 
+/// Errors:
+///     - If Civitai cannot be reached or responds with an error status
+///     - If the response is not valid JSON
+///     - If the query has no results
+pub async fn get_query_items(search: String, count: u8, safe: bool) -> Result<Vec<QueryItem>> {
+    CivitaiClient::new()?.get_query_items(search, count, safe).await
 }
 
 /// Find only the url of the first model from a Civitai query
 /// The most recent model version and file will be used
-pub fn get_model_file_url(search: String) -> String {
-    let count = 1;
-    let safe = false;
-    let queryitem = &get_query_items(search, count, safe)[0];
-    queryitem.get_download_url()
+/// A shortcut for CivitaiClient::get_model_file_url using the default client.
+pub async fn get_model_file_url(search: String) -> Result<String> {
+    CivitaiClient::new()?.get_model_file_url(search).await
 }
 
 /// Return the first query item from a Civitai query. Intended for quickly downloading
 /// a model from a simple search.
+/// A shortcut for CivitaiClient::get_first_query_item using the default client.
 /// Args:
 ///     search - The search term to get models from Civitai
 ///     safe - Enter search as 'safe' (no NSFW)
 ///            Note that this is done on a 'best effort' basis,
 ///            as it is very common for users to not properly
 ///            label their items.
-pub fn get_first_query_item(search: String, safe: bool) -> QueryItem {
-    let count = 1;
-    let query = get_query_items(search, count, safe);
-    let first = query[0].clone();
-    first
+pub async fn get_first_query_item(search: String, safe: bool) -> Result<QueryItem> {
+    CivitaiClient::new()?.get_first_query_item(search, safe).await
 }
 
 impl QueryResponse {
@@ -186,63 +249,33 @@ impl QueryResponse {
     }
 }
 
-fn handle_remove(path: &str, e: &str) -> () {
-    remove_file(path).expect(ERR_FILE_DELETE);
-    panic!("{}", e) //TODO add err msg
-}
-
-async fn perform_validated_download(mut file: File, path: String, res: reqwest::Response) -> () {
-    let stream = &mut res.bytes_stream();
-    while let Some(item) = stream.next().await {
-        let chunk = item.or(Err(ERR_FILE_DOWNLOAD));
-        match &chunk {
-            Ok(c) => file.write_all(c).expect(ERR_CONNECTION),
-            Err(e) => handle_remove( &path, e)
-        }
-    }
-}
-
 /// Download a Civitai model given the Id (of the model version).
 /// This is the same Id that will appear on the Civitai page for
 /// that model. The get_download_url() of QueryItem can be used to
 /// find this.
 /// 
-/// Panics:
+/// Errors:
 ///     - If reqwest cannot establish connection
-///     - If file cannot be created (file will be removed)
-///     - If corrupted file cannot be deleted
-pub async fn download_civitai_model_by_id(id: String, path: String) -> Result<(), Error> {
-    let url = format!("{BASE_DL_URL}{id}");
-    download_file_by_url(url, path).await
+///     - If the server responds with an error status
+///     - If the connection drops, or the file cannot be written (the .part file and
+///       its .part.json are kept so the download can be resumed)
+///     - If the finished file cannot be renamed into place
+pub async fn download_civitai_model_by_id(id: String, path: String) -> Result<()> {
+    CivitaiClient::new()?.download_civitai_model_by_id(id, path).await
 }
 
 
 /// Download a file given a url and path.
+/// The file is downloaded to <path>.part first and renamed when complete.
 /// 
-/// Panics:
+/// Errors:
 ///     - If reqwest cannot establish connection
-///     - If file cannot be created (file will be removed)
-///     - If corrupted file cannot be deleted
-pub async fn download_file_by_url(url: String, path: String) -> Result<(), Error> {
-    let res = reqwest::get(url)
-        .await
-        .or(Err(ERR_FETCH));
-
-    let validated_res = match res {
-        Ok(r) => r,
-        Err(e) => panic!("{}", e),
-    };
-
-    let file = File::create(&path);
-    match file {
-        Ok(f) => {
-            perform_validated_download(f, path, validated_res).await;
-            return Ok(())
-        },
-        Err(e) => {
-            panic!("{}\n{}", e, ERR_FILE_CREATE);
-        },
-    }
+///     - If the server responds with an error status
+///     - If the connection drops, or the file cannot be written (the .part file and
+///       its .part.json are kept so the download can be resumed)
+///     - If the finished file cannot be renamed into place
+pub async fn download_file_by_url(url: String, path: String) -> Result<()> {
+    CivitaiClient::new()?.download_file_by_url(url, path).await
 }
 
 impl QueryItem {
@@ -250,8 +283,7 @@ impl QueryItem {
         self.id.to_string()
     }
     pub fn get_tags(&self) -> String {
-        let tags = self.tags.join(", ");
-        tags
+        self.tags.join(", ")
     }
 
     pub fn get_creator_name(&self) -> String {
@@ -263,9 +295,41 @@ impl QueryItem {
         self.model_versions.clone()
     }
 
-    pub fn get_first(&self) -> ModelVersion {
-        let model_versions = self.model_versions.clone();
-        model_versions[0].clone()
+    /// The newest version
+    ///
+    /// Errors:
+    ///     - If the model has no versions
+    pub fn get_first(&self) -> Result<ModelVersion> {
+        self.get_version(&VersionSelector::Index(1))
+    }
+
+    /// The version picked by selector
+    ///
+    /// Errors:
+    ///     - If no version matches, listing the versions there are
+    pub fn get_version(&self, selector: &VersionSelector) -> Result<ModelVersion> {
+        selector.select(&self.model_versions).cloned().map(|v| self.with_model(v)).ok_or_else(|| VorpalError::NoMatchingVersion {
+            model: self.name.clone(),
+            wanted: selector.to_string(),
+            available: self.generate_version_list(),
+        })
+    }
+
+    /// Versions nested in a model are sent without the model's name and type, so fill them in
+    fn with_model(&self, mut version: ModelVersion) -> ModelVersion {
+        version.model.get_or_insert_with(|| VersionModel {
+            name: self.name.clone(),
+            model_type: self.model_type.clone().unwrap_or_default(),
+        });
+        version
+    }
+
+    /// One numbered line per version, newest first, for picking a version from
+    pub fn generate_version_list(&self) -> Vec<String> {
+        self.model_versions.iter()
+            .enumerate()
+            .map(|(i, version)| format!("[{}] {}", i + 1, version.get_summary()))
+            .collect()
     }
 
     /// Get model description without HTML artifacts
@@ -300,47 +364,56 @@ impl QueryItem {
 
 
     /// Make a list of metadata that can be used in a txt file
-    pub fn generate_model_report(&self) -> Vec<String> {
-        let mut report_fields: Vec<String> = Vec::new();
-        let version_metadata = self.get_first().get_version_metadata();
-        let file_metadata = self.get_first().get_latest_file().get_file_metadata();
-        report_fields.extend(version_metadata);
-        report_fields.extend(file_metadata);
-        report_fields
+    ///
+    /// Errors:
+    ///     - If the model has no versions
+    pub fn generate_model_report(&self) -> Result<Vec<String>> {
+        // get_first fills in the model, so its report starts with "Model: <name>"
+        Ok(self.get_first()?.generate_model_report())
     }
 
 
-    pub fn get_download_url(&self) -> String {
-        let model_version = self.get_first();
-        let model_file = model_version.get_latest_file();
-        model_file.download_url
+    /// The download url of the newest version's default file
+    ///
+    /// Errors:
+    ///     - If the model has no versions, or its newest version has no files
+    pub fn get_download_url(&self) -> Result<String> {
+        self.get_first()?.get_download_url()
     }
 
-    pub fn get_download_id(&self) -> String {
-        let model_version = self.get_first();
-        model_version.get_model_id()
+    /// Errors:
+    ///     - If the model has no versions
+    pub fn get_download_id(&self) -> Result<String> {
+        Ok(self.get_first()?.get_id())
     }
 
-    pub fn get_model_filename(&self) -> String {
-        let model_version = self.get_first();
-        let model_file = model_version.get_latest_file();
-        model_file.name
+    /// Errors:
+    ///     - If the model has no versions, or its newest version has no files
+    pub fn get_model_filename(&self) -> Result<String> {
+        self.get_first()?.get_model_filename()
     }
 
-    pub fn get_model_filesize(&self) -> f64 {
-        let model_version = self.get_first();
-        let model_file = model_version.get_latest_file();
-        model_file.size_kb
+    /// Errors:
+    ///     - If the model has no versions, or its newest version has no files
+    pub fn get_model_filesize(&self) -> Result<f64> {
+        self.get_first()?.get_model_filesize()
     }
 
     /// Generate CLI-oriented output of QueryItem
     /// Args:
     ///     full - true for full description, false for short description
-    pub fn make_cli_query_display(&self, full: bool) -> String {
+    ///
+    /// Errors:
+    ///     - If the model has no versions, or its newest version has no files,
+    ///       so there is nothing to show
+    pub fn make_cli_query_display(&self, full: bool) -> Result<String> {
+        let file = self.get_first()?.get_latest_file()?;
         let mut display_vec: Vec<String> = Vec::new();
-        display_vec.push(format!("{}Model: {}", QUERY_INDENT, self.get_model_filename()));
+        display_vec.push(format!("{}Model: {}", QUERY_INDENT, file.get_name()));
         display_vec.push(format!("{}Id: {}", QUERY_INDENT, self.get_id()));
-        display_vec.push(format!("{}Size (KB): {}", QUERY_INDENT, self.get_model_filesize()));
+        display_vec.push(format!("{}Size (KB): {}", QUERY_INDENT, file.size_kb));
+        display_vec.push(format!("{}Format: {}", QUERY_INDENT, file.get_description()));
+        display_vec.push(format!("{}Scans: {}", QUERY_INDENT, file.get_scan_summary()));
         display_vec.push(format!("{}Creator: {}", QUERY_INDENT, self.get_creator_name()));
         display_vec.push(format!("{}Tags: {}", QUERY_INDENT, self.get_tags()));
         match full {
@@ -348,64 +421,275 @@ impl QueryItem {
             false => display_vec.push(format!("{}Desc: {}", QUERY_INDENT, self.get_short_description(SHORT_SIZE, DESC_CUTOFF))),
         }
         display_vec.push("\n".to_string());
-        display_vec.join("\n")
+        Ok(display_vec.join("\n"))
     }
 }
 
 impl ModelVersion {
-    fn get_model_id(&self) -> String {
+    /// The Id of this version, which is also the Id used in download links
+    pub fn get_id(&self) -> String {
         self.id.to_string()
     }
 
-    #[allow(dead_code)]
-    fn get_version_id(&self) -> String {
+    /// The Id of the parent model (the QueryItem)
+    pub fn get_model_id(&self) -> String {
         self.model_id.to_string()
     }
 
-    fn get_name(&self) -> String {
+    pub fn get_name(&self) -> String {
         self.name.clone()
     }
 
-    fn get_trained_words(&self) -> String {
+    /// The name of the parent model, if this version was fetched on its own or
+    /// through its QueryItem
+    pub fn get_model_name(&self) -> Option<String> {
+        self.model.as_ref().map(|m| m.name.clone())
+    }
+
+    /// The type of the parent model (Checkpoint, LORA...), when the model name is known
+    pub fn get_model_type(&self) -> Option<String> {
+        self.model.as_ref().map(|m| m.model_type.clone()).filter(|t| !t.is_empty())
+    }
+
+    /// Errors:
+    ///     - If the version has no files
+    pub fn get_download_url(&self) -> Result<String> {
+        Ok(self.get_latest_file()?.download_url)
+    }
+
+    /// The day this version was published (or created, if it never was), as YYYY-MM-DD
+    pub fn get_date(&self) -> Option<String> {
+        let date = self.published_at.as_ref().or(self.created_at.as_ref())?;
+        Some(date.chars().take(10).collect())
+    }
+
+    pub fn get_base_model(&self) -> Option<String> {
+        self.base_model.clone()
+    }
+
+    /// A one line description like "v2.0 | SDXL 1.0 | 2024-01-12 | Id: 264911"
+    pub fn get_summary(&self) -> String {
+        let mut summary = vec![self.get_name()];
+        summary.extend(self.get_base_model());
+        summary.extend(self.get_date());
+        summary.push(format!("Id: {}", self.get_id()));
+        summary.join(" | ")
+    }
+
+    /// The Civitai page for this version
+    pub fn get_page_url(&self) -> String {
+        format!("{}{}?modelVersionId={}", MODEL_PAGE_URL, self.model_id, self.id)
+    }
+
+    pub fn get_trained_words(&self) -> String {
         self.trained_words.join(", ")
     }
 
-    fn get_latest_file(&self) -> ModelFile {
-        self.files[0].clone()
+    pub fn get_trained_word_list(&self) -> Vec<String> {
+        self.trained_words.clone()
+    }
+
+    /// Errors:
+    ///     - If the version has no files
+    pub fn get_model_filename(&self) -> Result<String> {
+        Ok(self.get_latest_file()?.name)
+    }
+
+    /// Errors:
+    ///     - If the version has no files
+    pub fn get_model_filesize(&self) -> Result<f64> {
+        Ok(self.get_latest_file()?.size_kb)
+    }
+
+    /// Make a list of metadata for this version that can be used in a txt file.
+    /// A version without files gets a report of the version alone.
+    pub fn generate_model_report(&self) -> Vec<String> {
+        self.generate_report(self.get_latest_file().ok().as_ref())
+    }
+
+    /// Like generate_model_report, but describing file instead of the default file
+    pub fn generate_file_report(&self, file: &ModelFile) -> Vec<String> {
+        self.generate_report(Some(file))
+    }
+
+    fn generate_report(&self, file: Option<&ModelFile>) -> Vec<String> {
+        let mut report_fields: Vec<String> = Vec::new();
+        if let Some(name) = self.get_model_name() {
+            report_fields.push(format!("Model: {}", name));
+        }
+        report_fields.extend(self.get_version_metadata());
+        if let Some(file) = file {
+            report_fields.extend(file.get_file_metadata());
+        }
+        report_fields
+    }
+
+    /// The file picked by the default FileSelector: a primary SafeTensor model
+    /// file if there is one, otherwise the first file.
+    ///
+    /// Errors:
+    ///     - If the version has no files
+    pub fn get_latest_file(&self) -> Result<ModelFile> {
+        self.select_file(&FileSelector::new())
+            .or_else(|| self.files.first().cloned())
+            .ok_or_else(|| VorpalError::NoMatchingFile { version: self.get_name(), available: Vec::new() })
+    }
+
+    /// The best file that passes selector's filters, if any does
+    pub fn select_file(&self, selector: &FileSelector) -> Option<ModelFile> {
+        selector.select(&self.files).cloned()
+    }
+
+    /// The file to download: like select_file, but refusing pickles and files that
+    /// failed Civitai's scans unless selector allows them. Safer alternatives are
+    /// always picked first, so this only fails if there are none.
+    ///
+    /// Errors:
+    ///     - If no file passes the filters, listing the files there are
+    ///     - If the best file is a pickle, or failed a scan, and that is not allowed
+    pub fn get_file(&self, selector: &FileSelector) -> Result<ModelFile> {
+        let file = self.select_file(selector).ok_or_else(|| VorpalError::NoMatchingFile {
+            version: self.get_name(),
+            available: self.files.iter()
+                .map(|file| format!("{} ({})", file.get_name(), file.get_description()))
+                .collect(),
+        })?;
+        selector.check_safety(&file)?;
+        Ok(file)
+    }
+
+    pub fn get_files(&self) -> Vec<ModelFile> {
+        self.files.clone()
     }
 
     fn get_version_metadata(&self) -> Vec<String> {
         let mut version_metadata: Vec<String> = Vec::new();
         version_metadata.push(format!("Model Name/Version: {}", self.get_name()));
         version_metadata.push(format!("Trained Words: {}", self.get_trained_words()));
-        version_metadata.push(format!("Id: {}", self.get_name()));
-        version_metadata.push(format!("Model Name/Version: {}", self.get_name()));
+        version_metadata.push(format!("Version Id: {}", self.get_id()));
+        version_metadata.push(format!("Model Id: {}", self.get_model_id()));
+        version_metadata.push(format!("Page: {}", self.get_page_url()));
+        if let Some(base_model) = &self.base_model {
+            version_metadata.push(format!("Base Model: {}", base_model));
+        }
+        if let Some(date) = self.get_date() {
+            version_metadata.push(format!("Published: {}", date));
+        }
         version_metadata
     }
 }
 
 impl ModelFile {
-    fn get_id(&self) -> String {
+    pub fn get_id(&self) -> String {
         self.id.to_string()
     }
 
     fn get_size(&self) -> String {
         self.size_kb.to_string()
     }
+
+    /// Approximate size in bytes, from Civitai's size in KB
+    pub fn get_size_bytes(&self) -> u64 {
+        (self.size_kb * 1024.0) as u64
+    }
     
-    fn get_name(&self) -> String {
+    pub fn get_name(&self) -> String {
         self.name.clone()
     }
 
-    fn get_url(&self) -> String {
+    pub fn get_url(&self) -> String {
         self.download_url.clone()
     }
+
+    pub fn get_hashes(&self) -> FileHashes {
+        self.hashes.clone()
+    }
+
+    pub fn get_sha256(&self) -> Option<String> {
+        self.hashes.sha256.clone()
+    }
+
+    /// What the file is: "Model", "Pruned Model", "VAE", "Config"...
+    pub fn get_type(&self) -> Option<String> {
+        self.file_type.clone()
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.primary
+    }
+
+    pub fn get_metadata(&self) -> FileMetadata {
+        self.metadata.clone()
+    }
+
+    /// The file's format, from Civitai's metadata or else its extension
+    pub fn get_format(&self) -> Option<FileFormat> {
+        match &self.metadata.format {
+            Some(format) => format.parse().ok(),
+            None => FileFormat::from_filename(&self.name),
+        }
+    }
+
+    /// Whether the file is a Python pickle, which can run code when loaded
+    pub fn is_pickle(&self) -> bool {
+        self.get_format() == Some(FileFormat::PickleTensor)
+    }
+
+    pub fn get_pickle_scan_result(&self) -> Option<String> {
+        self.pickle_scan_result.clone()
+    }
+
+    pub fn get_virus_scan_result(&self) -> Option<String> {
+        self.virus_scan_result.clone()
+    }
+
+    /// Whether either of Civitai's scans found a problem or could not finish
+    pub fn scan_failed(&self) -> bool {
+        [&self.pickle_scan_result, &self.virus_scan_result].into_iter()
+            .flatten()
+            .any(|result| SCAN_FAILURES.iter().any(|failure| result.eq_ignore_ascii_case(failure)))
+    }
+
+    /// Both scan results on one line, like "Pickle Success, Virus Success"
+    pub fn get_scan_summary(&self) -> String {
+        let result = |r: &Option<String>| r.clone().unwrap_or_else(|| NO_SCAN.to_string());
+        format!("Pickle {}, Virus {}", result(&self.pickle_scan_result), result(&self.virus_scan_result))
+    }
+
+    /// A short description like "SafeTensor fp16 pruned"
+    pub fn get_description(&self) -> String {
+        let format = self.get_format().map(|f| f.to_string());
+        [format, self.metadata.fp.clone(), self.metadata.size.clone(), self.file_type.clone()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
     fn get_file_metadata(&self) -> Vec<String> {
         let mut file_metadata: Vec<String> = Vec::new();
         file_metadata.push(format!("Filename: {}", self.get_name()));
         file_metadata.push(format!("Url: {}", self.get_url()));
         file_metadata.push(format!("File Id: {}", self.get_id()));
         file_metadata.push(format!("File Size (KB): {}", self.get_size()));
+        let description = self.get_description();
+        if !description.is_empty() {
+            file_metadata.push(format!("File Format: {}", description));
+        }
+        if let Some(sha256) = self.get_sha256() {
+            file_metadata.push(format!("SHA256: {}", sha256));
+        }
+        let scans = [
+            ("Pickle Scan", &self.pickle_scan_result, &self.pickle_scan_message),
+            ("Virus Scan", &self.virus_scan_result, &self.virus_scan_message),
+        ];
+        for (scan, result, message) in scans {
+            let result = result.clone().unwrap_or_else(|| NO_SCAN.to_string());
+            match message {
+                Some(message) => file_metadata.push(format!("{}: {} ({})", scan, result, message)),
+                None => file_metadata.push(format!("{}: {}", scan, result)),
+            }
+        }
         file_metadata
     }
 }
@@ -420,7 +704,7 @@ use unicode_segmentation::UnicodeSegmentation;
 ///     trail - What to add at the end of the description (ex. ...)
 pub fn shorten_unicode(string: String, length: usize, trail: &str) -> String {
     let graphemes = string.grapheme_indices(true);
-    let graph_vec: Vec<_> = graphemes.take(length).into_iter().collect();
+    let graph_vec: Vec<_> = graphemes.take(length).collect();
     let mut unpacked: Vec<&str> = vec![];
     for grapheme in graph_vec {
         unpacked.push(grapheme.1)
@@ -428,6 +712,5 @@ pub fn shorten_unicode(string: String, length: usize, trail: &str) -> String {
     let joined = unpacked.join("");
     
     //dbg!{&graphemes};
-    let shortened = format!("{}{}", joined, trail);
-    shortened
+    format!("{}{}", joined, trail)
 }
@@ -0,0 +1,202 @@
+//! The index of models vorpal has installed.
+//!
+//! The vorpal CLI records every download it makes in a JSON file in the platform
+//! data directory, which is $XDG_DATA_HOME/vorpal/library.json (usually
+//! ~/.local/share/vorpal/library.json) on Linux. An entry says which Civitai
+//! model, version and file a local file came from, so it can be found again
+//! without hashing it.
+//!
+//! The download functions and DownloadQueue do not touch the index, since a file
+//! may still be converted or moved after it is downloaded. Programs using
+//! libvorpal record their own installs once a file has its final path:
+//!
+//!     let mut library = Library::load()?;
+//!     library.record(LibraryEntry::new(&version, &file, &path, &sha256));
+//!     library.save()?;
+//!
+//!     for entry in Library::load()?.find_by_version_id("264911") { ... }
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::{FileHashes, ModelFile, ModelVersion, VorpalError};
+
+const LIBRARY_DIR: &str = "vorpal";
+const LIBRARY_FILE: &str = "library.json";
+/// Written first, then renamed over the index, so a crash cannot leave half an index
+const TEMP_EXTENSION: &str = "json.tmp";
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// One installed model file
+pub struct LibraryEntry {
+    pub model_id: String,
+    pub version_id: String,
+    pub file_id: String,
+    pub model_name: Option<String>,
+    pub version_name: String,
+    /// Checkpoint, LORA, TextualInversion...
+    pub model_type: Option<String>,
+    pub base_model: Option<String>,
+    pub trained_words: Vec<String>,
+    /// SHA256 of the file on disk. This differs from hashes.sha256 if the file was
+    /// converted after it was downloaded.
+    pub sha256: String,
+    /// The hashes Civitai published for the file
+    pub hashes: FileHashes,
+    /// Absolute path of the file
+    pub path: PathBuf,
+    /// Seconds since the Unix epoch
+    pub downloaded_at: u64,
+}
+
+impl LibraryEntry {
+    /// An entry for file of version, saved at path with the given SHA256, downloaded now
+    pub fn new(version: &ModelVersion, file: &ModelFile, path: &Path, sha256: &str) -> Self {
+        LibraryEntry {
+            model_id: version.get_model_id(),
+            version_id: version.get_id(),
+            file_id: file.get_id(),
+            model_name: version.get_model_name(),
+            version_name: version.get_name(),
+            model_type: version.get_model_type(),
+            base_model: version.get_base_model(),
+            trained_words: version.get_trained_word_list(),
+            sha256: sha256.to_lowercase(),
+            hashes: file.get_hashes(),
+            path: fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            downloaded_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        }
+    }
+
+    /// The day the file was downloaded, as YYYY-MM-DD (UTC)
+    pub fn get_date(&self) -> String {
+        // Days since 1970-01-01 to a civil date, from Howard Hinnant's date algorithms
+        let days = (self.downloaded_at / SECONDS_PER_DAY) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+
+    /// A one line description like "Red Glitter v1.0 | LORA | SDXL 1.0 | 2024-01-12"
+    pub fn get_summary(&self) -> String {
+        let name = match &self.model_name {
+            Some(model) => format!("{} {}", model, self.version_name),
+            None => self.version_name.clone(),
+        };
+        let mut summary = vec![name];
+        summary.extend(self.model_type.clone());
+        summary.extend(self.base_model.clone());
+        summary.push(self.get_date());
+        summary.join(" | ")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+/// The installed models, as read from (and saved back to) the index file
+pub struct Library {
+    #[serde(skip)]
+    path: PathBuf,
+    entries: Vec<LibraryEntry>,
+}
+
+impl Library {
+    /// Location of the index, if the platform has a data directory
+    pub fn path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join(LIBRARY_DIR).join(LIBRARY_FILE))
+    }
+
+    /// Load the index from its default location. Returns an empty Library if there
+    /// is no index yet.
+    ///
+    /// Errors:
+    ///     - VorpalError::Library if the platform has no data directory
+    ///     - As load_from
+    pub fn load() -> Result<Library> {
+        match Library::path() {
+            Some(path) => Library::load_from(&path),
+            None => Err(VorpalError::Library { path: LIBRARY_FILE.to_string(), message: "this platform has no data directory".to_string() }),
+        }
+    }
+
+    /// Load the index at path. Returns an empty Library, which saves to path, if
+    /// the file does not exist.
+    ///
+    /// Errors:
+    ///     - If the file exists but cannot be read
+    ///     - VorpalError::Library if the file is not a valid index
+    pub fn load_from(path: &Path) -> Result<Library> {
+        let raw = match fs::read(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Library { path: path.to_path_buf(), entries: Vec::new() }),
+            Err(source) => return Err(VorpalError::FileRead { path: path.display().to_string(), source }),
+        };
+        let mut library: Library = serde_json::from_slice(&raw)
+            .map_err(|e| VorpalError::Library { path: path.display().to_string(), message: e.to_string() })?;
+        library.path = path.to_path_buf();
+        Ok(library)
+    }
+
+    /// Write the index back to where it was loaded from, creating its directory if needed
+    ///
+    /// Errors:
+    ///     - If the directory or file cannot be written
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|source| VorpalError::FileCreate { path: dir.display().to_string(), source })?;
+        }
+        let json = serde_json::to_vec_pretty(self)?;
+        let temp = self.path.with_extension(TEMP_EXTENSION);
+        fs::write(&temp, json).map_err(|source| VorpalError::FileWrite { path: temp.display().to_string(), source })?;
+        fs::rename(&temp, &self.path).map_err(|source| VorpalError::FileWrite { path: self.path.display().to_string(), source })
+    }
+
+    /// Add entry, replacing any entry for the same path
+    pub fn record(&mut self, entry: LibraryEntry) {
+        self.entries.retain(|existing| existing.path != entry.path);
+        self.entries.push(entry);
+    }
+
+    /// Forget the file at path, returning its entry if it had one
+    pub fn remove(&mut self, path: &Path) -> Option<LibraryEntry> {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let index = self.entries.iter().position(|entry| entry.path == path)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Every entry, oldest download first
+    pub fn get_entries(&self) -> &[LibraryEntry] {
+        &self.entries
+    }
+
+    pub fn find_by_path(&self, path: &Path) -> Option<&LibraryEntry> {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// The entry for a file with this SHA256, either as it is on disk or as Civitai published it
+    pub fn find_by_sha256(&self, sha256: &str) -> Option<&LibraryEntry> {
+        self.entries.iter().find(|entry| {
+            entry.sha256.eq_ignore_ascii_case(sha256)
+                || entry.hashes.sha256.as_ref().is_some_and(|published| published.eq_ignore_ascii_case(sha256))
+        })
+    }
+
+    /// Every installed file of a model version
+    pub fn find_by_version_id(&self, version_id: &str) -> Vec<&LibraryEntry> {
+        self.entries.iter().filter(|entry| entry.version_id == version_id).collect()
+    }
+
+    /// Every installed file of a model
+    pub fn find_by_model_id(&self, model_id: &str) -> Vec<&LibraryEntry> {
+        self.entries.iter().filter(|entry| entry.model_id == model_id).collect()
+    }
+}
@@ -1,40 +1,121 @@
-use clap::Parser;
+use clap::{ArgAction, Parser, Subcommand};
 use clap_num::number_range;
 use std::env;
-use anyhow::Result;
-use std::path::PathBuf;
-use std::fs::File;
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::fs::{self, File};
 use std::io;
 use std::io::Write;
+use futures_util::{StreamExt, TryStreamExt};
 use libvorpal::*;
 
+mod bar;
 mod test;
 
-const DEFAULT_COUNT: u8 = 15;
+const DEFAULT_COUNT: u32 = 15;
+const DEFAULT_JOBS: usize = 4;
+const MAX_JOBS: usize = 16;
+const MAX_SEGMENTS: usize = 16;
+const MAX_PAGE_SIZE: u32 = 100;
 const REPORT_FORMAT: &str = ".txt";
 const ENV_MODEL_DIR: &str = "MODEL_DIRECTORY";
-const ERR_COUNT_TOO_BIG: &str = "Vorpal: Maximum query count allowed by API is 100";
+const ENV_API_TOKEN: &str = "CIVITAI_API_TOKEN";
 const ERR_MUTUALLY_EXCLUSIVE: &str = "Vorpal: These arguments are mutually exclusive. The -m argument is meant for only downloading metadata, and the -o argument is for only downloading models.";
+const ERR_NO_CONFIG_DIR: &str = "Vorpal: This platform has no config directory for the config file";
+const ERR_NOT_SET: &str = "Vorpal: This setting is not set:";
+const MSG_CONFIG_FILE: &str = "Vorpal: Config file:";
+const MSG_CONFIG_SET: &str = "Vorpal: Set";
+/// Setting names ending with this are shown masked by config list
+const SECRET_SETTING: &str = "api_token";
+const ERR_GET_NEEDS_ID: &str = "Vorpal: get needs either --model-id or --version-id";
+const MSG_NOT_ON_CIVITAI: &str = "Vorpal: No file on Civitai matches this hash";
+const MSG_PICKLE_WARNING: &str = "Vorpal: Warning: this is a pickle file, which can run code when loaded. Only load it if you trust the uploader.";
+const MSG_SCAN_WARNING: &str = "Vorpal: Warning: this file failed Civitai's scans:";
+const MSG_SCANNED: &str = "Vorpal: Scanned";
+const MSG_SCAN_SAFE: &str = "Vorpal: Pickle scan passed. It only imports what PyTorch needs to load a model.";
+const MSG_SCAN_DANGEROUS: &str = "Vorpal: DANGER: loading this file would import:";
+const MSG_SAFETENSORS_SAFE: &str = "Vorpal: SafeTensor files hold no code, so there is nothing to scan.";
+const MSG_CONVERTED: &str = "Vorpal: Converted to";
+const MSG_REMOVED_PICKLE: &str = "Vorpal: Removed the pickle file";
+const MSG_NO_CIVITAI_METADATA: &str = "Vorpal: The file is not on Civitai (or Civitai could not be reached), so no model details were added";
+const ERR_ALREADY_SAFETENSORS: &str = "Vorpal: The file is already a SafeTensor file";
+const SAFETENSORS_EXTENSION: &str = "safetensors";
+const MSG_NO_HASH: &str = "Vorpal: Civitai has no SHA256 for this file, so it cannot be verified";
 const MSG_DRY_RUN: &str = "Vorpal: Performing dry run (no download)";
 const MSG_PLEASE_SELECT: &str = "Please enter the number of the desired model";
+const MSG_PLEASE_SELECT_VERSION: &str = "Please enter the number of the desired version (leave empty for the newest)";
 const MSG_WRITE_SUCCESS: &str = "Vorpal: Wrote metadata file";
 const ERR_WRITE_FAIL: &str = "Vorpal: An error occured when writing the metadata file.\nDo you have write permission?";
+const MSG_LIBRARY_WARNING: &str = "Vorpal: Warning: the download worked, but could not be added to the library index";
+const MSG_LIBRARY_EMPTY: &str = "Vorpal: No models have been downloaded yet";
+const MSG_MISSING: &str = "(missing)";
+const MSG_ALREADY_DOWNLOADED: &str = "Vorpal: Already downloaded, skipping";
+const MSG_RENAMED: &str = "Vorpal: A different file is already there, saving as";
 const MSG_DOWNLOAD_START: &str = "Vorpal: Starting download...";
 const MSG_DOWNLOAD_SUCCESS: &str = "Vorpal: Download successful! Enjoy your model!";
 const MSG_DOWNLOAD_FAIL: &str = "Vorpal: Download failed";
+const MSG_QUEUED: &str = "queued";
+const MSG_RUNNING: &str = "downloading";
+const MSG_DONE: &str = "done";
+const MSG_FAILED: &str = "failed";
+const ERR_SOME_FAILED: &str = "Vorpal: Some downloads failed";
+const ERR_SOME_UNFINISHED: &str = "Vorpal: Some downloads could not be converted or reported on";
 const STDIN_FAILED: &str = "Failed to get input";
 const STDIN_INVALID: &str = "Vorpal: Only input integers";
 const STDIN_OUT_OF_RANGE: &str = "Vorpal: The number you entered is not in the query";
 const STDIN_GETTING: &str = "Getting item: ";
 
-fn check_limit(s: &str) -> Result<u8, String> {
-    number_range(s, 0, 100)
+// Exit codes, one per class of VorpalError so scripts can tell failures apart
+const EXIT_GENERAL: u8 = 1;
+const EXIT_CONNECTION: u8 = 2;
+const EXIT_HTTP_STATUS: u8 = 3;
+const EXIT_JSON: u8 = 4;
+const EXIT_NO_RESULTS: u8 = 5;
+const EXIT_DOWNLOAD: u8 = 6;
+const EXIT_FILE: u8 = 7;
+const EXIT_AUTH: u8 = 8;
+const EXIT_NOT_FOUND: u8 = 9;
+const EXIT_VERIFY: u8 = 10;
+const EXIT_UNSAFE: u8 = 11;
+const EXIT_CONVERT: u8 = 12;
+
+/// Everything that decides what happens when a model version is fetched
+struct FetchSettings {
+    only_meta: bool,
+    only_model: bool,
+    dir: PathBuf,
+    download: DownloadOptions,
+    selector: FileSelector,
+    version: Option<VersionSelector>,
+    to_safetensors: bool,
+    template: FilenameTemplate,
+    on_collision: OnCollision,
+    router: Router,
+    create_dir: bool,
+}
+
+fn check_limit(s: &str) -> Result<u32, String> {
+    number_range(s, 1, u32::MAX)
+}
+
+fn check_jobs(s: &str) -> Result<usize, String> {
+    number_range(s, 1, MAX_JOBS)
+}
+
+fn check_segments(s: &str) -> Result<usize, String> {
+    number_range(s, 1, MAX_SEGMENTS)
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, disable_version_flag = true)]
 struct Args {
 
+    /// Print version (--version picks a model version instead).
+    #[arg(short = 'V', action = ArgAction::Version)]
+    print_version: Option<bool>,
+
     /// The name of the model to download. First result will be downloaded.
     model_name: Option<String>,
 
@@ -43,29 +124,34 @@ struct Args {
     get_first: bool,
 
     /// Specify a directory to download to. Overrides MODEL_DIRECTORY environment variable.
-    /// Currnet directory will be used if both are empty.
-    #[arg(short, long, value_name = "DIRECTORY")]
-    directory: Option<PathBuf>,
+    /// Currnet directory will be used if both are empty. ~, $VAR and ${VAR} are expanded, and
+    /// @name (or @name/subfolder) uses an alias from the config file.
+    #[arg(short, long, value_name = "DIRECTORY", global = true)]
+    directory: Option<String>,
+
+    /// Create the download directory if it does not exist, instead of stopping.
+    #[arg(long, default_value_t = false, global = true)]
+    create_dir: bool,
 
     /// Only download model (don't save metadata).
-    #[arg(short, long, default_value_t = false)]
+    #[arg(short, long, default_value_t = false, global = true)]
     only_model: bool,
 
     /// Only get metadata of model.
-    #[arg(short, long, value_name = "MODEL_NAME")]
+    #[arg(short, long, value_name = "MODEL_NAME", global = true)]
     meta: bool,
 
-    /// Search Civitai for available models and LoRAs.
-    #[arg(short, long, value_name = "QUERY")]
+    /// Search Civitai for available models and LoRAs. Can be left empty to search by filters only.
+    #[arg(short, long, value_name = "QUERY", num_args = 0..=1, default_missing_value = "")]
     query: Option<String>,
 
-    /// How many models to search 
+    /// How many models to search. Counts over 100 are fetched a page at a time.
     #[arg(short, long, default_value_t = DEFAULT_COUNT, value_name = "COUNT", value_parser=check_limit)]
-    count: u8,
+    count: u32,
     
-    /// Enter query as 'safe' (no NSFW).
-    #[arg(short, long, default_value_t = false, value_name = "QUERY")]
-    safe: bool,
+    /// Enter query as 'safe' (no NSFW). --safe=false turns off safe in the config file.
+    #[arg(short, long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    safe: Option<bool>,
 
     /// Show full descriptions of query.
     #[arg(short, long, default_value_t = false)]
@@ -75,126 +161,844 @@ struct Args {
     #[arg(short, long, value_name = "MODEL_NAME")]
     url: Option<String>,
 
+    /// Only search for these model types (Checkpoint, LORA, TextualInversion, VAE, Controlnet...).
+    #[arg(long = "type", value_name = "TYPE", value_delimiter = ',')]
+    types: Vec<ModelType>,
+
+    /// Order results by Highest Rated, Most Downloaded, or Newest.
+    #[arg(long, value_name = "SORT")]
+    sort: Option<Sort>,
+
+    /// Time period to sort over (AllTime, Year, Month, Week, Day).
+    #[arg(long, value_name = "PERIOD")]
+    period: Option<Period>,
+
+    /// Only search for models trained on these base models (ex. "SD 1.5", "SDXL 1.0").
+    #[arg(long, value_name = "BASE_MODEL", value_delimiter = ',')]
+    base_model: Vec<String>,
+
+    /// Only search for models with this tag.
+    #[arg(long, value_name = "TAG")]
+    tag: Option<String>,
+
+    /// Only search for models uploaded by this user.
+    #[arg(long, value_name = "USERNAME")]
+    username: Option<String>,
+
+    /// Only search your favorited models (requires an API token).
+    #[arg(long, default_value_t = false)]
+    favorites: bool,
+
+    /// Civitai API token for gated downloads. Overrides CIVITAI_API_TOKEN environment variable
+    /// and the api_token setting in the config file.
+    #[arg(short, long, value_name = "TOKEN", global = true)]
+    token: Option<String>,
+
+    /// What to do with a download that fails its SHA256, size or format check: delete, keep, or quarantine
+    /// (move to <name>.corrupt). Pickles that fail the scan are always quarantined.
+    #[arg(long, value_name = "ACTION", default_value = "quarantine", global = true)]
+    on_mismatch: MismatchAction,
+
+    /// Pick a model version instead of the newest: by name ("v2.0", "inpainting"), by position
+    /// in the version list ("#2"), or by version Id.
+    #[arg(long, value_name = "VERSION", global = true)]
+    version: Option<VersionSelector>,
+
+    /// Only download files in this format (safetensors, pickletensor, gguf, diffusers).
+    #[arg(long, value_name = "FORMAT", global = true)]
+    format: Option<FileFormat>,
+
+    /// Only download files with this precision (fp16, fp32, bf16).
+    #[arg(long, value_name = "PRECISION", global = true)]
+    precision: Option<String>,
+
+    /// Only download pruned or full files.
+    #[arg(long, value_name = "SIZE", global = true)]
+    size: Option<String>,
+
+    /// Download a file of this type instead of the model (Model, Pruned Model, VAE, Config, Training Data).
+    #[arg(long, value_name = "TYPE", global = true)]
+    file_type: Option<String>,
+
+    /// Allow downloading pickle files (.ckpt, .pt) when a version has no SafeTensor file.
+    /// Pickles can run code when they are loaded.
+    #[arg(long, default_value_t = false, global = true)]
+    allow_pickle: bool,
+
+    /// Allow downloading files that failed Civitai's pickle or virus scan, or vorpal's own
+    /// pickle scan.
+    #[arg(long, default_value_t = false, global = true)]
+    allow_unsafe: bool,
+
+    /// Convert downloaded pickle files to SafeTensor files, then delete the pickle.
+    /// Implies --allow-pickle.
+    #[arg(long, default_value_t = false, global = true)]
+    to_safetensors: bool,
+
+    /// How to name downloaded files, ex. "{model}-{version}-{versionId}.{ext}". Fields: {filename},
+    /// {name}, {ext}, {model}, {modelId}, {version}, {versionId}, {baseModel}, {type}, {format},
+    /// {precision}, {size}. Overrides the filename_template setting in the config file.
+    #[arg(long, value_name = "TEMPLATE", global = true)]
+    filename_template: Option<FilenameTemplate>,
+
+    /// What to do when a file is already where a model or metadata file would be saved: skip
+    /// (if it is the same file, otherwise rename), rename (to <name>_1.<ext>), overwrite, or fail.
+    /// Overrides the on_collision setting in the config file. Defaults to skip.
+    #[arg(long, value_name = "POLICY", global = true)]
+    on_collision: Option<OnCollision>,
+
+    /// Sort downloads into the folders a UI expects for each model type, treating the download
+    /// directory as the UI's root folder: flat (no sorting), webui (AUTOMATIC1111 and Forge), or
+    /// comfyui. Overrides the layout setting in the config file. Defaults to flat.
+    #[arg(long, value_name = "LAYOUT", global = true)]
+    layout: Option<Layout>,
+
+    /// Split each download into this many parallel connections. Falls back to one
+    /// connection if the server does not support it.
+    #[arg(long, value_name = "SEGMENTS", default_value_t = 1, value_parser = check_segments, global = true)]
+    segments: usize,
+
+    /// Use the settings of this profile in the config file, on top of its defaults.
+    #[arg(long, value_name = "PROFILE", global = true)]
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download models by Id instead of by name. With --model-id, the newest version is used
+    /// unless --version is given.
+    /// Several Ids can be given, comma separated, to download them in parallel.
+    Get {
+        /// Model Ids (the number after /models/ in a Civitai url).
+        #[arg(long, value_name = "ID", value_delimiter = ',', required_unless_present = "version_id")]
+        model_id: Vec<u32>,
+
+        /// Model version Ids (the modelVersionId in a Civitai url).
+        #[arg(long, value_name = "ID", value_delimiter = ',')]
+        version_id: Vec<u32>,
+
+        /// How many downloads to run at once. Overrides the jobs setting in the config file.
+        /// Defaults to 4.
+        #[arg(short, long, value_name = "JOBS", value_parser = check_jobs)]
+        jobs: Option<usize>,
+    },
+    /// List every version of a model, with its base model, date and Id.
+    Versions {
+        /// The model Id (the number after /models/ in a Civitai url).
+        #[arg(value_name = "MODEL_ID")]
+        model_id: u32,
+    },
+    /// Check pickle model files (.ckpt, .pt) for code that would run when they are loaded,
+    /// without loading them.
+    Scan {
+        /// Model files to scan.
+        #[arg(value_name = "FILE", required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Convert a PyTorch checkpoint (.ckpt, .pt) to a SafeTensor file, without loading it.
+    /// If the file is on Civitai, the model's details are saved in the new file.
+    Convert {
+        /// The checkpoint to convert.
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Where to save the SafeTensor file. Defaults to FILE with a .safetensors extension.
+        #[arg(long, value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+    },
+    /// Show what is in a SafeTensor file: tensor count, data types, size and any LoRA
+    /// training settings, plus the Civitai metadata file saved next to it, if there is one.
+    Inspect {
+        /// The SafeTensor file to inspect.
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+    /// Find out which Civitai model a local file (or hash) belongs to.
+    Identify {
+        /// Model files to hash, or hashes (SHA256, AutoV2, BLAKE3, CRC32) to look up directly.
+        #[arg(value_name = "FILE_OR_HASH", required = true)]
+        targets: Vec<String>,
+    },
+    /// List the models vorpal has downloaded, with where they are.
+    List,
+    /// Read or change the config file. With --profile, settings are read from and saved to
+    /// that profile.
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
 
-fn print_query(mut query: Vec<QueryItem>, full: bool) -> () {
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print a setting's value.
+    Get {
+        /// The setting (ex. layout, jobs, aliases.sdxl).
+        #[arg(value_name = "KEY")]
+        key: String,
+    },
+    /// Change a setting, creating the config file if needed.
+    Set {
+        /// The setting: directory, layout, safe, api_token, jobs, format, precision, size,
+        /// file_type, filename_template, on_collision, type_dirs.<TYPE> or aliases.<NAME>.
+        #[arg(value_name = "KEY")]
+        key: String,
+
+        #[arg(value_name = "VALUE")]
+        value: String,
+    },
+    /// Print every setting in the config file, profiles included.
+    List,
+}
+
+
+fn print_query(mut query: Vec<QueryItem>, full: bool) {
     query.reverse();
     let output = concatenate_query_items(query, full);
     println!("{}", output);
 }
 
-fn download_first(model_name: String, safe: bool, only_meta: bool, only_model: bool, dir: PathBuf) -> () {
-    let model = get_first_query_item(model_name, safe);
-    if !only_meta { download(model.clone(), dir.clone()) }
-    if !only_model { write_report(model, dir) }
+async fn download_first(client: &CivitaiClient, params: SearchParams, settings: &FetchSettings) -> Result<()> {
+    let model = search(client, params, 1).await?.remove(0);
+    let version = choose_version(&model, settings, false)?;
+    fetch(client, &version, settings).await
+}
+
+/// The version of item to fetch: the one asked for with --version, otherwise the
+/// newest. When interactive and there are several versions, the user picks one.
+fn choose_version(item: &QueryItem, settings: &FetchSettings, interactive: bool) -> Result<ModelVersion> {
+    if let Some(wanted) = &settings.version {
+        return Ok(item.get_version(wanted)?)
+    }
+    let versions = item.generate_version_list();
+    if !interactive || versions.len() < 2 {
+        return Ok(item.get_first()?)
+    }
+    println!("{}", versions.join("\n"));
+    println!("{}", MSG_PLEASE_SELECT_VERSION);
+    let selection = match read_line()?.as_str() {
+        "" => 1,
+        line => parse_selection(line, versions.len())?,
+    };
+    Ok(item.get_version(&VersionSelector::Index(selection))?)
+}
+
+fn read_line() -> Result<String> {
+    let mut user_input = String::new();
+    io::stdin()
+        .read_line(&mut user_input)
+        .map_err(|e| anyhow!("{}\n{}", STDIN_FAILED, e))?;
+    Ok(user_input.trim().to_string())
+}
+
+/// Parse a 1-based selection out of len items
+fn parse_selection(input: &str, len: usize) -> Result<usize> {
+    let selection = match input.parse::<usize>() {
+        Ok(i) => {
+            println!("{}{}", STDIN_GETTING, i);
+            i
+        },
+        Err(..) => return Err(anyhow!(STDIN_INVALID)),
+    };
+    if selection == 0 || selection > len { return Err(anyhow!(STDIN_OUT_OF_RANGE)) }
+    Ok(selection)
+}
+
+/// Look up each file or hash on Civitai and print what it is. Files that are not
+/// found are reported and skipped, so one unknown file does not stop the rest.
+async fn identify(client: &CivitaiClient, targets: Vec<String>) -> Result<()> {
+    for target in targets {
+        let path = PathBuf::from(&target);
+        let found = match path.is_file() {
+            true => client.get_model_version_by_file(&path).await,
+            false => client.get_model_version_by_hash(&target).await,
+        };
+        println!("\n[{}]=========", target);
+        match found {
+            Ok(version) => println!("{}", version.generate_model_report().join("\n")),
+            Err(VorpalError::NotFound { .. }) => println!("{}", MSG_NOT_ON_CIVITAI),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Download a model version and/or write its metadata report
+async fn fetch(client: &CivitaiClient, version: &ModelVersion, settings: &FetchSettings) -> Result<()> {
+    let file = version.get_file(&settings.selector)?;
+    directory::prepare(&settings.dir, settings.create_dir)?;
+    let path = route(version, &file, settings)?.join(settings.template.render(version, &file));
+    if settings.only_meta {
+        // With --only-model too, this is a dry run
+        if !settings.only_model { write_report(version, &file, &output_path(&path, &file, settings), settings.on_collision)? }
+        return Ok(())
+    }
+    let (mut path, mut sha256) = download(client, &file, path, settings).await?;
+    if settings.to_safetensors && file.is_pickle() {
+        path = replace_with_safetensors(&path, version, settings.on_collision)?;
+        sha256 = hash::sha256_file(&path)?;
+    }
+    if !settings.only_model { write_report(version, &file, &path, settings.on_collision)? }
+    record_installs(vec![LibraryEntry::new(version, &file, &path, &sha256)]);
+    Ok(())
+}
+
+/// Download several model versions at once, jobs at a time. Every download is
+/// attempted, and every finished one converted and reported on, even if some
+/// fail; the first failure is returned at the end.
+async fn fetch_all(client: &CivitaiClient, versions: Vec<ModelVersion>, jobs: usize, settings: &FetchSettings) -> Result<()> {
+    directory::prepare(&settings.dir, settings.create_dir)?;
+    let mut jobs_list = Vec::new();
+    for version in versions {
+        let dir = match version.get_file(&settings.selector) {
+            Ok(file) => route(&version, &file, settings)?,
+            // Reported when the job fails
+            Err(_) => settings.dir.clone(),
+        };
+        jobs_list.push(DownloadJob::new(version, dir)
+            .selector(settings.selector.clone())
+            .template(settings.template.clone())
+            .on_collision(settings.on_collision));
+    }
+    if settings.only_meta {
+        // With --only-model too, this is a dry run
+        if settings.only_model { return Ok(()) }
+        // Versions without a matching file have nothing to report on
+        for job in &jobs_list {
+            if let (Ok(file), Ok(path)) = (job.get_file(), job.get_path()) {
+                write_report(job.get_version(), &file, &output_path(&path, &file, settings), settings.on_collision)?;
+            }
+        }
+        return Ok(())
+    }
+
+    let total = jobs_list.len();
+    let status = move |job: &DownloadJob, status: &JobStatus| {
+        let name = match job.get_file() {
+            Ok(file) => file.get_name(),
+            Err(_) => job.get_version().get_name(),
+        };
+        match status {
+            JobStatus::Queued => println!("Vorpal: [{}] {}", MSG_QUEUED, name),
+            JobStatus::Running => println!("Vorpal: [{}] {}", MSG_RUNNING, name),
+            JobStatus::Done(_) => println!("Vorpal: [{}] {}", MSG_DONE, name),
+            JobStatus::Failed(e) => println!("Vorpal: [{}] {}\n{}", MSG_FAILED, name, e),
+        }
+    };
+    // Progress bars for several jobs would draw over each other, so only job status is shown
+    let outcomes = DownloadQueue::new(client.clone())
+        .concurrency(jobs)
+        .options(settings.download.clone())
+        .observer(Arc::new(status))
+        .extend(jobs_list)
+        .run()
+        .await;
+
+    // Reports are written once each file's final name is known. A job that cannot
+    // be finished is reported and the others carry on, as their files are on disk
+    let mut installs = Vec::new();
+    let mut unfinished = Vec::new();
+    for outcome in &outcomes {
+        let (Ok(sha256), Some(path)) = (&outcome.result, &outcome.path) else { continue };
+        match finish_job(&outcome.job, path, sha256, settings) {
+            Ok(entry) => installs.push(entry),
+            Err(e) => {
+                println!("Vorpal: [{}] {}\n{}", MSG_FAILED, path.display(), e);
+                unfinished.push(e);
+            },
+        }
+    }
+    record_installs(installs);
+    let mut failures: Vec<anyhow::Error> = outcomes.into_iter()
+        .filter_map(|o| o.result.err())
+        .map(Into::into)
+        .collect();
+    println!("Vorpal: {} of {} downloads succeeded", total - failures.len(), total);
+    if !failures.is_empty() { println!("{}", ERR_SOME_FAILED) }
+    if !unfinished.is_empty() { println!("{}", ERR_SOME_UNFINISHED) }
+    failures.extend(unfinished);
+    match failures.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Convert (with --to-safetensors) and report on a job the queue downloaded to
+/// path, returning its library entry
+fn finish_job(job: &DownloadJob, path: &Path, sha256: &str, settings: &FetchSettings) -> Result<LibraryEntry> {
+    let (file, version) = (job.get_file()?, job.get_version());
+    let (path, sha256) = match settings.to_safetensors && file.is_pickle() {
+        true => {
+            let converted = replace_with_safetensors(path, version, settings.on_collision)?;
+            let sha256 = hash::sha256_file(&converted)?;
+            (converted, sha256)
+        },
+        false => (path.to_path_buf(), sha256.to_string()),
+    };
+    if !settings.only_model { write_report(version, &file, &path, settings.on_collision)? }
+    Ok(LibraryEntry::new(version, &file, &path, &sha256))
+}
+
+/// Where the model downloaded to path ends up: the path itself, or the SafeTensor
+/// file it is converted to with --to-safetensors
+fn output_path(path: &Path, file: &ModelFile, settings: &FetchSettings) -> PathBuf {
+    match settings.to_safetensors && file.is_pickle() {
+        true => path.with_extension(SAFETENSORS_EXTENSION),
+        false => path.to_path_buf(),
+    }
+}
+
+/// The folder to save file of version in, created if the layout sorts it into a
+/// folder of its own
+fn route(version: &ModelVersion, file: &ModelFile, settings: &FetchSettings) -> Result<PathBuf> {
+    let dir = settings.router.get_dir(&settings.dir, version, file);
+    if dir != settings.dir {
+        fs::create_dir_all(&dir).map_err(|source| VorpalError::FileCreate { path: dir.display().to_string(), source })?;
+    }
+    Ok(dir)
+}
+
+/// Collect the file filters given on the command line
+fn file_selector(args: &Args) -> FileSelector {
+    let mut selector = FileSelector::new()
+        .allow_pickle(args.allow_pickle || args.to_safetensors)
+        .allow_unsafe(args.allow_unsafe);
+    if let Some(format) = args.format { selector = selector.format(format) }
+    if let Some(precision) = &args.precision { selector = selector.precision(precision) }
+    if let Some(size) = &args.size { selector = selector.size(size) }
+    if let Some(file_type) = &args.file_type { selector = selector.file_type(file_type) }
+    selector
+}
+
+/// Collect the search filters given on the command line
+fn search_params(args: &Args) -> SearchParams {
+    let mut params = SearchParams::new()
+        .safe(args.safe.unwrap_or(false))
+        .types(args.types.clone())
+        .base_models(args.base_model.clone())
+        .favorites(args.favorites);
+    if let Some(sort) = args.sort { params = params.sort(sort) }
+    if let Some(period) = args.period { params = params.period(period) }
+    if let Some(tag) = &args.tag { params = params.tag(tag) }
+    if let Some(username) = &args.username { params = params.username(username) }
+    params
+}
+
+/// Search Civitai for up to count items, paging past the API's 100 item limit if needed
+async fn search(client: &CivitaiClient, params: SearchParams, count: u32) -> Result<Vec<QueryItem>> {
+    let page_size = count.min(MAX_PAGE_SIZE) as u8;
+    let items: Vec<QueryItem> = client.search_stream(&params.limit(page_size))
+        .take(count as usize)
+        .try_collect()
+        .await?;
+    // A model without versions or files has nothing to show or download
+    let items: Vec<QueryItem> = items.into_iter()
+        .filter(|item| item.get_first().and_then(|version| version.get_latest_file()).is_ok())
+        .collect();
+    if items.is_empty() { return Err(VorpalError::NoResults.into()) }
+    Ok(items)
 }
 
 fn concatenate_query_items(queries: Vec<QueryItem>, full: bool) -> String {
     let mut cli_output = String::new();
-    let mut i = queries.len() as u8;
-    queries.iter().for_each(|q| -> () {
-            let item_header = format!("\n[{}]=========\n", i);
-            cli_output.push_str(&item_header);
-            cli_output.push_str(q.make_cli_query_display(full).as_str());
+    let mut i = queries.len();
+    queries.iter().for_each(|q| {
+            // Items with nothing to show keep their number, so selections still line up
+            if let Ok(display) = q.make_cli_query_display(full) {
+                let item_header = format!("\n[{}]=========\n", i);
+                cli_output.push_str(&item_header);
+                cli_output.push_str(display.as_str());
+            }
             i -= 1;
         });
     cli_output
 }
 
-#[tokio::main]
-async fn download(model: QueryItem, dir: PathBuf) {
-    let test = model.get_download_url();
-    let filename = model.get_model_filename();
-    let size_mb = model.get_model_filesize() * 0.001;
-    let file_path = format!("{}/{}", dir.display(), filename);
-    println!("{} {:.2}MB", MSG_DOWNLOAD_START, size_mb);
-    match download_file_by_url(test, file_path).await {
-        Ok(_) => println!("{}", MSG_DOWNLOAD_SUCCESS),
-        Err(e) => println!("{}\n{}", e, MSG_DOWNLOAD_FAIL),
+/// Download model_file to path, or wherever the collision policy moves it to.
+/// Returns the path the file was saved at, and its SHA256.
+async fn download(client: &CivitaiClient, model_file: &ModelFile, path: PathBuf, settings: &FetchSettings) -> Result<(PathBuf, String)> {
+    let file_path = match filename::resolve(&path, settings.on_collision, model_file.get_sha256().as_deref())? {
+        filename::Destination::Existing(existing, sha256) => {
+            println!("{} {}", MSG_ALREADY_DOWNLOADED, existing.display());
+            return Ok((existing, sha256))
+        },
+        filename::Destination::Write(target) => {
+            if target != path { println!("{} {}", MSG_RENAMED, target.display()) }
+            target
+        },
+    };
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let size_mb = model_file.get_size_bytes() as f64 / 1_048_576.0;
+    println!("{} {} {:.2}MB {}", MSG_DOWNLOAD_START, name, size_mb, model_file.get_description());
+    if model_file.get_sha256().is_none() { println!("{}", MSG_NO_HASH) }
+    if model_file.is_pickle() { println!("{}", MSG_PICKLE_WARNING) }
+    if model_file.scan_failed() { println!("{} {}", MSG_SCAN_WARNING, model_file.get_scan_summary()) }
+    let options = settings.download.clone().progress(Arc::new(bar::ProgressBar::new()));
+    let sha256 = match client.download_model_file(model_file, file_path.display().to_string(), &options).await {
+        Ok(sha256) => {
+            println!("{}", MSG_DOWNLOAD_SUCCESS);
+            sha256
+        },
+        Err(e) => {
+            println!("{}", MSG_DOWNLOAD_FAIL);
+            return Err(e.into())
+        },
     };
+    if model_file.is_pickle() {
+        // The download was only kept if the library's scan passed, unless the scan was
+        // skipped to allow dangerous pickles. Then the user should see what it imports.
+        match settings.download.get_allow_dangerous_pickle() {
+            true => print_scan_report(&pickle::scan_file(&file_path)?),
+            false => println!("{}", MSG_SCAN_SAFE),
+        }
+    }
+    Ok((file_path, sha256))
+}
+
+/// Add downloaded files to the library index. The downloads themselves worked, so
+/// an index that cannot be updated is only a warning.
+fn record_installs(installs: Vec<LibraryEntry>) {
+    if installs.is_empty() { return }
+    let recorded = Library::load().and_then(|mut library| {
+        for entry in installs {
+            library.record(entry);
+        }
+        library.save()
+    });
+    if let Err(e) = recorded { println!("{}\n{}", MSG_LIBRARY_WARNING, e) }
+}
+
+/// Print every model in the library index, with where it is and its trigger words
+fn list_library() -> Result<()> {
+    let library = Library::load()?;
+    if library.get_entries().is_empty() {
+        println!("{}", MSG_LIBRARY_EMPTY);
+        return Ok(())
+    }
+    for (i, entry) in library.get_entries().iter().enumerate() {
+        let missing = if entry.path.exists() { "" } else { MSG_MISSING };
+        println!("\n[{}]=========\n{}", i + 1, entry.get_summary());
+        println!("    {} {}", entry.path.display(), missing);
+        if !entry.trained_words.is_empty() {
+            println!("    Trained Words: {}", entry.trained_words.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Scan each pickle file and print what it imports. Every file is scanned; the
+/// first dangerous or unreadable one is returned as the error.
+fn scan(files: Vec<PathBuf>) -> Result<()> {
+    let mut failure = None;
+    for file in files {
+        println!("\n[{}]=========", file.display());
+        if FileFormat::from_filename(&file.to_string_lossy()) == Some(FileFormat::SafeTensor) {
+            println!("{}", MSG_SAFETENSORS_SAFE);
+            continue
+        }
+        match pickle::scan_file(&file) {
+            Ok(report) => {
+                print_scan_report(&report);
+                if !report.is_safe() && failure.is_none() {
+                    failure = Some(VorpalError::DangerousPickle {
+                        path: Some(file.display().to_string()),
+                        imports: report.get_dangerous().iter().map(|i| i.to_string()).collect(),
+                    });
+                }
+            },
+            Err(e) => {
+                println!("{}", e);
+                failure.get_or_insert(e);
+            },
+        }
+    }
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+/// Print a SafeTensor file's header, followed by its Civitai metadata file if vorpal saved one
+fn inspect(file: &Path) -> Result<()> {
+    let header = safetensors::read_header(file)?;
+    println!("[{}]=========", file.display());
+    println!("{}", header.generate_report().join("\n"));
+
+    let report_path = PathBuf::from(format!("{}{}", file.display(), REPORT_FORMAT));
+    if report_path.exists() {
+        let report = fs::read_to_string(&report_path)
+            .map_err(|source| VorpalError::FileRead { path: report_path.display().to_string(), source })?;
+        println!("\n[{}]=========\n{}", report_path.display(), report);
+    }
+    Ok(())
 }
 
-fn write_report(model: QueryItem, dir: PathBuf) -> () {
-    let filename = model.get_model_filename();
-    let report = model.generate_model_report().join("\n");
-    let file_path = format!("{}/{}{}", dir.display(), filename, REPORT_FORMAT);
-    let file = File::create(file_path);
-    let written = file.expect(ERR_WRITE_FAIL).write_all(&report.as_bytes());
+/// Convert a checkpoint to a SafeTensor file, saving the version's details in its header
+fn convert(src: &Path, dest: &Path, version: Option<&ModelVersion>) -> Result<()> {
+    if src.extension().is_some_and(|ext| ext == SAFETENSORS_EXTENSION) {
+        return Err(anyhow!(ERR_ALREADY_SAFETENSORS))
+    }
+    let metadata = version.map(convert::civitai_metadata).unwrap_or_default();
+    let count = convert::convert_checkpoint(src, dest, &metadata)?;
+    println!("{} {} ({} tensors)", MSG_CONVERTED, dest.display(), count);
+    Ok(())
+}
+
+/// Convert a downloaded pickle file, then delete it. Returns the SafeTensor file's
+/// path, which on_collision may have renamed.
+fn replace_with_safetensors(path: &Path, version: &ModelVersion, on_collision: OnCollision) -> Result<PathBuf> {
+    // Without a SHA256 to compare, an existing file is never taken to be the conversion
+    let converted = match filename::resolve(&path.with_extension(SAFETENSORS_EXTENSION), on_collision, None)? {
+        filename::Destination::Write(target) | filename::Destination::Existing(target, _) => target,
+    };
+    convert(path, &converted, Some(version))?;
+    fs::remove_file(path).map_err(|source| VorpalError::FileDelete { path: path.display().to_string(), source })?;
+    println!("{} {}", MSG_REMOVED_PICKLE, path.display());
+    Ok(converted)
+}
+
+fn print_scan_report(report: &pickle::ScanReport) {
+    let dangerous = report.get_dangerous();
+    println!("{} {} ({} imports)", MSG_SCANNED, report.pickles.join(", "), report.imports.len());
+    match dangerous.is_empty() {
+        true => println!("{}", MSG_SCAN_SAFE),
+        false => {
+            println!("{}", MSG_SCAN_DANGEROUS);
+            for import in dangerous {
+                println!("    {}", import);
+            }
+        },
+    }
+}
+
+/// Write the metadata report for the model file at model_path next to it, as <model_path>.txt
+fn write_report(model: &ModelVersion, file: &ModelFile, model_path: &Path, on_collision: OnCollision) -> Result<()> {
+    let report = model.generate_file_report(file).join("\n");
+    let report_path = PathBuf::from(format!("{}{}", model_path.display(), REPORT_FORMAT));
+    let file_path = match filename::resolve(&report_path, on_collision, Some(&hash::sha256_bytes(report.as_bytes())))? {
+        filename::Destination::Existing(..) => return Ok(()),
+        filename::Destination::Write(target) => target,
+    };
+    let written = File::create(&file_path)
+        .and_then(|mut file| file.write_all(report.as_bytes()));
     match written {
         Ok(()) => println!("{}", MSG_WRITE_SUCCESS),
-        Err(e) => println!("{}\n{}", e, ERR_WRITE_FAIL),
+        Err(source) => {
+            println!("{}", ERR_WRITE_FAIL);
+            return Err(VorpalError::FileWrite { path: file_path.display().to_string(), source }.into())
+        },
+    }
+    Ok(())
+}
+
+/// Read or edit the config file
+fn config_command(action: ConfigAction, profile: Option<&str>) -> Result<()> {
+    let path = config::Config::path().ok_or_else(|| anyhow!(ERR_NO_CONFIG_DIR))?;
+    match action {
+        ConfigAction::Get { key } => match config::get_value(&path, profile, &key)? {
+            Some(value) => println!("{}", value),
+            None => return Err(anyhow!("{} {}", ERR_NOT_SET, key)),
+        },
+        ConfigAction::Set { key, value } => {
+            config::set_value(&path, profile, &key, &value)?;
+            println!("{} {} = {}", MSG_CONFIG_SET, key, value);
+        },
+        ConfigAction::List => {
+            println!("{} {}", MSG_CONFIG_FILE, path.display());
+            for (key, value) in config::list_values(&path)? {
+                let value = match key.ends_with(SECRET_SETTING) {
+                    true => "\"********\"".to_string(),
+                    false => value,
+                };
+                println!("{} = {}", key, value);
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Fill in the settings not given on the command line from the config profile.
+/// The directory and API token are left alone, since their environment variables
+/// come before the config.
+fn apply_profile(args: &mut Args, profile: &config::Profile) {
+    args.safe = args.safe.or(profile.safe);
+    args.format = args.format.or(profile.format);
+    args.precision = args.precision.take().or_else(|| profile.precision.clone());
+    args.size = args.size.take().or_else(|| profile.size.clone());
+    args.file_type = args.file_type.take().or_else(|| profile.file_type.clone());
+    args.layout = args.layout.or(profile.layout);
+    args.filename_template = args.filename_template.take().or_else(|| profile.filename_template.clone());
+    args.on_collision = args.on_collision.or(profile.on_collision);
+    if let Some(Command::Get { jobs, .. }) = &mut args.command {
+        *jobs = jobs.or(profile.jobs);
+    }
+}
+
+/// Build the Civitai client, taking the API token from (in order) the --token flag,
+/// the CIVITAI_API_TOKEN environment variable, or the config file
+fn make_client(token: Option<String>, profile: &config::Profile) -> Result<CivitaiClient> {
+    let token = token
+        .or_else(|| env::var(ENV_API_TOKEN).ok())
+        .or_else(|| profile.api_token.clone());
+    let mut builder = CivitaiClient::builder();
+    if let Some(token) = token {
+        builder = builder.api_token(token);
     }
+    Ok(builder.build()?)
 }
 
-fn run(args: Args) -> Result<()> {
+async fn run(mut args: Args) -> Result<()> {
     //dbg!{&args};
+    // The config file may not load yet, and that is what this command fixes
+    if let Some(Command::Config { action }) = args.command {
+        return config_command(action, args.profile.as_deref())
+    }
+    let profile = config::Config::load()?.get_profile(args.profile.as_deref())?;
+    apply_profile(&mut args, &profile);
+
     let count = args.count;
-    if count > 100 { panic!("{}", ERR_COUNT_TOO_BIG )}
-    let safe = args.safe;
+    let params = search_params(&args);
+    let selector = file_selector(&args);
     let full = args.full;
     let only_model = args.only_model;
     let only_meta = args.meta;
     let get_first = args.get_first;
     //let model_name = args.model_name;
 
-    let env_directory = match env::var(ENV_MODEL_DIR).is_ok() {
-        true => PathBuf::from(env::var(ENV_MODEL_DIR).unwrap()),
-        false => env::current_dir().unwrap(),
+    let directory = args.directory
+        .or_else(|| env::var(ENV_MODEL_DIR).ok())
+        .or_else(|| profile.directory.clone());
+    let dir = match directory {
+        Some(directory) => directory::expand(&directory, &profile.aliases)?,
+        None => env::current_dir()?,
     };
-    let dir = match args.directory {
-        Some(directory) => directory,
-        None => env_directory,
+    let client = make_client(args.token, &profile)?;
+
+    let settings = FetchSettings {
+        only_meta,
+        only_model,
+        dir,
+        download: DownloadOptions::new()
+            .on_mismatch(args.on_mismatch)
+            .segments(args.segments)
+            .allow_dangerous_pickle(args.allow_unsafe),
+        selector,
+        version: args.version,
+        to_safetensors: args.to_safetensors,
+        template: args.filename_template.unwrap_or_default(),
+        on_collision: args.on_collision.unwrap_or_default(),
+        router: Router::new(args.layout.unwrap_or_default()).routes(&profile.type_dirs),
+        create_dir: args.create_dir,
     };
 
     if only_model && only_meta { println!("{}\n{}", ERR_MUTUALLY_EXCLUSIVE, MSG_DRY_RUN) }
 
-    if args.url.is_some() {
-        let u = args.url.unwrap();
-        let url = get_model_file_url(u);
+    match args.command {
+        Some(Command::Get { model_id, version_id, jobs }) => {
+            let jobs = check_jobs(&jobs.unwrap_or(DEFAULT_JOBS).to_string()).map_err(|e| anyhow!(e))?;
+            let mut versions = Vec::new();
+            for id in model_id {
+                versions.push(choose_version(&client.get_model(id).await?, &settings, false)?);
+            }
+            for id in version_id {
+                versions.push(client.get_model_version(id).await?);
+            }
+            match versions.len() {
+                0 => return Err(anyhow!(ERR_GET_NEEDS_ID)),
+                1 => fetch(&client, &versions[0], &settings).await?,
+                _ => fetch_all(&client, versions, jobs, &settings).await?,
+            }
+        },
+        Some(Command::Versions { model_id }) => {
+            let model = client.get_model(model_id).await?;
+            println!("{}", model.generate_version_list().join("\n"))
+        },
+        Some(Command::Identify { targets }) => identify(&client, targets).await?,
+        Some(Command::List) => list_library()?,
+        Some(Command::Config { .. }) => unreachable!("config commands return early"),
+        Some(Command::Scan { files }) => scan(files)?,
+        Some(Command::Inspect { file }) => inspect(&file)?,
+        Some(Command::Convert { file, output }) => {
+            let version = client.get_model_version_by_file(&file).await.ok();
+            if version.is_none() { println!("{}", MSG_NO_CIVITAI_METADATA) }
+            let output = output.unwrap_or_else(|| file.with_extension(SAFETENSORS_EXTENSION));
+            convert(&file, &output, version.as_ref())?
+        },
+        None => (),
+    }
+
+    if let Some(u) = args.url {
+        let url = client.get_model_file_url(u).await?;
         println!("{}", url);
     }
 
-    if args.query.is_some() {
-        let q = args.query.unwrap();
-        let query = get_query_items(q, count, safe);
+    if let Some(q) = args.query {
+        let query = search(&client, params.clone().query(q), count).await?;
         print_query(query, full)
     }
 
-    if args.model_name.is_some() {
-        let model_name = args.model_name.unwrap();
+    if let Some(model_name) = args.model_name {
         if !get_first {
-            let query = get_query_items(model_name, count, safe);
-            let len = query.len() + 1;
+            let query = search(&client, params.query(model_name), count).await?;
             print_query(query.clone(), full);
-            let mut user_input = String::new();
             println!("{}", MSG_PLEASE_SELECT);
-            io::stdin()
-                .read_line(&mut user_input)
-                .expect(STDIN_FAILED);
-            let trimmed = user_input.trim();
-            let user_selection: usize;
-            match trimmed.parse::<usize>() {
-                Ok(i) => {
-                    println!("{}{}", STDIN_GETTING, i); 
-                    user_selection = i; }
-                Err(..) => panic!("{}", STDIN_INVALID),
-            };
-            if user_selection >= len { panic!("{}", STDIN_OUT_OF_RANGE) }
-            else {
-                let desired_model = query[user_selection - 1].clone();
-                if !only_meta { download(desired_model.clone(), dir.clone()) }
-                if !only_model { write_report(desired_model, dir) }
-            }
+            let user_selection = parse_selection(&read_line()?, query.len())?;
+            let desired_model = choose_version(&query[user_selection - 1], &settings, true)?;
+            fetch(&client, &desired_model, &settings).await?
         } else {
-            download_first(model_name, safe, only_meta, only_model, dir)
+            download_first(&client, params.query(model_name), &settings).await?
         }
     }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
+/// Pick an exit code for an error coming out of run()
+fn exit_code(e: &anyhow::Error) -> u8 {
+    match e.downcast_ref::<VorpalError>() {
+        Some(VorpalError::Connection(_)) | Some(VorpalError::Timeout { .. }) => EXIT_CONNECTION,
+        Some(VorpalError::HttpStatus { .. }) => EXIT_HTTP_STATUS,
+        Some(VorpalError::AuthRequired { .. }) => EXIT_AUTH,
+        Some(VorpalError::NotFound { .. }) => EXIT_NOT_FOUND,
+        Some(VorpalError::JsonDecode(_)) => EXIT_JSON,
+        Some(VorpalError::NoResults)
+        | Some(VorpalError::NoMatchingFile { .. })
+        | Some(VorpalError::NoMatchingVersion { .. }) => EXIT_NO_RESULTS,
+        Some(VorpalError::HashMismatch { .. })
+        | Some(VorpalError::InvalidSafetensors { .. })
+        | Some(VorpalError::InvalidDownload { .. }) => EXIT_VERIFY,
+        Some(VorpalError::UnsafeFile { .. })
+        | Some(VorpalError::InvalidPickle { .. })
+        | Some(VorpalError::DangerousPickle { .. }) => EXIT_UNSAFE,
+        Some(VorpalError::Convert { .. }) => EXIT_CONVERT,
+        Some(VorpalError::Fetch(_))
+        | Some(VorpalError::Download(_))
+        | Some(VorpalError::Incomplete { .. })
+        | Some(VorpalError::RangeIgnored { .. })
+        | Some(VorpalError::UnexpectedContentType { .. }) => EXIT_DOWNLOAD,
+        Some(VorpalError::FileCreate { .. })
+        | Some(VorpalError::FileRead { .. })
+        | Some(VorpalError::FileWrite { .. })
+        | Some(VorpalError::FileDelete { .. })
+        | Some(VorpalError::FileExists { .. })
+        | Some(VorpalError::InvalidDirectory { .. })
+        | Some(VorpalError::Library { .. }) => EXIT_FILE,
+        Some(VorpalError::Client(_)) | Some(VorpalError::Config { .. }) | None => EXIT_GENERAL,
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
     let args = Args::parse();
-    run(args)
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(exit_code(&e))
+        },
+    }
 }
\ No newline at end of file
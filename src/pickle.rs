@@ -0,0 +1,492 @@
+//! Static safety scanner for pickle-based model files (.ckpt, .pt, .pth, .bin).
+//!
+//! Loading a pickle can run arbitrary code: any function named by a GLOBAL,
+//! STACK_GLOBAL or INST opcode gets imported and may be called. This module walks
+//! the opcode stream without executing any of it and lists every import, so
+//! anything outside ALLOWED_IMPORTS (the helpers PyTorch uses to rebuild tensors)
+//! can be flagged before the file is ever loaded.
+//!
+//! Both PyTorch formats are handled: zip checkpoints, where the pickle lives in
+//! `<archive>/data.pkl`, and the older format, which is a few pickles back to back.
+//!
+//!     let report = pickle::scan_file(Path::new("model.ckpt"))?;
+//!     for import in report.get_dangerous() {
+//!         println!("{}", import);
+//!     }
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use crate::error::Result;
+use crate::VorpalError;
+
+pub(crate) const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const PICKLE_EXTENSION: &str = ".pkl";
+/// The old PyTorch format is a magic number, protocol version, system info, the
+/// model itself and a list of storage keys, each pickled separately
+const LEGACY_PICKLE_COUNT: usize = 5;
+/// Strings longer than this cannot be a module or function name, so are not kept
+pub(crate) const MAX_NAME_LENGTH: u64 = 1024;
+/// What a STACK_GLOBAL with names that could not be worked out is reported as
+const UNKNOWN_NAME: &str = "<dynamic>";
+
+/// Imports a PyTorch checkpoint legitimately needs, as "module.name"
+pub const ALLOWED_IMPORTS: [&str; 30] = [
+    "collections.OrderedDict",
+    "torch._utils._rebuild_tensor",
+    "torch._utils._rebuild_tensor_v2",
+    "torch._utils._rebuild_parameter",
+    "torch._utils._rebuild_parameter_with_state",
+    "torch._utils._rebuild_device_tensor_from_numpy",
+    "torch.FloatStorage",
+    "torch.HalfStorage",
+    "torch.BFloat16Storage",
+    "torch.DoubleStorage",
+    "torch.LongStorage",
+    "torch.IntStorage",
+    "torch.ShortStorage",
+    "torch.CharStorage",
+    "torch.ByteStorage",
+    "torch.BoolStorage",
+    "torch.float16",
+    "torch.float32",
+    "torch.bfloat16",
+    "torch.Size",
+    "torch.nn.modules.container.ParameterDict",
+    "numpy.core.multiarray._reconstruct",
+    "numpy.core.multiarray.scalar",
+    "numpy.dtype",
+    "numpy.ndarray",
+    "_codecs.encode",
+    "builtins.set",
+    "__builtin__.set",
+    "torch.device",
+    "builtins.slice",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A function or class a pickle would import when loaded
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    /// The pickle it was found in: a zip entry name, or the file name
+    pub pickle: String,
+}
+
+impl Import {
+    /// Whether the import is on ALLOWED_IMPORTS
+    pub fn is_allowed(&self) -> bool {
+        let full = format!("{}.{}", self.module, self.name);
+        ALLOWED_IMPORTS.contains(&full.as_str())
+    }
+}
+
+impl fmt::Display for Import {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{} (in {})", self.module, self.name, self.pickle)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Everything a pickle file would import when loaded
+pub struct ScanReport {
+    /// The pickles that were scanned
+    pub pickles: Vec<String>,
+    pub imports: Vec<Import>,
+}
+
+impl ScanReport {
+    /// Imports that are not on the allowlist
+    pub fn get_dangerous(&self) -> Vec<&Import> {
+        self.imports.iter().filter(|import| !import.is_allowed()).collect()
+    }
+
+    /// Whether loading the file would only import allowlisted functions
+    pub fn is_safe(&self) -> bool {
+        self.imports.iter().all(Import::is_allowed)
+    }
+}
+
+/// Scan a PyTorch checkpoint (zip or legacy) without loading it
+///
+/// Errors:
+///     - If the file cannot be read
+///     - If the file is not a pickle or PyTorch zip, or the pickle is malformed
+pub fn scan_file(path: &Path) -> Result<ScanReport> {
+    let path_name = path.display().to_string();
+    let read_error = |source| VorpalError::FileRead { path: path_name.clone(), source };
+    let mut file = File::open(path).map_err(read_error)?;
+    let mut magic = [0u8; 4];
+    let read = file.read(&mut magic).map_err(read_error)?;
+    drop(file);
+    match &magic[..read] {
+        m if m == ZIP_MAGIC => scan_zip(path),
+        [PROTO, ..] => scan_legacy(path),
+        _ => Err(VorpalError::InvalidPickle { path: path_name, message: "not a pickle or PyTorch zip file".to_string() }),
+    }
+}
+
+/// Scan every .pkl entry in a PyTorch zip checkpoint
+fn scan_zip(path: &Path) -> Result<ScanReport> {
+    let path_name = path.display().to_string();
+    let invalid = |message: String| VorpalError::InvalidPickle { path: path_name.clone(), message };
+    let file = File::open(path).map_err(|source| VorpalError::FileRead { path: path_name.clone(), source })?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| invalid(e.to_string()))?;
+
+    let mut report = ScanReport::default();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).map_err(|e| invalid(e.to_string()))?;
+        let name = entry.name().to_string();
+        if !name.ends_with(PICKLE_EXTENSION) { continue }
+        let imports = scan_pickle(&mut BufReader::new(entry), &name)
+            .map_err(|e| invalid(format!("{}: {}", name, e)))?;
+        report.imports.extend(imports);
+        report.pickles.push(name);
+    }
+    if report.pickles.is_empty() {
+        return Err(invalid("the zip file has no pickle in it".to_string()))
+    }
+    Ok(report)
+}
+
+/// Scan the back to back pickles at the start of a legacy PyTorch file
+fn scan_legacy(path: &Path) -> Result<ScanReport> {
+    let path_name = path.display().to_string();
+    let file = File::open(path).map_err(|source| VorpalError::FileRead { path: path_name.clone(), source })?;
+    let mut reader = BufReader::new(file);
+    let pickle_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+    let mut report = ScanReport::default();
+    for _ in 0..LEGACY_PICKLE_COUNT {
+        // Plain pickle files hold just one
+        let at_end = reader.fill_buf()
+            .map_err(|source| VorpalError::FileRead { path: path_name.clone(), source })?
+            .is_empty();
+        if at_end { break }
+        let imports = scan_pickle(&mut reader, &pickle_name)
+            .map_err(|e| VorpalError::InvalidPickle { path: path_name.clone(), message: e.to_string() })?;
+        report.imports.extend(imports);
+    }
+    report.pickles.push(pickle_name);
+    Ok(report)
+}
+
+// Pickle opcodes, protocols 0 to 5
+pub(crate) const MARK: u8 = b'(';
+pub(crate) const STOP: u8 = b'.';
+pub(crate) const POP: u8 = b'0';
+pub(crate) const POP_MARK: u8 = b'1';
+pub(crate) const DUP: u8 = b'2';
+pub(crate) const FLOAT: u8 = b'F';
+pub(crate) const INT: u8 = b'I';
+pub(crate) const BININT: u8 = b'J';
+pub(crate) const BININT1: u8 = b'K';
+pub(crate) const LONG: u8 = b'L';
+pub(crate) const BININT2: u8 = b'M';
+pub(crate) const NONE: u8 = b'N';
+pub(crate) const PERSID: u8 = b'P';
+pub(crate) const BINPERSID: u8 = b'Q';
+pub(crate) const REDUCE: u8 = b'R';
+pub(crate) const STRING: u8 = b'S';
+pub(crate) const BINSTRING: u8 = b'T';
+pub(crate) const SHORT_BINSTRING: u8 = b'U';
+pub(crate) const UNICODE: u8 = b'V';
+pub(crate) const BINUNICODE: u8 = b'X';
+pub(crate) const APPEND: u8 = b'a';
+pub(crate) const BUILD: u8 = b'b';
+pub(crate) const GLOBAL: u8 = b'c';
+pub(crate) const DICT: u8 = b'd';
+pub(crate) const EMPTY_DICT: u8 = b'}';
+pub(crate) const APPENDS: u8 = b'e';
+pub(crate) const GET: u8 = b'g';
+pub(crate) const BINGET: u8 = b'h';
+pub(crate) const INST: u8 = b'i';
+pub(crate) const LONG_BINGET: u8 = b'j';
+pub(crate) const LIST: u8 = b'l';
+pub(crate) const EMPTY_LIST: u8 = b']';
+pub(crate) const OBJ: u8 = b'o';
+pub(crate) const PUT: u8 = b'p';
+pub(crate) const BINPUT: u8 = b'q';
+pub(crate) const LONG_BINPUT: u8 = b'r';
+pub(crate) const SETITEM: u8 = b's';
+pub(crate) const TUPLE: u8 = b't';
+pub(crate) const EMPTY_TUPLE: u8 = b')';
+pub(crate) const SETITEMS: u8 = b'u';
+pub(crate) const BINFLOAT: u8 = b'G';
+pub(crate) const PROTO: u8 = 0x80;
+pub(crate) const NEWOBJ: u8 = 0x81;
+pub(crate) const EXT1: u8 = 0x82;
+pub(crate) const EXT2: u8 = 0x83;
+pub(crate) const EXT4: u8 = 0x84;
+pub(crate) const TUPLE1: u8 = 0x85;
+pub(crate) const TUPLE2: u8 = 0x86;
+pub(crate) const TUPLE3: u8 = 0x87;
+pub(crate) const NEWTRUE: u8 = 0x88;
+pub(crate) const NEWFALSE: u8 = 0x89;
+pub(crate) const LONG1: u8 = 0x8a;
+pub(crate) const LONG4: u8 = 0x8b;
+pub(crate) const BINBYTES: u8 = b'B';
+pub(crate) const SHORT_BINBYTES: u8 = b'C';
+pub(crate) const SHORT_BINUNICODE: u8 = 0x8c;
+pub(crate) const BINUNICODE8: u8 = 0x8d;
+pub(crate) const BINBYTES8: u8 = 0x8e;
+pub(crate) const EMPTY_SET: u8 = 0x8f;
+pub(crate) const ADDITEMS: u8 = 0x90;
+pub(crate) const FROZENSET: u8 = 0x91;
+pub(crate) const NEWOBJ_EX: u8 = 0x92;
+pub(crate) const STACK_GLOBAL: u8 = 0x93;
+pub(crate) const MEMOIZE: u8 = 0x94;
+pub(crate) const FRAME: u8 = 0x95;
+pub(crate) const BYTEARRAY8: u8 = 0x96;
+pub(crate) const NEXT_BUFFER: u8 = 0x97;
+pub(crate) const READONLY_BUFFER: u8 = 0x98;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Just enough of a stack value to follow where STACK_GLOBAL's names come from
+enum Value {
+    Mark,
+    Str(String),
+    Other,
+}
+
+/// Walk one pickle up to its STOP opcode, returning every import in it.
+/// Nothing is executed; the stack only tracks strings.
+///
+/// Errors:
+///     - If the stream ends early, or has an unknown opcode or impossible stack
+pub fn scan_pickle<R: BufRead>(reader: &mut R, pickle: &str) -> io::Result<Vec<Import>> {
+    let mut stack: Vec<Value> = Vec::new();
+    let mut memo: HashMap<u64, Value> = HashMap::new();
+    let mut imports = Vec::new();
+    let mut import = |module: String, name: String| imports.push(Import { module, name, pickle: pickle.to_string() });
+
+    loop {
+        let op = read_u8(reader)?;
+        match op {
+            STOP => break,
+            MARK => stack.push(Value::Mark),
+            POP => { pop(&mut stack)?; },
+            POP_MARK => { pop_mark(&mut stack)?; },
+            DUP => {
+                let top = stack.last().cloned().ok_or_else(|| malformed("DUP on an empty stack"))?;
+                stack.push(top);
+            },
+            FLOAT | INT | LONG => {
+                read_line(reader)?;
+                stack.push(Value::Other);
+            },
+            BININT => push_skipped(reader, &mut stack, 4)?,
+            BININT1 => push_skipped(reader, &mut stack, 1)?,
+            BININT2 => push_skipped(reader, &mut stack, 2)?,
+            BINFLOAT => push_skipped(reader, &mut stack, 8)?,
+            LONG1 => {
+                let len = read_u8(reader)? as u64;
+                push_skipped(reader, &mut stack, len)?
+            },
+            LONG4 => {
+                let len = read_uint(reader, 4)?;
+                push_skipped(reader, &mut stack, len)?
+            },
+            NONE | NEWTRUE | NEWFALSE | EMPTY_DICT | EMPTY_LIST | EMPTY_TUPLE | EMPTY_SET | NEXT_BUFFER => stack.push(Value::Other),
+            PERSID => {
+                read_line(reader)?;
+                stack.push(Value::Other);
+            },
+            BINPERSID => {
+                pop(&mut stack)?;
+                stack.push(Value::Other);
+            },
+            REDUCE | NEWOBJ => {
+                pop(&mut stack)?;
+                pop(&mut stack)?;
+                stack.push(Value::Other);
+            },
+            NEWOBJ_EX => {
+                for _ in 0..3 { pop(&mut stack)?; }
+                stack.push(Value::Other);
+            },
+            STRING | UNICODE => {
+                let line = read_line(reader)?;
+                stack.push(Value::Str(line.trim_matches(|c| c == '\'' || c == '"').to_string()));
+            },
+            SHORT_BINSTRING | SHORT_BINUNICODE => {
+                let len = read_u8(reader)? as u64;
+                stack.push(read_str(reader, len)?);
+            },
+            BINSTRING | BINUNICODE => {
+                let len = read_uint(reader, 4)?;
+                stack.push(read_str(reader, len)?);
+            },
+            BINUNICODE8 => {
+                let len = read_uint(reader, 8)?;
+                stack.push(read_str(reader, len)?);
+            },
+            SHORT_BINBYTES => {
+                let len = read_u8(reader)? as u64;
+                push_skipped(reader, &mut stack, len)?
+            },
+            BINBYTES => {
+                let len = read_uint(reader, 4)?;
+                push_skipped(reader, &mut stack, len)?
+            },
+            BINBYTES8 | BYTEARRAY8 => {
+                let len = read_uint(reader, 8)?;
+                push_skipped(reader, &mut stack, len)?
+            },
+            APPEND | BUILD => { pop(&mut stack)?; },
+            SETITEM => {
+                pop(&mut stack)?;
+                pop(&mut stack)?;
+            },
+            APPENDS | SETITEMS | ADDITEMS => { pop_mark(&mut stack)?; },
+            DICT | LIST | TUPLE | FROZENSET | OBJ => {
+                pop_mark(&mut stack)?;
+                stack.push(Value::Other);
+            },
+            TUPLE1 | TUPLE2 | TUPLE3 => {
+                for _ in 0..(op - TUPLE1 + 1) { pop(&mut stack)?; }
+                stack.push(Value::Other);
+            },
+            GLOBAL => {
+                let module = read_line(reader)?;
+                let name = read_line(reader)?;
+                import(module, name);
+                stack.push(Value::Other);
+            },
+            INST => {
+                let module = read_line(reader)?;
+                let name = read_line(reader)?;
+                import(module, name);
+                pop_mark(&mut stack)?;
+                stack.push(Value::Other);
+            },
+            STACK_GLOBAL => {
+                let name = pop(&mut stack)?;
+                let module = pop(&mut stack)?;
+                match (module, name) {
+                    (Value::Str(module), Value::Str(name)) => import(module, name),
+                    (Value::Str(module), _) => import(module, UNKNOWN_NAME.to_string()),
+                    _ => import(UNKNOWN_NAME.to_string(), UNKNOWN_NAME.to_string()),
+                }
+                stack.push(Value::Other);
+            },
+            EXT1 | EXT2 | EXT4 => {
+                // Extension codes name globals through copyreg, which cannot be checked
+                let width = match op { EXT1 => 1, EXT2 => 2, _ => 4 };
+                let code = read_uint(reader, width)?;
+                import("copyreg".to_string(), format!("<extension {}>", code));
+                stack.push(Value::Other);
+            },
+            GET => {
+                let index = parse_index(&read_line(reader)?)?;
+                stack.push(memo.get(&index).cloned().unwrap_or(Value::Other));
+            },
+            BINGET => {
+                let index = read_u8(reader)? as u64;
+                stack.push(memo.get(&index).cloned().unwrap_or(Value::Other));
+            },
+            LONG_BINGET => {
+                let index = read_uint(reader, 4)?;
+                stack.push(memo.get(&index).cloned().unwrap_or(Value::Other));
+            },
+            PUT => {
+                let index = parse_index(&read_line(reader)?)?;
+                memo.insert(index, top(&stack)?);
+            },
+            BINPUT => {
+                let index = read_u8(reader)? as u64;
+                memo.insert(index, top(&stack)?);
+            },
+            LONG_BINPUT => {
+                let index = read_uint(reader, 4)?;
+                memo.insert(index, top(&stack)?);
+            },
+            MEMOIZE => {
+                let index = memo.len() as u64;
+                memo.insert(index, top(&stack)?);
+            },
+            PROTO => { read_u8(reader)?; },
+            FRAME => { read_uint(reader, 8)?; },
+            READONLY_BUFFER => (),
+            _ => return Err(malformed(&format!("unknown opcode 0x{:02x}", op))),
+        }
+    }
+    Ok(imports)
+}
+
+pub(crate) fn malformed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn pop(stack: &mut Vec<Value>) -> io::Result<Value> {
+    match stack.pop() {
+        Some(Value::Mark) | None => Err(malformed("stack underflow")),
+        Some(value) => Ok(value),
+    }
+}
+
+fn top(stack: &[Value]) -> io::Result<Value> {
+    stack.last().cloned().ok_or_else(|| malformed("memo of an empty stack"))
+}
+
+fn pop_mark(stack: &mut Vec<Value>) -> io::Result<()> {
+    let mark = stack.iter().rposition(|v| *v == Value::Mark).ok_or_else(|| malformed("no MARK on the stack"))?;
+    stack.truncate(mark);
+    Ok(())
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Read a little endian unsigned integer of width bytes
+pub(crate) fn read_uint<R: Read>(reader: &mut R, width: usize) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes[..width])?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.take(MAX_NAME_LENGTH).read_until(b'\n', &mut line)?;
+    if line.pop() != Some(b'\n') {
+        return Err(malformed("unterminated text argument"))
+    }
+    Ok(String::from_utf8_lossy(&line).to_string())
+}
+
+fn parse_index(line: &str) -> io::Result<u64> {
+    line.trim().parse().map_err(|_| malformed("bad memo index"))
+}
+
+/// Read a string argument. Long strings cannot be names, so are skipped.
+fn read_str<R: Read>(reader: &mut R, len: u64) -> io::Result<Value> {
+    if len > MAX_NAME_LENGTH {
+        skip(reader, len)?;
+        return Ok(Value::Other)
+    }
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into())
+    }
+    Ok(Value::Str(String::from_utf8_lossy(&bytes).to_string()))
+}
+
+pub(crate) fn skip<R: Read>(reader: &mut R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::ErrorKind::UnexpectedEof.into())
+    }
+    Ok(())
+}
+
+fn push_skipped<R: Read>(reader: &mut R, stack: &mut Vec<Value>, len: u64) -> io::Result<()> {
+    skip(reader, len)?;
+    stack.push(Value::Other);
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Download progress reporting.
+//!
+//! Anything implementing ProgressObserver can be handed to DownloadOptions to
+//! hear about a download as it happens. Plain closures work too:
+//!
+//!     let options = DownloadOptions::new()
+//!         .progress(Arc::new(|p: &Progress| println!("{} bytes", p.done)));
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often on_progress is called at most, so observers are not flooded
+/// with one call per network chunk
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A snapshot of a download in progress
+pub struct Progress {
+    /// Bytes of the file on disk so far, including any resumed from a .part file
+    pub done: u64,
+    /// Size of the whole file, from Content-Length or Civitai's reported size
+    pub total: Option<u64>,
+    /// Average speed of this session, in bytes per second
+    pub bytes_per_sec: f64,
+    /// Time since this session started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// How far along the download is, from 0.0 to 1.0
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) | None => None,
+            Some(total) => Some((self.done as f64 / total as f64).min(1.0)),
+        }
+    }
+
+    /// Estimated time left at the current average speed
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.total?;
+        if self.bytes_per_sec <= 0.0 { return None }
+        let left = total.saturating_sub(self.done) as f64;
+        Some(Duration::from_secs_f64(left / self.bytes_per_sec))
+    }
+}
+
+/// Receives progress updates for a download. Every method has an empty default,
+/// so implement only the ones you need.
+pub trait ProgressObserver: Send + Sync {
+    /// The server has answered and data is about to arrive
+    fn on_start(&self, _progress: &Progress) {}
+    /// More data has been written. Called at most every 100ms.
+    fn on_progress(&self, _progress: &Progress) {}
+    /// The last byte has been written (the file has not been verified yet)
+    fn on_finish(&self, _progress: &Progress) {}
+}
+
+impl<F> ProgressObserver for F
+where F: Fn(&Progress) + Send + Sync {
+    fn on_progress(&self, progress: &Progress) {
+        self(progress)
+    }
+
+    fn on_finish(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// A shareable ProgressObserver, as stored in DownloadOptions
+#[derive(Clone)]
+pub(crate) struct SharedObserver(pub(crate) Arc<dyn ProgressObserver>);
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressObserver")
+    }
+}
+
+/// Keeps count of bytes for one download session and calls the observer
+pub(crate) struct ProgressTracker<'a> {
+    observer: Option<&'a dyn ProgressObserver>,
+    start: Instant,
+    last_report: Instant,
+    resumed_from: u64,
+    done: u64,
+    total: Option<u64>,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub(crate) fn new(observer: Option<&'a dyn ProgressObserver>, resumed_from: u64, total: Option<u64>) -> Self {
+        let now = Instant::now();
+        let tracker = ProgressTracker { observer, start: now, last_report: now, resumed_from, done: resumed_from, total };
+        if let Some(observer) = tracker.observer {
+            observer.on_start(&tracker.snapshot());
+        }
+        tracker
+    }
+
+    fn snapshot(&self) -> Progress {
+        let elapsed = self.start.elapsed();
+        let session_bytes = (self.done - self.resumed_from) as f64;
+        let bytes_per_sec = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => session_bytes / secs,
+            _ => 0.0,
+        };
+        Progress { done: self.done, total: self.total, bytes_per_sec, elapsed }
+    }
+
+    pub(crate) fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        if let Some(observer) = self.observer {
+            if self.last_report.elapsed() >= PROGRESS_INTERVAL {
+                self.last_report = Instant::now();
+                observer.on_progress(&self.snapshot());
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Some(observer) = self.observer {
+            observer.on_finish(&self.snapshot());
+        }
+    }
+}
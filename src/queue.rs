@@ -0,0 +1,282 @@
+//! Download many model versions at once.
+//!
+//! A DownloadQueue holds (model version, directory) jobs and runs them with at
+//! most `concurrency` downloads in flight. A failed job does not stop the others;
+//! every job gets its own result back:
+//!
+//!     let outcomes = DownloadQueue::new(client)
+//!         .concurrency(4)
+//!         .push(DownloadJob::new(version, "/models/Lora"))
+//!         .run()
+//!         .await;
+//!
+//! Every job's destination is worked out before any job starts, so two jobs that
+//! would save files under the same name never write the same file. Each job's
+//! OnCollision policy applies against the files of earlier jobs in the queue as
+//! well as against files already on disk.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use futures_util::{stream, StreamExt};
+use crate::error::Result;
+use crate::filename::{self, Destination, FilenameTemplate, OnCollision};
+use crate::{CivitaiClient, DownloadOptions, FileSelector, ModelFile, ModelVersion};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Clone)]
+/// One model version to download, and the directory to save it in
+pub struct DownloadJob {
+    version: ModelVersion,
+    directory: PathBuf,
+    selector: FileSelector,
+    template: FilenameTemplate,
+    on_collision: OnCollision,
+}
+
+impl DownloadJob {
+    pub fn new(version: ModelVersion, directory: impl Into<PathBuf>) -> Self {
+        DownloadJob {
+            version,
+            directory: directory.into(),
+            selector: FileSelector::new(),
+            template: FilenameTemplate::default(),
+            on_collision: OnCollision::default(),
+        }
+    }
+
+    /// Which of the version's files to download. The default FileSelector is used otherwise.
+    pub fn selector(mut self, selector: FileSelector) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// What to name the file. By default it keeps the name Civitai gives it.
+    pub fn template(mut self, template: FilenameTemplate) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// What to do if a file is already at the job's path. Skip by default.
+    pub fn on_collision(mut self, on_collision: OnCollision) -> Self {
+        self.on_collision = on_collision;
+        self
+    }
+
+    pub fn get_version(&self) -> &ModelVersion {
+        &self.version
+    }
+
+    pub fn get_directory(&self) -> &PathBuf {
+        &self.directory
+    }
+
+    /// The file that will be downloaded
+    ///
+    /// Errors:
+    ///     - If none of the version's files pass the job's selector
+    pub fn get_file(&self) -> Result<ModelFile> {
+        self.version.get_file(&self.selector)
+    }
+
+    /// Where the model file will be saved, before any collision is dealt with: the
+    /// template's name for it, in the job's directory
+    ///
+    /// Errors:
+    ///     - If none of the version's files pass the job's selector
+    pub fn get_path(&self) -> Result<PathBuf> {
+        Ok(self.directory.join(self.template.render(&self.version, &self.get_file()?)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where a job is at, as reported to a JobObserver
+pub enum JobStatus {
+    /// Waiting for a free download slot
+    Queued,
+    Running,
+    /// Downloaded and verified, with the file's SHA256
+    Done(String),
+    /// The job failed with this error message
+    Failed(String),
+}
+
+/// Hears about every job's status changes. Plain closures work too.
+pub trait JobObserver: Send + Sync {
+    fn on_status(&self, job: &DownloadJob, status: &JobStatus);
+}
+
+impl<F> JobObserver for F
+where F: Fn(&DownloadJob, &JobStatus) + Send + Sync {
+    fn on_status(&self, job: &DownloadJob, status: &JobStatus) {
+        self(job, status)
+    }
+}
+
+#[derive(Debug)]
+/// A finished job and what came of it. The result holds the file's SHA256.
+pub struct JobOutcome {
+    pub job: DownloadJob,
+    pub result: Result<String>,
+    /// Where the file ended up, if the job succeeded. This differs from the job's
+    /// path if the file was renamed to avoid a collision.
+    pub path: Option<PathBuf>,
+}
+
+/// A batch of downloads with bounded parallelism. Finished jobs are not recorded in
+/// the Library; see the library module.
+pub struct DownloadQueue {
+    client: CivitaiClient,
+    concurrency: usize,
+    options: DownloadOptions,
+    observer: Option<Arc<dyn JobObserver>>,
+    jobs: Vec<DownloadJob>,
+}
+
+impl DownloadQueue {
+    pub fn new(client: CivitaiClient) -> Self {
+        DownloadQueue {
+            client,
+            concurrency: DEFAULT_CONCURRENCY,
+            options: DownloadOptions::new(),
+            observer: None,
+            jobs: Vec::new(),
+        }
+    }
+
+    /// How many downloads may run at once. Values below 1 are treated as 1.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Options used for every job. Each job's expected size and hash are filled
+    /// in from its model file. A progress observer set here hears from all jobs.
+    pub fn options(mut self, options: DownloadOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn observer(mut self, observer: Arc<dyn JobObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub fn push(mut self, job: DownloadJob) -> Self {
+        self.jobs.push(job);
+        self
+    }
+
+    pub fn extend(mut self, jobs: impl IntoIterator<Item = DownloadJob>) -> Self {
+        self.jobs.extend(jobs);
+        self
+    }
+
+    /// Run every job. Outcomes come back in the order the jobs were added,
+    /// whatever order they finished in.
+    pub async fn run(self) -> Vec<JobOutcome> {
+        let DownloadQueue { client, concurrency, options, observer, jobs } = self;
+        let notify = |job: &DownloadJob, status: JobStatus| {
+            if let Some(observer) = &observer {
+                observer.on_status(job, &status);
+            }
+        };
+        for job in &jobs {
+            notify(job, JobStatus::Queued);
+        }
+
+        let client = &client;
+        let options = &options;
+        let notify = &notify;
+        let plans = plan(&jobs);
+        let mut planned = Vec::new();
+        let mut duplicates = Vec::new();
+        for (index, (job, plan)) in jobs.into_iter().zip(plans).enumerate() {
+            match plan.transpose() {
+                Some(destination) => planned.push((index, job, destination)),
+                None => duplicates.push((index, job)),
+            }
+        }
+
+        let mut outcomes: Vec<(usize, JobOutcome)> = stream::iter(planned)
+            .map(|(index, job, destination)| async move {
+                (index, run_job(client, job, destination, options, notify).await)
+            })
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+        // The file each duplicate shares with an earlier job is on disk by now (unless
+        // that job failed), so resolving again finds it, or downloads it this time
+        for (index, job) in duplicates {
+            let destination = job.get_file().and_then(|file| {
+                filename::resolve(&job.get_path()?, job.on_collision, file.get_sha256().as_deref())
+            });
+            outcomes.push((index, run_job(client, job, destination, options, notify).await));
+        }
+        outcomes.sort_by_key(|(index, _)| *index);
+        outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+    }
+}
+
+/// Where each job will save its file, worked out before any job starts. A path
+/// one job will write is reserved, and the jobs after it resolve against it with
+/// their own policy. Ok(None) marks a job whose file is the same (by SHA256) as
+/// the one an earlier job writes to its path, under OnCollision::Skip; it waits
+/// for that job instead of downloading the file again.
+fn plan(jobs: &[DownloadJob]) -> Vec<Result<Option<Destination>>> {
+    let mut reserved: HashSet<PathBuf> = HashSet::new();
+    let mut reserved_sha256: HashMap<PathBuf, String> = HashMap::new();
+    jobs.iter()
+        .map(|job| -> Result<Option<Destination>> {
+            let file = job.get_file()?;
+            let path = job.get_path()?;
+            let expected = file.get_sha256();
+            let same_file = match (reserved_sha256.get(&path), &expected) {
+                (Some(reserved), Some(expected)) => reserved.eq_ignore_ascii_case(expected),
+                _ => false,
+            };
+            if same_file && job.on_collision == OnCollision::Skip {
+                return Ok(None)
+            }
+            let destination = filename::resolve_reserved(&path, job.on_collision, expected.as_deref(), &reserved)?;
+            if let Destination::Write(target) = &destination {
+                reserved.insert(target.clone());
+                if let Some(expected) = expected {
+                    reserved_sha256.insert(target.clone(), expected);
+                }
+            }
+            Ok(Some(destination))
+        })
+        .collect()
+}
+
+/// Download the job's file to its destination (unless it is already there),
+/// reporting its status as it goes
+async fn run_job(
+    client: &CivitaiClient,
+    job: DownloadJob,
+    destination: Result<Destination>,
+    options: &DownloadOptions,
+    notify: &(dyn Fn(&DownloadJob, JobStatus) + Sync),
+) -> JobOutcome {
+    notify(&job, JobStatus::Running);
+    let saved = match destination {
+        Ok(Destination::Existing(path, sha256)) => Ok((path, sha256)),
+        Ok(Destination::Write(path)) => match job.get_file() {
+            Ok(file) => client.download_model_file(&file, path.display().to_string(), options).await
+                .map(|sha256| (path, sha256)),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    let (path, result) = match saved {
+        Ok((path, sha256)) => (Some(path), Ok(sha256)),
+        Err(e) => (None, Err(e)),
+    };
+    match &result {
+        Ok(sha256) => notify(&job, JobStatus::Done(sha256.clone())),
+        Err(e) => notify(&job, JobStatus::Failed(e.to_string())),
+    }
+    JobOutcome { job, result, path }
+}
@@ -0,0 +1,234 @@
+//! The safetensors file format.
+//!
+//! A safetensors file is an 8 byte little endian header length, a JSON header,
+//! then the raw tensor data. The header maps each tensor's name to its dtype,
+//! shape and byte range in the data, plus an optional `__metadata__` map of
+//! strings. Unlike pickles, there is no code in it to run.
+//!
+//! LoRAs trained with kohya's scripts keep their training settings in the
+//! metadata as `ss_*` keys. read_header reads just the header, however big the
+//! file is:
+//!
+//!     let header = safetensors::read_header(Path::new("lora.safetensors"))?;
+//!     println!("{}", header.generate_report().join("\n"));
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::{shorten_unicode, VorpalError, DESC_CUTOFF, QUERY_INDENT, SHORT_SIZE};
+
+pub const METADATA_KEY: &str = "__metadata__";
+/// The header is padded with spaces to a multiple of this, so the data is aligned
+const HEADER_ALIGNMENT: usize = 8;
+/// The largest header the reference implementation accepts
+const MAX_HEADER_SIZE: u64 = 100_000_000;
+const TRAINING_PREFIX: &str = "ss_";
+const TAG_FREQUENCY_KEY: &str = "ss_tag_frequency";
+const TOP_TAGS: usize = 20;
+/// Kohya's training settings worth showing, and what to call them
+const TRAINING_FIELDS: [(&str, &str); 16] = [
+    ("ss_output_name", "Output Name"),
+    ("ss_sd_model_name", "Trained On"),
+    ("ss_base_model_version", "Base Model Version"),
+    ("ss_v2", "SD 2.x"),
+    ("ss_network_module", "Network Module"),
+    ("ss_network_dim", "Network Dim"),
+    ("ss_network_alpha", "Network Alpha"),
+    ("ss_resolution", "Resolution"),
+    ("ss_num_train_images", "Training Images"),
+    ("ss_num_epochs", "Epochs"),
+    ("ss_max_train_steps", "Steps"),
+    ("ss_learning_rate", "Learning Rate"),
+    ("ss_optimizer", "Optimizer"),
+    ("ss_clip_skip", "Clip Skip"),
+    ("ss_mixed_precision", "Mixed Precision"),
+    ("ss_training_started_at", "Training Started"),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Where one tensor is in a safetensors file
+pub struct TensorInfo {
+    /// "F16", "F32", "BF16", "I64"...
+    pub dtype: String,
+    pub shape: Vec<u64>,
+    /// Start and end of the tensor's bytes, counted from the end of the header
+    pub data_offsets: [u64; 2],
+}
+
+/// The length prefix and JSON header for tensors, whose data must then follow
+/// in the order of their data_offsets
+pub fn encode_header(tensors: &[(String, TensorInfo)], metadata: &BTreeMap<String, String>) -> Result<Vec<u8>> {
+    let mut header = serde_json::Map::new();
+    if !metadata.is_empty() {
+        header.insert(METADATA_KEY.to_string(), serde_json::to_value(metadata)?);
+    }
+    for (name, info) in tensors {
+        header.insert(name.clone(), serde_json::to_value(info)?);
+    }
+    let mut json = serde_json::to_vec(&header)?;
+    while json.len() % HEADER_ALIGNMENT != 0 {
+        json.push(b' ');
+    }
+    let mut encoded = (json.len() as u64).to_le_bytes().to_vec();
+    encoded.extend(json);
+    Ok(encoded)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The header of a safetensors file
+pub struct Header {
+    pub tensors: BTreeMap<String, TensorInfo>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Header {
+    pub fn get_tensor_count(&self) -> usize {
+        self.tensors.len()
+    }
+
+    /// How many tensors there are of each dtype
+    pub fn get_dtype_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for info in self.tensors.values() {
+            *counts.entry(info.dtype.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Total number of elements in all tensors, or None if the shapes in the header
+    /// are too large to add up
+    pub fn get_parameter_count(&self) -> Option<u64> {
+        self.tensors.values().try_fold(0u64, |count, info| {
+            let numel = info.shape.iter().try_fold(1u64, |numel, size| numel.checked_mul(*size))?;
+            count.checked_add(numel)
+        })
+    }
+
+    /// Bytes of tensor data after the header
+    pub fn get_data_size(&self) -> u64 {
+        self.tensors.values().map(|info| info.data_offsets[1]).max().unwrap_or(0)
+    }
+
+    /// The kohya `ss_*` training metadata, if the file has any
+    pub fn get_training_metadata(&self) -> BTreeMap<String, String> {
+        self.metadata.iter()
+            .filter(|(key, _)| key.starts_with(TRAINING_PREFIX))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Training tags and how many images had them, most common first, summed
+    /// over every dataset. Empty if the file has no (or unreadable) tag counts.
+    pub fn get_tag_frequency(&self) -> Vec<(String, u64)> {
+        let Some(json) = self.metadata.get(TAG_FREQUENCY_KEY) else { return Vec::new() };
+        let datasets: BTreeMap<String, BTreeMap<String, u64>> = serde_json::from_str(json).unwrap_or_default();
+        let mut tags: BTreeMap<String, u64> = BTreeMap::new();
+        for (tag, count) in datasets.into_values().flatten() {
+            *tags.entry(tag.trim().to_string()).or_insert(0) += count;
+        }
+        let mut tags: Vec<(String, u64)> = tags.into_iter().collect();
+        tags.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        tags
+    }
+
+    /// Human readable summary: tensors, dtypes, size, then any training settings
+    /// and other metadata
+    pub fn generate_report(&self) -> Vec<String> {
+        let dtypes: Vec<String> = self.get_dtype_counts().iter()
+            .map(|(dtype, count)| format!("{} ({})", dtype, count))
+            .collect();
+        let mut report_fields = vec![
+            format!("Tensors: {}", self.get_tensor_count()),
+            format!("Data Types: {}", dtypes.join(", ")),
+        ];
+        if let Some(count) = self.get_parameter_count() {
+            report_fields.push(format!("Parameters: {}", format_count(count)));
+        }
+        report_fields.push(format!("Tensor Data: {:.2}MB", self.get_data_size() as f64 / 1_048_576.0));
+
+        let training: Vec<String> = TRAINING_FIELDS.iter()
+            .filter_map(|(key, label)| self.metadata.get(*key).map(|value| format!("{}{}: {}", QUERY_INDENT, label, value)))
+            .collect();
+        if !training.is_empty() {
+            report_fields.push("Training:".to_string());
+            report_fields.extend(training);
+        }
+        let tags: Vec<String> = self.get_tag_frequency().into_iter()
+            .take(TOP_TAGS)
+            .map(|(tag, count)| format!("{} ({})", tag, count))
+            .collect();
+        if !tags.is_empty() {
+            report_fields.push(format!("Top Tags: {}", tags.join(", ")));
+        }
+
+        let other: Vec<String> = self.metadata.iter()
+            .filter(|(key, _)| !key.starts_with(TRAINING_PREFIX))
+            .map(|(key, value)| {
+                let value = match value.chars().count() > SHORT_SIZE {
+                    true => shorten_unicode(value.clone(), SHORT_SIZE, DESC_CUTOFF),
+                    false => value.clone(),
+                };
+                format!("{}{}: {}", QUERY_INDENT, key, value)
+            })
+            .collect();
+        if !other.is_empty() {
+            report_fields.push("Metadata:".to_string());
+            report_fields.extend(other);
+        }
+        report_fields
+    }
+}
+
+/// Read the header of the safetensors file at path, without reading its tensors
+///
+/// Errors:
+///     - If the file cannot be read
+///     - VorpalError::InvalidSafetensors if the header is missing or malformed
+pub fn read_header(path: &Path) -> Result<Header> {
+    let path_name = path.display().to_string();
+    let read_error = |source| VorpalError::FileRead { path: path_name.clone(), source };
+    let invalid = |message: &str| VorpalError::InvalidSafetensors { path: path_name.clone(), message: message.to_string() };
+    let mut file = File::open(path).map_err(read_error)?;
+    let file_size = file.metadata().map_err(read_error)?.len();
+
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len).map_err(|_| invalid("the file is too short to have a header"))?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_HEADER_SIZE || len > file_size - 8 {
+        return Err(invalid("the header length is larger than the file"))
+    }
+    let mut json = vec![0u8; len as usize];
+    file.read_exact(&mut json).map_err(read_error)?;
+    let header = parse_header(&json).map_err(|e| invalid(&e.to_string()))?;
+
+    if header.get_data_size() > file_size - 8 - len {
+        return Err(invalid("the tensors run past the end of the file"))
+    }
+    Ok(header)
+}
+
+/// Parse the JSON part of a header
+fn parse_header(json: &[u8]) -> serde_json::Result<Header> {
+    let mut entries: BTreeMap<String, serde_json::Value> = serde_json::from_slice(json)?;
+    let metadata = match entries.remove(METADATA_KEY) {
+        Some(metadata) => serde_json::from_value(metadata)?,
+        None => BTreeMap::new(),
+    };
+    let tensors = entries.into_iter()
+        .map(|(name, info)| Ok((name, serde_json::from_value(info)?)))
+        .collect::<serde_json::Result<_>>()?;
+    Ok(Header { tensors, metadata })
+}
+
+/// 859520964 as "859.52M"
+fn format_count(count: u64) -> String {
+    match count {
+        c if c >= 1_000_000_000 => format!("{:.2}B", c as f64 / 1e9),
+        c if c >= 1_000_000 => format!("{:.2}M", c as f64 / 1e6),
+        c if c >= 1_000 => format!("{:.2}K", c as f64 / 1e3),
+        c => c.to_string(),
+    }
+}
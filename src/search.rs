@@ -0,0 +1,295 @@
+//! Search filters for the Civitai models endpoint.
+//!
+//! SearchParams covers the filters the API accepts, so a search like
+//! "newest SDXL LoRAs tagged 'style' this week" can be done in one request:
+//!
+//!     let params = SearchParams::new()
+//!         .types(vec![ModelType::Lora])
+//!         .base_models(vec!["SDXL 1.0".to_string()])
+//!         .tag("style")
+//!         .sort(Sort::Newest)
+//!         .period(Period::Week);
+
+use std::fmt;
+use std::str::FromStr;
+
+const DEFAULT_LIMIT: u8 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The kind of model, as Civitai labels it
+pub enum ModelType {
+    Checkpoint,
+    TextualInversion,
+    Hypernetwork,
+    AestheticGradient,
+    Lora,
+    LoCon,
+    Controlnet,
+    Poses,
+    Vae,
+    Upscaler,
+    MotionModule,
+    Wildcards,
+    Workflows,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Result ordering
+pub enum Sort {
+    HighestRated,
+    MostDownloaded,
+    Newest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Time window that Sort is applied over
+pub enum Period {
+    AllTime,
+    Year,
+    Month,
+    Week,
+    Day,
+}
+
+const MODEL_TYPES: [ModelType; 14] = [
+    ModelType::Checkpoint,
+    ModelType::TextualInversion,
+    ModelType::Hypernetwork,
+    ModelType::AestheticGradient,
+    ModelType::Lora,
+    ModelType::LoCon,
+    ModelType::Controlnet,
+    ModelType::Poses,
+    ModelType::Vae,
+    ModelType::Upscaler,
+    ModelType::MotionModule,
+    ModelType::Wildcards,
+    ModelType::Workflows,
+    ModelType::Other,
+];
+const SORTS: [Sort; 3] = [Sort::HighestRated, Sort::MostDownloaded, Sort::Newest];
+const PERIODS: [Period; 5] = [Period::AllTime, Period::Year, Period::Month, Period::Week, Period::Day];
+
+impl ModelType {
+    /// The name the API uses for this type
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModelType::Checkpoint => "Checkpoint",
+            ModelType::TextualInversion => "TextualInversion",
+            ModelType::Hypernetwork => "Hypernetwork",
+            ModelType::AestheticGradient => "AestheticGradient",
+            ModelType::Lora => "LORA",
+            ModelType::LoCon => "LoCon",
+            ModelType::Controlnet => "Controlnet",
+            ModelType::Poses => "Poses",
+            ModelType::Vae => "VAE",
+            ModelType::Upscaler => "Upscaler",
+            ModelType::MotionModule => "MotionModule",
+            ModelType::Wildcards => "Wildcards",
+            ModelType::Workflows => "Workflows",
+            ModelType::Other => "Other",
+        }
+    }
+}
+
+impl Sort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sort::HighestRated => "Highest Rated",
+            Sort::MostDownloaded => "Most Downloaded",
+            Sort::Newest => "Newest",
+        }
+    }
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::AllTime => "AllTime",
+            Period::Year => "Year",
+            Period::Month => "Month",
+            Period::Week => "Week",
+            Period::Day => "Day",
+        }
+    }
+}
+
+/// Lowercase and drop separators, so "Highest Rated", "highest-rated" and
+/// "HIGHEST_RATED" all match
+pub(crate) fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '_'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Find the variant whose API name matches s, or list the valid names
+fn parse_variant<T: Copy>(s: &str, variants: &[T], name: fn(&T) -> &'static str, kind: &str) -> Result<T, String> {
+    let wanted = normalize(s);
+    variants.iter()
+        .find(|v| normalize(name(v)) == wanted)
+        .copied()
+        .ok_or_else(|| {
+            let valid: Vec<&str> = variants.iter().map(name).collect();
+            format!("Vorpal: Unknown {} '{}'. Expected one of: {}", kind, s, valid.join(", "))
+        })
+}
+
+impl FromStr for ModelType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_variant(s, &MODEL_TYPES, ModelType::as_str, "model type")
+    }
+}
+
+impl FromStr for Sort {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_variant(s, &SORTS, Sort::as_str, "sort")
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_variant(s, &PERIODS, Period::as_str, "period")
+    }
+}
+
+impl fmt::Display for ModelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Filters for a Civitai model search. Every filter is optional; unset filters
+/// are left out of the request.
+pub struct SearchParams {
+    query: Option<String>,
+    limit: u8,
+    types: Vec<ModelType>,
+    sort: Option<Sort>,
+    period: Option<Period>,
+    base_models: Vec<String>,
+    tag: Option<String>,
+    username: Option<String>,
+    favorites: bool,
+    safe: bool,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        SearchParams {
+            query: None,
+            limit: DEFAULT_LIMIT,
+            types: Vec::new(),
+            sort: None,
+            period: None,
+            base_models: Vec::new(),
+            tag: None,
+            username: None,
+            favorites: false,
+            safe: false,
+        }
+    }
+}
+
+impl SearchParams {
+    pub fn new() -> Self {
+        SearchParams::default()
+    }
+
+    /// Keyword to search for. An empty string is the same as no query.
+    pub fn query(mut self, query: impl Into<String>) -> Self {
+        let query = query.into();
+        self.query = if query.is_empty() { None } else { Some(query) };
+        self
+    }
+
+    /// Items per page, at most 100
+    pub fn limit(mut self, limit: u8) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn types(mut self, types: Vec<ModelType>) -> Self {
+        self.types = types;
+        self
+    }
+
+    pub fn sort(mut self, sort: Sort) -> Self {
+        self.sort = Some(sort);
+        self
+    }
+
+    pub fn period(mut self, period: Period) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    /// Base models as Civitai names them, e.g. "SD 1.5" or "SDXL 1.0"
+    pub fn base_models(mut self, base_models: Vec<String>) -> Self {
+        self.base_models = base_models;
+        self
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Only models uploaded by this creator
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Only models the token's owner has favorited. Needs an API token.
+    pub fn favorites(mut self, favorites: bool) -> Self {
+        self.favorites = favorites;
+        self
+    }
+
+    /// Leave out NSFW models. This is done on a 'best effort' basis,
+    /// as it is very common for users to not properly label their items.
+    pub fn safe(mut self, safe: bool) -> Self {
+        self.safe = safe;
+        self
+    }
+
+    /// Query string pairs for the models endpoint. List filters are repeated
+    /// once per value, which is how the API expects them.
+    pub fn to_query_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = vec![("limit", self.limit.to_string())];
+        if let Some(query) = &self.query {
+            pairs.push(("query", query.clone()));
+        }
+        for model_type in &self.types {
+            pairs.push(("types", model_type.as_str().to_string()));
+        }
+        if let Some(sort) = self.sort {
+            pairs.push(("sort", sort.as_str().to_string()));
+        }
+        if let Some(period) = self.period {
+            pairs.push(("period", period.as_str().to_string()));
+        }
+        for base_model in &self.base_models {
+            pairs.push(("baseModels", base_model.clone()));
+        }
+        if let Some(tag) = &self.tag {
+            pairs.push(("tag", tag.clone()));
+        }
+        if let Some(username) = &self.username {
+            pairs.push(("username", username.clone()));
+        }
+        if self.favorites {
+            pairs.push(("favorites", true.to_string()));
+        }
+        if self.safe {
+            pairs.push(("nsfw", false.to_string()));
+        }
+        pairs
+    }
+}
@@ -0,0 +1,267 @@
+//! Choosing which version of a model, and which file of that version, to download.
+//!
+//! VersionSelector picks a version by index, Id or name. Civitai versions often
+//! ship several files: pruned and full, fp16 and fp32, SafeTensor and
+//! PickleTensor, a separate VAE, a config or training data.
+//! FileSelector picks one. Filters that are set must match; among the files
+//! left, the preference order is:
+//!
+//!     1. model files over VAEs, configs and training data
+//!     2. files that passed Civitai's scans over ones that did not
+//!     3. SafeTensor over other formats
+//!     4. the file the uploader marked as primary
+//!     5. fp16 over other precisions
+//!     6. pruned over full
+//!
+//! Pickle files (.ckpt, .pt) can run code when loaded, so ModelVersion::get_file
+//! refuses them, and files that failed a scan, unless the selector allows them.
+//!
+//!     let file = version.select_file(&FileSelector::new()
+//!         .format(FileFormat::SafeTensor)
+//!         .precision("fp16"));
+
+use std::fmt;
+use std::str::FromStr;
+use serde::Deserialize;
+use crate::error::Result as VorpalResult;
+use crate::search::normalize;
+use crate::{ModelFile, ModelVersion, VorpalError};
+
+const MODEL_FILE_TYPES: [&str; 2] = ["Model", "Pruned Model"];
+const PREFERRED_PRECISION: &str = "fp16";
+const PREFERRED_SIZE: &str = "pruned";
+const SAFETENSORS_EXTENSION: &str = ".safetensors";
+const PICKLE_EXTENSIONS: [&str; 4] = [".ckpt", ".pt", ".pth", ".bin"];
+const GGUF_EXTENSION: &str = ".gguf";
+const REASON_PICKLE: &str = "it is a pickle file, which can run code when loaded. Use --allow-pickle to download it anyway.";
+const REASON_SCAN: &str = "it failed Civitai's pickle or virus scan. Use --allow-unsafe to download it anyway.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+/// How a model file is stored
+pub enum FileFormat {
+    SafeTensor,
+    /// Python pickle (.ckpt, .pt), which can run code when loaded
+    PickleTensor,
+    Gguf,
+    Diffusers,
+    Other,
+}
+
+impl FileFormat {
+    /// The name the API uses for this format
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileFormat::SafeTensor => "SafeTensor",
+            FileFormat::PickleTensor => "PickleTensor",
+            FileFormat::Gguf => "GGUF",
+            FileFormat::Diffusers => "Diffusers",
+            FileFormat::Other => "Other",
+        }
+    }
+
+    /// Guess the format from a file name, for files Civitai has no format for
+    pub fn from_filename(name: &str) -> Option<FileFormat> {
+        let name = name.to_lowercase();
+        match name {
+            n if n.ends_with(SAFETENSORS_EXTENSION) => Some(FileFormat::SafeTensor),
+            n if n.ends_with(GGUF_EXTENSION) => Some(FileFormat::Gguf),
+            n if PICKLE_EXTENSIONS.iter().any(|ext| n.ends_with(ext)) => Some(FileFormat::PickleTensor),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+    /// Accepts the API names, and the usual file extensions (safetensors, ckpt, pt)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match normalize(s).as_str() {
+            "safetensor" | "safetensors" => Ok(FileFormat::SafeTensor),
+            "pickletensor" | "pickle" | "ckpt" | "pt" => Ok(FileFormat::PickleTensor),
+            "gguf" => Ok(FileFormat::Gguf),
+            "diffusers" => Ok(FileFormat::Diffusers),
+            "other" => Ok(FileFormat::Other),
+            _ => Err(format!("Vorpal: Unknown file format '{}'. Expected one of: safetensors, pickletensor, gguf, diffusers, other", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for FileFormat {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Which file of a model version to pick. With no filters set, the best
+/// file by the preference order above is picked.
+pub struct FileSelector {
+    format: Option<FileFormat>,
+    precision: Option<String>,
+    size: Option<String>,
+    file_type: Option<String>,
+    allow_pickle: bool,
+    allow_unsafe: bool,
+}
+
+impl FileSelector {
+    pub fn new() -> Self {
+        FileSelector::default()
+    }
+
+    /// Only files in this format
+    pub fn format(mut self, format: FileFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Only files with this floating point precision, e.g. "fp16", "fp32" or "bf16"
+    pub fn precision(mut self, precision: impl Into<String>) -> Self {
+        self.precision = Some(precision.into());
+        self
+    }
+
+    /// Only "pruned" or only "full" files
+    pub fn size(mut self, size: impl Into<String>) -> Self {
+        self.size = Some(size.into());
+        self
+    }
+
+    /// Only files of this type, e.g. "Model", "Pruned Model", "VAE" or "Config".
+    /// This also allows picking a file that is not a model.
+    pub fn file_type(mut self, file_type: impl Into<String>) -> Self {
+        self.file_type = Some(file_type.into());
+        self
+    }
+
+    /// Allow picking pickle files (.ckpt, .pt) when there is no better file
+    pub fn allow_pickle(mut self, allow: bool) -> Self {
+        self.allow_pickle = allow;
+        self
+    }
+
+    /// Allow picking files that failed Civitai's pickle or virus scan
+    pub fn allow_unsafe(mut self, allow: bool) -> Self {
+        self.allow_unsafe = allow;
+        self
+    }
+
+    /// Refuse file if it is a pickle or failed a scan, and that is not allowed
+    ///
+    /// Errors:
+    ///     - VorpalError::UnsafeFile, saying why
+    pub fn check_safety(&self, file: &ModelFile) -> VorpalResult<()> {
+        let reason = match file {
+            f if f.scan_failed() && !self.allow_unsafe => REASON_SCAN,
+            f if f.is_pickle() && !self.allow_pickle => REASON_PICKLE,
+            _ => return Ok(()),
+        };
+        Err(VorpalError::UnsafeFile { name: file.get_name(), reason: reason.to_string() })
+    }
+
+    /// Whether file passes every filter that is set
+    pub fn matches(&self, file: &ModelFile) -> bool {
+        let same = |wanted: &Option<String>, actual: Option<String>| match wanted {
+            Some(wanted) => actual.is_some_and(|actual| normalize(&actual) == normalize(wanted)),
+            None => true,
+        };
+        let format_ok = self.format.is_none() || file.get_format() == self.format;
+        format_ok
+            && same(&self.precision, file.get_metadata().fp)
+            && same(&self.size, file.get_metadata().size)
+            && same(&self.file_type, file.get_type())
+    }
+
+    /// The best file that passes the filters, if any does
+    pub fn select<'a>(&self, files: &'a [ModelFile]) -> Option<&'a ModelFile> {
+        // max_by_key keeps the last of equal files, so search in reverse to prefer
+        // the first (Civitai lists the newest upload first)
+        files.iter()
+            .rev()
+            .filter(|file| self.matches(file))
+            .max_by_key(|file| self.rank(file))
+    }
+
+    /// Sort key for files that passed the filters. Bigger is better.
+    fn rank(&self, file: &ModelFile) -> (bool, bool, bool, bool, bool, bool) {
+        let is_model = self.file_type.is_some() || file.get_type()
+            .is_none_or(|t| MODEL_FILE_TYPES.iter().any(|m| normalize(m) == normalize(&t)));
+        let metadata = file.get_metadata();
+        (
+            is_model,
+            !file.scan_failed(),
+            file.get_format() == Some(FileFormat::SafeTensor),
+            file.is_primary(),
+            metadata.fp.is_some_and(|fp| fp.eq_ignore_ascii_case(PREFERRED_PRECISION)),
+            metadata.size.is_some_and(|size| size.eq_ignore_ascii_case(PREFERRED_SIZE)),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Which version of a model to pick. Parses "#2" as an index, plain numbers
+/// as a Number and anything else as a name.
+pub enum VersionSelector {
+    /// Position in the model's version list, starting from 1 (the newest)
+    Index(usize),
+    /// The version Id (the modelVersionId in a Civitai url)
+    Id(u32),
+    /// A version Id if the model has a version with it, otherwise an index
+    Number(u32),
+    /// The version name, e.g. "v2.0" or "Inpainting", ignoring case.
+    /// Exact matches win over versions whose name merely contains it.
+    Name(String),
+}
+
+impl VersionSelector {
+    /// The version this selector picks out of versions, if any
+    pub fn select<'a>(&self, versions: &'a [ModelVersion]) -> Option<&'a ModelVersion> {
+        let by_index = |index: usize| index.checked_sub(1).and_then(|i| versions.get(i));
+        let by_id = |id: u32| versions.iter().find(|v| v.id == id);
+        match self {
+            VersionSelector::Index(index) => by_index(*index),
+            VersionSelector::Id(id) => by_id(*id),
+            VersionSelector::Number(n) => by_id(*n).or_else(|| by_index(*n as usize)),
+            VersionSelector::Name(name) => {
+                let wanted = name.to_lowercase();
+                versions.iter()
+                    .find(|v| v.name.to_lowercase() == wanted)
+                    .or_else(|| versions.iter().find(|v| v.name.to_lowercase().contains(&wanted)))
+            },
+        }
+    }
+}
+
+impl FromStr for VersionSelector {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(index) = s.strip_prefix('#') {
+            return index.parse().map(VersionSelector::Index)
+                .map_err(|_| format!("Vorpal: '{}' is not a version number", s))
+        }
+        match s.parse() {
+            Ok(n) => Ok(VersionSelector::Number(n)),
+            Err(_) if s.is_empty() => Err("Vorpal: The version cannot be empty".to_string()),
+            Err(_) => Ok(VersionSelector::Name(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for VersionSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionSelector::Index(index) => write!(f, "#{}", index),
+            VersionSelector::Id(id) | VersionSelector::Number(id) => write!(f, "{}", id),
+            VersionSelector::Name(name) => f.write_str(name),
+        }
+    }
+}
@@ -43,60 +43,1160 @@ mod tests {
         assert_eq!(expected_outcome, shorten_unicode(input, trunc_length, trunc_trail));
     }
 
-    #[test]
+    #[tokio::test]
     // This uses the model id to verify that get_first is indeed getting the right
     // model.
-    fn get_first_test() {
+    async fn get_first_test() {
         let model_name = "cat".to_string();
         let safe = false;
         let expected_outcome = "20086".to_string();
-        let queryitem = get_first_query_item(model_name, safe);
+        let queryitem = get_first_query_item(model_name, safe).await.unwrap();
         assert_eq!(expected_outcome, queryitem.get_id());
     }
-    #[test]
+    #[tokio::test]
     // This test makes a query of 100 to ensure that Vorpal is able to make queryitems
     // properly. Previously, issues came about due to improper labeling, unicode breaking
     // things, etc. This test is necessary to ensure stability and integrity.
-    fn broad_query_test_1() {
+    async fn broad_query_test_1() {
         let model_name = "cat".to_string();
         let safe = true;
         let count = 100;
-        let query = get_query_items(model_name, count, safe);
+        let query = get_query_items(model_name, count, safe).await.unwrap();
         let len = query.len();
         assert_eq!(count, len as u8);
     }
-    #[test]
-    fn broad_query_test_2() {
+    #[tokio::test]
+    async fn broad_query_test_2() {
         let model_name = "dog".to_string();
         let safe = true;
         let count = 100;
-        let query = get_query_items(model_name, count, safe);
+        let query = get_query_items(model_name, count, safe).await.unwrap();
         let len = query.len();
         assert_eq!(count, len as u8);
     }
-    #[test]
-    fn broad_query_test_3() {
+    #[tokio::test]
+    async fn broad_query_test_3() {
         let model_name = "painting".to_string();
         let safe = true;
         let count = 100;
-        let query = get_query_items(model_name, count, safe);
+        let query = get_query_items(model_name, count, safe).await.unwrap();
         let len = query.len();
         assert_eq!(count, len as u8);
     }
-    #[test]
-    fn broad_query_test_4() {
+    #[tokio::test]
+    async fn broad_query_test_4() {
         let model_name = "girl".to_string();
         let safe = true;
         let count = 100;
-        let query = get_query_items(model_name, count, safe);
+        let query = get_query_items(model_name, count, safe).await.unwrap();
         let len = query.len();
         assert_eq!(count, len as u8);
     }
 
+    #[test]
+    // Scripts rely on these codes to tell a dead API apart from an empty search
+    fn exit_code_test() {
+        let no_results = Error::from(VorpalError::NoResults);
+        let other = anyhow::anyhow!("not a vorpal error");
+        let unsafe_file = Error::from(VorpalError::UnsafeFile { name: "a.ckpt".to_string(), reason: String::new() });
+        assert_eq!(crate::exit_code(&no_results), 5);
+        assert_eq!(crate::exit_code(&unsafe_file), 11);
+        assert_eq!(crate::exit_code(&other), 1);
+    }
+
+    // A minimal page from the models endpoint, trimmed to the fields vorpal reads
+    const MOCK_QUERY_JSON: &str = r#"{"items":[{"name":"Red Glitter","id":235002,"description":"<p>Shiny</p>",
+        "creator":{"username":"someone"},"tags":["glitter","style"],
+        "stats":{"downloadCount":1,"favoriteCount":2,"commentCount":3,"ratingCount":4,"rating":5.0,"tippedAmountCount":0},
+        "modelVersions":[{"id":264911,"modelId":235002,"name":"v1.0","trainedWords":["r3dglitter"],
+        "baseModel":"SDXL 1.0","baseModelType":"Standard",
+        "files":[{"id":1,"sizeKB":2.0,"name":"red_glitter.safetensors","downloadUrl":"https://civitai.com/api/download/models/264911"}]}]}],
+        "metadata":{}}"#;
+
+    /// Build a raw HTTP/1.1 response
+    fn http_response(status: u16, content_type: &str, body: &[u8]) -> Vec<u8> {
+        let mut res = format!("HTTP/1.1 {} X\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, content_type, body.len()).into_bytes();
+        res.extend_from_slice(body);
+        res
+    }
+
+    /// Serve HTTP on a random local port, answering each request with handler(request head).
+    /// Returns the base url of the server.
+    fn mock_server<F>(handler: F) -> String
+    where F: Fn(&str) -> Vec<u8> + Send + 'static {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream { Ok(s) => s, Err(_) => break };
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut byte) {
+                        Ok(1) => head.push(byte[0]),
+                        _ => break,
+                    }
+                }
+                let response = handler(&String::from_utf8_lossy(&head));
+                let _ = stream.write_all(&response);
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn client_base_url_test() {
+        let base = mock_server(|head| {
+            assert!(head.starts_with("GET /api/v1/models?"));
+            assert!(head.contains("query=red+glitter"));
+            http_response(200, "application/json", MOCK_QUERY_JSON.as_bytes())
+        });
+        let client = CivitaiClient::builder().base_url(base).build().unwrap();
+        let items = client.get_query_items("red glitter".to_string(), 1, false).await.unwrap();
+        assert_eq!(items[0].get_id(), "235002");
+    }
+
+    #[tokio::test]
+    async fn client_http_status_test() {
+        let base = mock_server(|_| http_response(503, "text/plain", b"down"));
+        let client = CivitaiClient::builder().base_url(base).build().unwrap();
+        let result = client.get_query_items("cat".to_string(), 1, false).await;
+        assert!(matches!(result, Err(VorpalError::HttpStatus { status: 503, .. })));
+    }
+
+    #[tokio::test]
+    async fn client_api_token_test() {
+        let base = mock_server(|head| {
+            match head.contains("authorization: Bearer secret") {
+                true => http_response(200, "application/json", MOCK_QUERY_JSON.as_bytes()),
+                false => http_response(401, "text/plain", b"unauthorized"),
+            }
+        });
+        let anonymous = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let result = anonymous.get_query_items("cat".to_string(), 1, false).await;
+        assert!(matches!(result, Err(VorpalError::AuthRequired { .. })));
+
+        let authed = CivitaiClient::builder().base_url(base).api_token("secret").build().unwrap();
+        assert!(authed.get_query_items("cat".to_string(), 1, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    // Urls that only start like the base url (another port, or the base host as
+    // userinfo, as in https://civitai.com@evil.example) must not get the token
+    async fn client_api_token_host_test() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        let model = safetensors::encode_header(&[], &Default::default()).unwrap();
+        let leaked = Arc::new(AtomicBool::new(false));
+        let seen = leaked.clone();
+        let server = mock_server(move |head| {
+            if head.contains("Bearer secret") { seen.store(true, Ordering::SeqCst) }
+            http_response(200, "application/octet-stream", &model)
+        });
+        let path = std::env::temp_dir().join("vorpal_client_api_token_host_test.safetensors");
+        let client = CivitaiClient::builder().base_url("http://127.0.0.1").api_token("secret").build().unwrap();
+        let look_alikes = [
+            format!("{}/api/download/models/1", server),
+            format!("{}/api/download/models/1", server.replace("http://", "http://127.0.0.1@")),
+        ];
+        for url in look_alikes {
+            client.download_file_by_url(url, path.display().to_string()).await.unwrap();
+            std::fs::remove_file(&path).unwrap();
+        }
+        assert!(!leaked.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    // Two pages linked by a cursor should come out as one uninterrupted stream
+    async fn query_stream_cursor_test() {
+        use futures_util::TryStreamExt;
+        let base = mock_server(|head| {
+            let page = MOCK_QUERY_JSON.trim_end_matches("\"metadata\":{}}");
+            match head.contains("cursor=abc") {
+                true => http_response(200, "application/json", format!("{}\"metadata\":{{}}}}", page).as_bytes()),
+                false => http_response(200, "application/json", format!("{}\"metadata\":{{\"nextCursor\":\"abc\"}}}}", page).as_bytes()),
+            }
+        });
+        let client = CivitaiClient::builder().base_url(base).build().unwrap();
+        let items: Vec<QueryItem> = client.query_stream("cat".to_string(), 1, false)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test]
+    fn search_params_test() {
+        let params = SearchParams::new()
+            .query("")
+            .limit(20)
+            .types(vec!["lora".parse().unwrap(), "checkpoint".parse().unwrap()])
+            .sort("most-downloaded".parse().unwrap())
+            .period(Period::Week)
+            .tag("style")
+            .safe(true);
+        let expected: Vec<(&str, String)> = vec![
+            ("limit", "20".to_string()),
+            ("types", "LORA".to_string()),
+            ("types", "Checkpoint".to_string()),
+            ("sort", "Most Downloaded".to_string()),
+            ("period", "Week".to_string()),
+            ("tag", "style".to_string()),
+            ("nsfw", "false".to_string()),
+        ];
+        assert_eq!(params.to_query_pairs(), expected);
+        assert!("not a type".parse::<ModelType>().is_err());
+    }
+
+    #[tokio::test]
+    async fn get_model_version_test() {
+        let base = mock_server(|head| {
+            assert!(head.starts_with("GET /api/v1/model-versions/264911 "));
+            let body = r#"{"id":264911,"modelId":235002,"name":"v1.0","trainedWords":["r3dglitter"],
+                "baseModel":"SDXL 1.0","files":[{"id":1,"sizeKB":2.0,"name":"red_glitter.safetensors",
+                "downloadUrl":"https://civitai.com/api/download/models/264911"}],
+                "model":{"name":"Red Glitter","type":"LORA","nsfw":false}}"#;
+            http_response(200, "application/json", body.as_bytes())
+        });
+        let client = CivitaiClient::builder().base_url(base).build().unwrap();
+        let version = client.get_model_version(264911).await.unwrap();
+        assert_eq!(version.get_model_id(), "235002");
+        assert_eq!(version.get_model_name(), Some("Red Glitter".to_string()));
+        assert!(version.generate_model_report().contains(&"Version Id: 264911".to_string()));
+    }
+
+    #[test]
+    // The default pick is a SafeTensor model, and set filters must match
+    fn file_selector_test() {
+        let version: ModelVersion = serde_json::from_str(r#"{"id":1,"modelId":1,"name":"v1","trainedWords":[],"files":[
+            {"id":1,"sizeKB":4000.0,"name":"full.ckpt","downloadUrl":"","type":"Model","primary":true,
+                "metadata":{"fp":"fp32","size":"full","format":"PickleTensor"}},
+            {"id":2,"sizeKB":2000.0,"name":"pruned.safetensors","downloadUrl":"","type":"Pruned Model",
+                "metadata":{"fp":"fp16","size":"pruned","format":"SafeTensor"}},
+            {"id":3,"sizeKB":4000.0,"name":"full.safetensors","downloadUrl":"","type":"Model",
+                "metadata":{"fp":"fp32","size":"full","format":"SafeTensor"}},
+            {"id":4,"sizeKB":300.0,"name":"vae.safetensors","downloadUrl":"","type":"VAE",
+                "metadata":{"fp":"fp16","format":"SafeTensor"}}]}"#).unwrap();
+        let pick = |selector: FileSelector| version.select_file(&selector).map(|f| f.get_name());
+
+        assert_eq!(version.get_latest_file().unwrap().get_name(), "pruned.safetensors");
+        assert_eq!(pick(FileSelector::new().precision("FP32")).unwrap(), "full.safetensors");
+        assert_eq!(pick(FileSelector::new().format("ckpt".parse().unwrap())).unwrap(), "full.ckpt");
+        assert_eq!(pick(FileSelector::new().file_type("vae")).unwrap(), "vae.safetensors");
+        assert_eq!(pick(FileSelector::new().size("full").format(FileFormat::SafeTensor)).unwrap(), "full.safetensors");
+        assert!(matches!(version.get_file(&FileSelector::new().precision("bf16")),
+            Err(VorpalError::NoMatchingFile { available, .. }) if available.len() == 4));
+    }
+
+    #[test]
+    // Pickles and files that failed a scan are refused unless allowed, and safe files win
+    fn unsafe_file_test() {
+        let version = |files: &str| -> ModelVersion {
+            serde_json::from_str(&format!(r#"{{"id":1,"modelId":1,"name":"v1","trainedWords":[],"files":[{}]}}"#, files)).unwrap()
+        };
+        let pickle = r#"{"id":1,"sizeKB":1.0,"name":"model.ckpt","downloadUrl":"","primary":true,
+            "pickleScanResult":"Success","pickleScanMessage":"No Pickle imports","virusScanResult":"Success"}"#;
+        let safe = r#"{"id":2,"sizeKB":1.0,"name":"model.safetensors","downloadUrl":"","virusScanResult":"Success"}"#;
+        let infected = r#"{"id":3,"sizeKB":1.0,"name":"bad.safetensors","downloadUrl":"","virusScanResult":"Danger"}"#;
+
+        let both = version(&format!("{},{}", pickle, safe));
+        assert_eq!(both.get_file(&FileSelector::new()).unwrap().get_name(), "model.safetensors");
+
+        let only_pickle = version(pickle);
+        assert!(matches!(only_pickle.get_file(&FileSelector::new()), Err(VorpalError::UnsafeFile { .. })));
+        assert!(only_pickle.get_file(&FileSelector::new().allow_pickle(true)).is_ok());
+        assert!(only_pickle.generate_model_report().contains(&"Pickle Scan: Success (No Pickle imports)".to_string()));
+
+        let failed = version(infected);
+        assert!(matches!(failed.get_file(&FileSelector::new().allow_pickle(true)), Err(VorpalError::UnsafeFile { .. })));
+        assert!(failed.get_file(&FileSelector::new().allow_unsafe(true)).is_ok());
+        assert_eq!(version(&format!("{},{}", infected, pickle)).get_latest_file().unwrap().get_name(), "model.ckpt");
+    }
+
+    #[test]
+    fn version_selector_test() {
+        let json = r#"{"name":"Dreamy","id":5,"description":null,"creator":{"username":"someone"},"tags":[],
+            "stats":{"downloadCount":1,"favoriteCount":2,"commentCount":3,"ratingCount":4,"rating":5.0,"tippedAmountCount":0},
+            "modelVersions":[
+                {"id":300,"modelId":5,"name":"v3.0","trainedWords":[],"baseModel":"SDXL 1.0","publishedAt":"2024-01-12T10:00:00.000Z","files":[]},
+                {"id":200,"modelId":5,"name":"v2.0 Inpainting","trainedWords":[],"baseModel":"SD 1.5","files":[]},
+                {"id":2,"modelId":5,"name":"v2.0","trainedWords":[],"baseModel":"SD 1.5","createdAt":"2023-03-01T00:00:00.000Z","files":[]}]}"#;
+        let item: QueryItem = serde_json::from_str(json).unwrap();
+        let pick = |s: &str| item.get_version(&s.parse().unwrap()).map(|v| v.get_id());
+
+        assert_eq!(pick("#2").unwrap(), "200");
+        assert_eq!(pick("200").unwrap(), "200");
+        // Not an Id of this model, so a position
+        assert_eq!(pick("3").unwrap(), "2");
+        assert_eq!(pick("V2.0").unwrap(), "2");
+        assert_eq!(pick("inpainting").unwrap(), "200");
+        assert!(matches!(pick("v9"), Err(VorpalError::NoMatchingVersion { available, .. }) if available.len() == 3));
+        assert_eq!(item.generate_version_list()[0], "[1] v3.0 | SDXL 1.0 | 2024-01-12 | Id: 300");
+    }
+
+    #[test]
+    // Models without versions and versions without files are errors, not panics
+    fn empty_model_test() {
+        let json = r#"{"name":"Empty","id":6,"description":null,"creator":{"username":"someone"},"tags":[],
+            "stats":{"downloadCount":1,"favoriteCount":2,"commentCount":3,"ratingCount":4,"rating":5.0,"tippedAmountCount":0},
+            "modelVersions":[]}"#;
+        let item: QueryItem = serde_json::from_str(json).unwrap();
+        assert!(matches!(item.get_first(), Err(VorpalError::NoMatchingVersion { .. })));
+        assert!(item.make_cli_query_display(false).is_err());
+
+        let json = json.replace(r#""modelVersions":[]"#,
+            r#""modelVersions":[{"id":7,"modelId":6,"name":"v1","trainedWords":[],"files":[]}]"#);
+        let item: QueryItem = serde_json::from_str(&json).unwrap();
+        let version = item.get_first().unwrap();
+        assert!(matches!(version.get_latest_file(), Err(VorpalError::NoMatchingFile { .. })));
+        assert!(item.get_download_url().is_err());
+        assert!(item.make_cli_query_display(false).is_err());
+        assert_eq!(version.generate_model_report()[0], "Model: Empty");
+    }
+
+    #[test]
+    fn sha256_file_test() {
+        let path = std::env::temp_dir().join("vorpal_sha256_file_test");
+        std::fs::write(&path, b"abc").unwrap();
+        let expected = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        assert_eq!(libvorpal::hash::sha256_file(&path).unwrap(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    // A leftover .part file should be continued with a Range request, not restarted
+    async fn resume_download_test() {
+        const BODY: &[u8] = b"0123456789";
+        let base = mock_server(|head| {
+            assert!(head.contains("range: bytes=4-"));
+            assert!(head.contains("if-range: \"v1\""));
+            let mut res = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 4-9/10\r\nETag: \"v1\"\r\nContent-Length: 6\r\nConnection: close\r\n\r\n".to_vec();
+            res.extend_from_slice(&BODY[4..]);
+            res
+        });
+        let dir = std::env::temp_dir().join("vorpal_resume_download_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.safetensors");
+        std::fs::write(download::part_path(&path), &BODY[..4]).unwrap();
+        std::fs::write(dir.join("model.safetensors.part.json"), r#"{"etag":"\"v1\"","total":10}"#).unwrap();
+
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let url = format!("{}/api/download/models/1", base);
+        client.download_file_by_url(url, path.display().to_string()).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!download::part_path(&path).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    // A file that does not match its published hash must not end up under its real name
+    async fn hash_mismatch_test() {
+        let base = mock_server(|_| http_response(200, "application/octet-stream", b"not the model"));
+        let dir = std::env::temp_dir().join("vorpal_hash_mismatch_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.safetensors");
+
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let options = DownloadOptions::new()
+            .expected_sha256("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+            .on_mismatch(MismatchAction::Quarantine);
+        let url = format!("{}/api/download/models/1", base);
+        let result = client.download_file_with_options(url, path.display().to_string(), &options).await;
+        assert!(matches!(result, Err(VorpalError::HashMismatch { path: Some(_), .. })));
+        assert!(!path.exists());
+        assert!(download::quarantine_path(&path).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    // One failing job must not stop the others, and outcomes keep the order jobs were added in
+    async fn download_queue_test() {
+        use std::sync::{Arc, Mutex};
+        let model = safetensors::encode_header(&[], &Default::default()).unwrap();
+        let body = model.clone();
+        let base = mock_server(move |head| match head.starts_with("GET /missing ") {
+            true => http_response(404, "text/plain", b"gone"),
+            false => http_response(200, "application/octet-stream", &body),
+        });
+        let dir = std::env::temp_dir().join("vorpal_download_queue_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let version = |name: &str, path: &str| -> ModelVersion {
+            let json = format!(r#"{{"id":1,"modelId":1,"name":"v1","trainedWords":[],"baseModel":"SD 1.5",
+                "files":[{{"id":1,"sizeKB":0.01,"name":"{}","downloadUrl":"{}{}"}}]}}"#, name, base, path);
+            serde_json::from_str(&json).unwrap()
+        };
+
+        let finished = Arc::new(Mutex::new(0));
+        let seen = finished.clone();
+        let observer = move |_: &DownloadJob, status: &JobStatus| {
+            if matches!(status, JobStatus::Done(_) | JobStatus::Failed(_)) { *seen.lock().unwrap() += 1 }
+        };
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let outcomes = DownloadQueue::new(client)
+            .concurrency(2)
+            .observer(Arc::new(observer))
+            .push(DownloadJob::new(version("a.safetensors", "/a"), &dir))
+            .push(DownloadJob::new(version("b.safetensors", "/missing"), &dir))
+            .push(DownloadJob::new(version("c.safetensors", "/c"), &dir))
+            .run()
+            .await;
+
+        let names: Vec<String> = outcomes.iter().map(|o| o.job.get_version().get_model_filename().unwrap()).collect();
+        assert_eq!(names, ["a.safetensors", "b.safetensors", "c.safetensors"]);
+        assert!(outcomes[0].result.is_ok());
+        assert!(matches!(outcomes[1].result, Err(VorpalError::NotFound { .. })));
+        assert!(outcomes[2].result.is_ok());
+        assert_eq!(std::fs::read(dir.join("c.safetensors")).unwrap(), model);
+        assert_eq!(*finished.lock().unwrap(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    // Jobs that would save under the same name get their own files, not one shared .part
+    async fn download_queue_same_name_test() {
+        let first = safetensors::encode_header(&[], &Default::default()).unwrap();
+        let metadata = std::collections::BTreeMap::from([("job".to_string(), "second".to_string())]);
+        let second = safetensors::encode_header(&[], &metadata).unwrap();
+        let (first_body, second_body) = (first.clone(), second.clone());
+        let base = mock_server(move |head| match head.starts_with("GET /second ") {
+            true => http_response(200, "application/octet-stream", &second_body),
+            false => http_response(200, "application/octet-stream", &first_body),
+        });
+        let dir = std::env::temp_dir().join("vorpal_download_queue_same_name_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let version = |id: u32, path: &str| -> ModelVersion {
+            let json = format!(r#"{{"id":{},"modelId":1,"name":"v1","trainedWords":[],"baseModel":"SD 1.5",
+                "files":[{{"id":{},"sizeKB":0.01,"name":"model.safetensors","downloadUrl":"{}{}"}}]}}"#, id, id, base, path);
+            serde_json::from_str(&json).unwrap()
+        };
+
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let outcomes = DownloadQueue::new(client)
+            .concurrency(3)
+            .push(DownloadJob::new(version(1, "/first"), &dir))
+            .push(DownloadJob::new(version(2, "/second"), &dir))
+            .push(DownloadJob::new(version(3, "/third"), &dir).on_collision(OnCollision::Fail))
+            .run()
+            .await;
+
+        assert_eq!(outcomes[0].path, Some(dir.join("model.safetensors")));
+        assert_eq!(outcomes[1].path, Some(dir.join("model_1.safetensors")));
+        assert!(matches!(outcomes[2].result, Err(VorpalError::FileExists { .. })));
+        assert_eq!(std::fs::read(dir.join("model.safetensors")).unwrap(), first);
+        assert_eq!(std::fs::read(dir.join("model_1.safetensors")).unwrap(), second);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    // A 416 to a request without a range is an error, not a panic or a restart
+    async fn range_not_satisfiable_test() {
+        let base = mock_server(|_| http_response(416, "text/plain", b"no"));
+        let path = std::env::temp_dir().join("vorpal_range_not_satisfiable_test.safetensors");
+        let _ = download::discard_partial(&path);
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let result = client.download_file_by_url(format!("{}/api/download/models/1", base), path.display().to_string()).await;
+        assert!(matches!(result, Err(VorpalError::HttpStatus { status: 416, .. })));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    // A login page served with a 200 status must not be saved as the model
+    async fn html_download_test() {
+        let base = mock_server(|_| http_response(200, "text/html; charset=utf-8", b"<html>Log in</html>"));
+        let path = std::env::temp_dir().join("vorpal_html_download_test.safetensors");
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let result = client.download_file_by_url(format!("{}/api/download/models/1", base), path.display().to_string()).await;
+        assert!(matches!(result, Err(VorpalError::UnexpectedContentType { .. })));
+        assert!(!path.exists());
+        assert!(!download::part_path(&path).exists());
+    }
+
+    #[tokio::test]
+    // Files that are not what Civitai describes are moved aside, not kept as models
+    async fn invalid_download_test() {
+        let model = safetensors::encode_header(&[], &Default::default()).unwrap();
+        let body = model.clone();
+        let base = mock_server(move |head| match head.starts_with("GET /page ") {
+            true => http_response(200, "application/octet-stream", b"<!DOCTYPE html><html>Oops</html>"),
+            false => http_response(200, "application/octet-stream", &body),
+        });
+        let dir = std::env::temp_dir().join("vorpal_invalid_download_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.safetensors");
+        let file = |size_kb: f64, url: &str| -> ModelFile {
+            serde_json::from_str(&format!(r#"{{"id":1,"sizeKB":{},"name":"model.safetensors","downloadUrl":"{}{}"}}"#, size_kb, base, url)).unwrap()
+        };
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let download = |file: ModelFile| {
+            let (client, path) = (client.clone(), path.display().to_string());
+            async move { client.download_model_file(&file, path, &DownloadOptions::new()).await }
+        };
+
+        let result = download(file(0.03, "/page")).await;
+        assert!(matches!(result, Err(VorpalError::InvalidDownload { path: Some(_), .. })));
+        assert!(!path.exists());
+        assert!(download::quarantine_path(&path).exists());
+
+        let result = download(file(2048.0, "/model")).await;
+        assert!(matches!(result, Err(VorpalError::InvalidDownload { ref message, .. }) if message.contains("bytes")));
+        assert!(!path.exists());
+
+        download(file(0.02, "/model")).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), model);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    // Whatever the server calls a file, it must land directly in the model directory
+    fn hostile_filename_test() {
+        let cases = [
+            ("model.safetensors", "model.safetensors"),
+            ("猫 LoRA v2.safetensors", "猫 LoRA v2.safetensors"),
+            ("../../.bashrc", "bashrc"),
+            ("../../../etc/passwd", "passwd"),
+            ("..\\..\\Windows\\win.ini", "win.ini"),
+            ("/absolute/path/model.ckpt", "model.ckpt"),
+            ("C:\\Users\\model.ckpt", "model.ckpt"),
+            ("models/", "download"),
+            ("a\0b.safetensors", "a_b.safetensors"),
+            ("line\nbreak.pt", "line_break.pt"),
+            ("what?<is>:this|\"*.ckpt", "what__is__this___.ckpt"),
+            (".", "download"),
+            ("..", "download"),
+            ("", "download"),
+            ("   ", "download"),
+            ("...hidden", "hidden"),
+            ("model.safetensors. . ", "model.safetensors"),
+            ("CON", "_CON"),
+            ("con.safetensors", "_con.safetensors"),
+            ("LPT1.txt", "_LPT1.txt"),
+            ("CONSOLE.safetensors", "CONSOLE.safetensors"),
+        ];
+        let dir = std::path::Path::new("/models/Lora");
+        for (hostile, expected) in cases {
+            assert_eq!(filename::sanitize(hostile), expected, "{:?}", hostile);
+            let path = filename::join(dir, hostile);
+            assert_eq!(path.parent(), Some(dir), "{:?}", hostile);
+            assert_eq!(path.components().count(), dir.components().count() + 1, "{:?}", hostile);
+        }
+
+        let long = format!("{}.safetensors", "é".repeat(300));
+        let sanitized = filename::sanitize(&long);
+        assert!(sanitized.len() <= 255);
+        assert!(sanitized.ends_with("é.safetensors"));
+    }
+
+    #[test]
+    fn content_disposition_test() {
+        let parse = filename::from_content_disposition;
+        assert_eq!(parse(r#"attachment; filename="cat.safetensors""#).unwrap(), "cat.safetensors");
+        assert_eq!(parse(r#"attachment; filename="../../evil.sh""#).unwrap(), "evil.sh");
+        assert_eq!(parse("attachment; filename*=UTF-8''%E7%8C%AB.safetensors").unwrap(), "猫.safetensors");
+        assert_eq!(parse("attachment; filename*=UTF-8''..%2F..%2Fx.safetensors").unwrap(), "x.safetensors");
+        assert_eq!(parse(r#"attachment; filename="plain.ckpt"; filename*=UTF-8''fancy%20name.ckpt"#).unwrap(), "fancy name.ckpt");
+        assert_eq!(parse(r#"attachment; filename*=broken.ckpt; filename="plain.ckpt""#).unwrap(), "plain.ckpt");
+        assert_eq!(parse("attachment"), None);
+        assert_eq!(parse(r#"attachment; filename="""#), None);
+        assert_eq!(filename::from_url("https://cdn.example.com/files/my%20model.safetensors?token=1").unwrap(), "my model.safetensors");
+        assert_eq!(filename::from_url("https://cdn.example.com/"), None);
+    }
+
+    #[tokio::test]
+    async fn download_to_dir_test() {
+        let base = mock_server(|_| {
+            let body = b"weights";
+            let mut res = format!("HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Disposition: attachment; filename=\"../../escape.safetensors\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()).into_bytes();
+            res.extend_from_slice(body);
+            res
+        });
+        let dir = std::env::temp_dir().join("vorpal_download_to_dir_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let path = client.download_file_to_dir(format!("{}/api/download/models/1", base), &dir, &DownloadOptions::new()).await.unwrap();
+        assert_eq!(path, dir.join("escape.safetensors"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"weights");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filename_template_test() {
+        let version: ModelVersion = serde_json::from_str(r#"{"id":264911,"modelId":235002,"name":"v1.0/final",
+            "trainedWords":[],"baseModel":"SDXL 1.0","model":{"name":"Red Glitter","type":"LORA"},
+            "files":[{"id":1,"sizeKB":2.0,"name":"red_glitter.safetensors","downloadUrl":"",
+                "metadata":{"fp":"fp16","format":"SafeTensor"}}]}"#).unwrap();
+        let file = version.get_latest_file().unwrap();
+        let render = |template: &str| template.parse::<FilenameTemplate>().unwrap().render(&version, &file);
+
+        assert_eq!(FilenameTemplate::default().render(&version, &file), "red_glitter.safetensors");
+        assert_eq!(render("{model}-{version}-{versionId}.{ext}"), "Red Glitter-v1.0_final-264911.safetensors");
+        assert_eq!(render("{type}_{baseModel}_{precision}_{name}.{ext}"), "LORA_SDXL 1.0_fp16_red_glitter.safetensors");
+        // Slashes in the template itself cannot make a directory either
+        assert_eq!(render("{size}/../{filename}"), "red_glitter.safetensors");
+        assert!("{model".parse::<FilenameTemplate>().is_err());
+        assert!("{author}.{ext}".parse::<FilenameTemplate>().is_err());
+        assert!("".parse::<FilenameTemplate>().is_err());
+    }
+
+    #[test]
+    fn on_collision_test() {
+        let dir = std::env::temp_dir().join("vorpal_on_collision_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lora.safetensors");
+        let resolve = |policy: &str, sha256: Option<&str>| filename::resolve(&path, policy.parse().unwrap(), sha256);
+
+        assert_eq!(resolve("fail", None).unwrap(), filename::Destination::Write(path.clone()));
+        std::fs::write(&path, b"weights").unwrap();
+        let sha256 = hash::sha256_bytes(b"weights");
+        let renamed = filename::Destination::Write(dir.join("lora_1.safetensors"));
+
+        assert_eq!(resolve("skip", Some(&sha256)).unwrap(), filename::Destination::Existing(path.clone(), sha256.clone()));
+        assert_eq!(resolve("skip", Some(&hash::sha256_bytes(b"other"))).unwrap(), renamed);
+        assert_eq!(resolve("skip", None).unwrap(), renamed);
+        assert_eq!(resolve("rename", Some(&sha256)).unwrap(), renamed);
+        assert_eq!(resolve("overwrite", Some(&sha256)).unwrap(), filename::Destination::Write(path.clone()));
+        assert!(matches!(resolve("fail", Some(&sha256)), Err(VorpalError::FileExists { .. })));
+        std::fs::write(dir.join("lora_1.safetensors"), b"other").unwrap();
+        assert_eq!(resolve("rename", None).unwrap(), filename::Destination::Write(dir.join("lora_2.safetensors")));
+        // A renamed name still fits in 255 bytes
+        let long = dir.join(filename::sanitize(&format!("{}.safetensors", "é".repeat(200))));
+        std::fs::write(&long, b"weights").unwrap();
+        match filename::resolve(&long, OnCollision::Rename, None).unwrap() {
+            filename::Destination::Write(renamed) => {
+                let name = renamed.file_name().unwrap().to_str().unwrap();
+                assert!(name.len() <= 255 && name.ends_with("_1.safetensors"));
+            },
+            other => panic!("expected a new name, got {:?}", other),
+        }
+        assert!("sometimes".parse::<OnCollision>().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn layout_router_test() {
+        let version = |model_type: &str, file_type: &str| -> ModelVersion {
+            serde_json::from_str(&format!(r#"{{"id":1,"modelId":1,"name":"v1","trainedWords":[],
+                "model":{{"name":"m","type":"{}"}},
+                "files":[{{"id":1,"sizeKB":1.0,"name":"m.safetensors","downloadUrl":"","type":"{}"}}]}}"#,
+                model_type, file_type)).unwrap()
+        };
+        let root = std::path::Path::new("/opt/ui");
+        let dir = |router: &Router, model_type: &str, file_type: &str| {
+            let version = version(model_type, file_type);
+            router.get_dir(root, &version, &version.get_latest_file().unwrap())
+        };
+
+        let webui = Router::new("forge".parse().unwrap());
+        assert_eq!(dir(&webui, "Checkpoint", "Model"), root.join("models/Stable-diffusion"));
+        assert_eq!(dir(&webui, "LoCon", "Model"), root.join("models/Lora"));
+        assert_eq!(dir(&webui, "TextualInversion", "Model"), root.join("embeddings"));
+        assert_eq!(dir(&webui, "Checkpoint", "VAE"), root.join("models/VAE"));
+        assert_eq!(dir(&webui, "Wildcards", "Archive"), root);
+
+        let comfy = Router::new(Layout::ComfyUi).route("dora", "models/loras").route("lora", "/srv/loras");
+        assert_eq!(dir(&comfy, "Upscaler", "Model"), root.join("models/upscale_models"));
+        assert_eq!(dir(&comfy, "DoRA", "Model"), root.join("models/loras"));
+        assert_eq!(dir(&comfy, "LORA", "Model"), std::path::Path::new("/srv/loras"));
+        assert_eq!(dir(&Router::default(), "LORA", "Model"), root);
+        assert!("invokeai".parse::<Layout>().is_err());
+    }
+
+    #[test]
+    fn directory_expand_test() {
+        std::env::set_var("VORPAL_TEST_MODELS", "/srv/models");
+        std::env::remove_var("VORPAL_TEST_UNSET");
+        let aliases = std::collections::BTreeMap::from([
+            ("sdxl".to_string(), "$VORPAL_TEST_MODELS/sdxl".to_string()),
+            ("home".to_string(), "~/models".to_string()),
+        ]);
+        let expand = |raw: &str| directory::expand(raw, &aliases);
+        let home = dirs::home_dir().unwrap();
+
+        assert_eq!(expand("$VORPAL_TEST_MODELS/Lora").unwrap(), std::path::Path::new("/srv/models/Lora"));
+        assert_eq!(expand("${VORPAL_TEST_MODELS}_old").unwrap(), std::path::Path::new("/srv/models_old"));
+        assert_eq!(expand("~/models").unwrap(), home.join("models"));
+        assert_eq!(expand("~").unwrap(), home);
+        assert_eq!(expand("~other/models").unwrap(), std::path::Path::new("~other/models"));
+        assert_eq!(expand("costs $5").unwrap(), std::path::Path::new("costs $5"));
+        assert_eq!(expand("@sdxl").unwrap(), std::path::Path::new("/srv/models/sdxl"));
+        assert_eq!(expand("@sdxl/styles").unwrap(), std::path::Path::new("/srv/models/sdxl/styles"));
+        assert_eq!(expand("@home").unwrap(), home.join("models"));
+        assert!(matches!(expand("@sd15"), Err(VorpalError::InvalidDirectory { .. })));
+        assert!(matches!(expand("$VORPAL_TEST_UNSET/Lora"), Err(VorpalError::InvalidDirectory { .. })));
+        assert!(expand("${VORPAL_TEST_MODELS").is_err());
+    }
+
+    #[test]
+    fn directory_prepare_test() {
+        let dir = std::env::temp_dir().join("vorpal_directory_prepare_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let nested = dir.join("models/Lora");
+        assert!(matches!(directory::prepare(&nested, false), Err(VorpalError::InvalidDirectory { .. })));
+        directory::prepare(&nested, true).unwrap();
+        assert!(nested.is_dir());
+        directory::prepare(&nested, false).unwrap();
+        std::fs::write(nested.join("file"), b"").unwrap();
+        assert!(directory::prepare(&nested.join("file"), true).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn config_profile_test() {
+        let dir = std::env::temp_dir().join("vorpal_config_profile_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, r#"# my settings
+layout = "webui"
+jobs = 2
+format = "safetensors"
+
+[aliases]
+sdxl = "~/sdxl"
+
+[profiles.comfy-workstation]
+layout = "comfyui"
+directory = "/opt/ComfyUI"
+
+[profiles.comfy-workstation.aliases]
+sd15 = "/opt/sd15"
+"#).unwrap();
+
+        let config = config::Config::load_from(&path).unwrap();
+        assert_eq!(config.get_profile(None).unwrap(), config.defaults);
+        let profile = config.get_profile(Some("comfy-workstation")).unwrap();
+        assert_eq!(profile.layout, Some(Layout::ComfyUi));
+        assert_eq!(profile.directory.as_deref(), Some("/opt/ComfyUI"));
+        assert_eq!(profile.jobs, Some(2));
+        assert_eq!(profile.format, Some(FileFormat::SafeTensor));
+        assert_eq!(profile.aliases.len(), 2);
+        assert!(matches!(config.get_profile(Some("laptop")), Err(VorpalError::Config { .. })));
+
+        config::set_value(&path, None, "jobs", "6").unwrap();
+        config::set_value(&path, Some("laptop"), "safe", "true").unwrap();
+        config::set_value(&path, None, "type_dirs.DoRA", "models/Lora").unwrap();
+        assert_eq!(config::get_value(&path, None, "jobs").unwrap().as_deref(), Some("6"));
+        assert_eq!(config::get_value(&path, Some("laptop"), "safe").unwrap().as_deref(), Some("true"));
+        assert_eq!(config::get_value(&path, Some("comfy-workstation"), "layout").unwrap().as_deref(), Some("comfyui"));
+        assert_eq!(config::get_value(&path, Some("laptop"), "layout").unwrap(), None);
+        // The file may hold the API token, so only its owner may read it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+            let fresh = dir.join("fresh.toml");
+            config::set_value(&fresh, None, "api_token", "secret").unwrap();
+            assert_eq!(std::fs::metadata(&fresh).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Bad keys and values are refused without touching the file
+        let before = std::fs::read_to_string(&path).unwrap();
+        assert!(config::set_value(&path, None, "layout", "invokeai").is_err());
+        assert!(config::set_value(&path, None, "jobs", "many").is_err());
+        assert!(config::set_value(&path, None, "colour", "blue").is_err());
+        assert!(config::get_value(&path, None, "aliases").is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+        assert!(before.starts_with("# my settings\n"));
+
+        let listed = config::list_values(&path).unwrap();
+        assert!(listed.contains(&("jobs".to_string(), "6".to_string())));
+        assert!(listed.contains(&("profiles.laptop.safe".to_string(), "true".to_string())));
+        assert!(listed.contains(&("type_dirs.DoRA".to_string(), "\"models/Lora\"".to_string())));
+        assert_eq!(config::Config::load_from(&path).unwrap().get_profile(Some("laptop")).unwrap().jobs, Some(6));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn library_test() {
+        let dir = std::env::temp_dir().join("vorpal_library_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let version: ModelVersion = serde_json::from_str(r#"{"id":264911,"modelId":235002,"name":"v1.0",
+            "trainedWords":["r3dglitter","shiny, sparkly"],"baseModel":"SDXL 1.0","model":{"name":"Red Glitter","type":"LORA"},
+            "files":[{"id":7,"sizeKB":1.0,"name":"red_glitter.safetensors","downloadUrl":"",
+                "hashes":{"SHA256":"ABCDEF"}}]}"#).unwrap();
+        let file = version.get_latest_file().unwrap();
+        let model = dir.join("red_glitter.safetensors");
+        std::fs::write(&model, b"weights").unwrap();
+        let sha256 = hash::sha256_bytes(b"weights");
+
+        let index = dir.join("library.json");
+        let mut library = Library::load_from(&index).unwrap();
+        assert!(library.get_entries().is_empty());
+        let mut entry = LibraryEntry::new(&version, &file, &model, &sha256);
+        assert_eq!(entry.file_id, "7");
+        assert_eq!(entry.model_type.as_deref(), Some("LORA"));
+        assert_eq!(entry.trained_words, vec!["r3dglitter", "shiny, sparkly"]);
+        assert!(entry.path.is_absolute());
+        library.record(entry.clone());
+        library.record(entry.clone());
+        library.save().unwrap();
+
+        let mut library = Library::load_from(&index).unwrap();
+        assert_eq!(library.get_entries(), [entry.clone()]);
+        assert_eq!(library.find_by_sha256(&sha256.to_uppercase()), Some(&entry));
+        assert_eq!(library.find_by_sha256("abcdef"), Some(&entry));
+        assert_eq!(library.find_by_version_id("264911").len(), 1);
+        assert_eq!(library.find_by_model_id("235002").len(), 1);
+        assert_eq!(library.find_by_path(&model), Some(&entry));
+        assert_eq!(library.remove(&model), Some(entry.clone()));
+        assert!(library.find_by_version_id("264911").is_empty());
+
+        entry.downloaded_at = 1_705_017_600;
+        assert_eq!(entry.get_date(), "2024-01-12");
+        assert_eq!(entry.get_summary(), "Red Glitter v1.0 | LORA | SDXL 1.0 | 2024-01-12");
+        std::fs::write(&index, b"{not json").unwrap();
+        assert!(matches!(Library::load_from(&index), Err(VorpalError::Library { .. })));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Serve body, honoring "range: bytes=a-b" headers if ranges is true.
+    /// Returns the base url and a count of the ranged requests made.
+    fn range_server(body: Vec<u8>, ranges: bool) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let ranged = std::sync::Arc::new(AtomicUsize::new(0));
+        let counter = ranged.clone();
+        let base = mock_server(move |head| {
+            let range = head.lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|r| r.split_once('-'))
+                .map(|(a, b)| (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap_or(body.len() - 1)));
+            match (range, ranges) {
+                (Some((start, end)), true) => {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let mut res = format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        start, end, body.len(), end - start + 1).into_bytes();
+                    res.extend_from_slice(&body[start..=end]);
+                    res
+                },
+                _ => http_response(200, "application/octet-stream", &body),
+            }
+        });
+        (base, ranged)
+    }
+
+    #[tokio::test]
+    // A large file should be fetched as several ranges and come out byte for byte the same
+    async fn segmented_download_test() {
+        use sha2::{Digest, Sha256};
+        let body: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 251) as u8).collect();
+        let sha256 = libvorpal::hash::to_hex(&Sha256::digest(&body));
+        let (base, ranged) = range_server(body.clone(), true);
+        let dir = std::env::temp_dir().join("vorpal_segmented_download_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.safetensors");
+
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let options = DownloadOptions::new().segments(4).expected_sha256(sha256.clone());
+        let url = format!("{}/api/download/models/1", base);
+        let hash = client.download_file_with_options(url, path.display().to_string(), &options).await.unwrap();
+        assert_eq!(hash, sha256);
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        // The probe plus one request per MiB of file
+        assert_eq!(ranged.load(std::sync::atomic::Ordering::SeqCst), 4);
+        assert!(!download::part_path(&path).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    // Servers that ignore Range still get the whole file, over one stream
+    async fn segmented_fallback_test() {
+        let body: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 7) as u8).collect();
+        let (base, ranged) = range_server(body.clone(), false);
+        let dir = std::env::temp_dir().join("vorpal_segmented_fallback_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.safetensors");
+
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let options = DownloadOptions::new().segments(4);
+        let url = format!("{}/api/download/models/1", base);
+        client.download_file_with_options(url, path.display().to_string(), &options).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(ranged.load(std::sync::atomic::Ordering::SeqCst), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Loads an OrderedDict, as a PyTorch state dict would
+    const SAFE_PICKLE: &[u8] = b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01.";
+    // os.system("ls"), with the names hidden behind the memo
+    const DANGEROUS_PICKLE: &[u8] = b"\x80\x04\x8c\x02os\x94\x8c\x06system\x94h\x00h\x01\x93\x8c\x02ls\x85R.";
+    // torch.storage._load_from_bytes(b"..."), which torch.loads its argument
+    const LOAD_FROM_BYTES_PICKLE: &[u8] = b"\x80\x03ctorch.storage\n_load_from_bytes\nB\x04\x00\x00\x00\x80\x02N.\x85R.";
+
+    #[test]
+    fn pickle_scan_test() {
+        let mut safe = std::io::Cursor::new(SAFE_PICKLE);
+        let imports = pickle::scan_pickle(&mut safe, "data.pkl").unwrap();
+        assert_eq!(imports.len(), 1);
+        assert!(imports[0].is_allowed());
+
+        let mut dangerous = std::io::Cursor::new(DANGEROUS_PICKLE);
+        let imports = pickle::scan_pickle(&mut dangerous, "data.pkl").unwrap();
+        assert_eq!((imports[0].module.as_str(), imports[0].name.as_str()), ("os", "system"));
+        assert!(!imports[0].is_allowed());
+
+        let mut nested = std::io::Cursor::new(LOAD_FROM_BYTES_PICKLE);
+        let imports = pickle::scan_pickle(&mut nested, "data.pkl").unwrap();
+        assert_eq!((imports[0].module.as_str(), imports[0].name.as_str()), ("torch.storage", "_load_from_bytes"));
+        assert!(!imports[0].is_allowed());
+
+        let mut truncated = std::io::Cursor::new(&DANGEROUS_PICKLE[..10]);
+        assert!(pickle::scan_pickle(&mut truncated, "data.pkl").is_err());
+    }
+
+    #[test]
+    // PyTorch zip checkpoints keep their pickle in <archive>/data.pkl
+    fn pickle_scan_zip_test() {
+        use std::io::Write;
+        let path = std::env::temp_dir().join("vorpal_pickle_scan_zip_test.ckpt");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let stored = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("archive/data.pkl", stored).unwrap();
+        zip.write_all(DANGEROUS_PICKLE).unwrap();
+        zip.start_file("archive/data/0", stored).unwrap();
+        zip.write_all(&[0u8; 16]).unwrap();
+        zip.finish().unwrap();
+
+        let report = pickle::scan_file(&path).unwrap();
+        assert_eq!(report.pickles, ["archive/data.pkl"]);
+        assert!(!report.is_safe());
+        assert_eq!(report.get_dangerous()[0].to_string(), "os.system (in archive/data.pkl)");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // OrderedDict({"a.weight": a 2x2 fp16 tensor}), stored transposed (stride 1, 2)
+    const CHECKPOINT_PICKLE: &[u8] = b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01(X\x08\x00\x00\x00a.weight\
+        ctorch._utils\n_rebuild_tensor_v2\n((X\x07\x00\x00\x00storagectorch\nHalfStorage\nX\x01\x00\x00\x000X\x03\x00\x00\x00cpuK\x04tQ\
+        K\x00K\x02K\x02\x86K\x01K\x02\x86\x89ccollections\nOrderedDict\n)RtRu.";
+
+    // As CHECKPOINT_PICKLE, but the tensor's shape is (2^31 - 1, 2^31 - 1, 2^31 - 1)
+    const OVERFLOW_PICKLE: &[u8] = b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01(X\x08\x00\x00\x00a.weight\
+        ctorch._utils\n_rebuild_tensor_v2\n((X\x07\x00\x00\x00storagectorch\nHalfStorage\nX\x01\x00\x00\x000X\x03\x00\x00\x00cpuK\x04tQ\
+        K\x00J\xff\xff\xff\x7fJ\xff\xff\xff\x7fJ\xff\xff\xff\x7f\x87K\x01K\x01K\x01\x87\x89ccollections\nOrderedDict\n)RtRu.";
+
+    // {"a": inner, "b": inner}, with inner filled with {"w": a tensor} only after both
+    // refer to it, the way pickle memoizes a dict before setting its items
+    const SHARED_DICT_PICKLE: &[u8] = b"\x80\x02}q\x00(X\x01\x00\x00\x00a}q\x01X\x01\x00\x00\x00bh\x01uh\x01(X\x01\x00\x00\x00w\
+        ctorch._utils\n_rebuild_tensor_v2\n((X\x07\x00\x00\x00storagectorch\nHalfStorage\nX\x01\x00\x00\x000X\x03\x00\x00\x00cpuK\x04tQ\
+        K\x00K\x02K\x02\x86K\x01K\x02\x86\x89ccollections\nOrderedDict\n)RtRu0.";
+
+    // {"self": the dict itself, "w": a tensor}
+    const CYCLIC_DICT_PICKLE: &[u8] = b"\x80\x02}q\x00(X\x04\x00\x00\x00selfh\x00X\x01\x00\x00\x00w\
+        ctorch._utils\n_rebuild_tensor_v2\n((X\x07\x00\x00\x00storagectorch\nHalfStorage\nX\x01\x00\x00\x000X\x03\x00\x00\x00cpuK\x04tQ\
+        K\x00K\x02K\x02\x86K\x01K\x02\x86\x89ccollections\nOrderedDict\n)RtRu.";
+
+    fn write_checkpoint(path: &std::path::Path, pickle: &[u8]) {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        let stored = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("archive/data.pkl", stored).unwrap();
+        zip.write_all(pickle).unwrap();
+        zip.start_file("archive/byteorder", stored).unwrap();
+        zip.write_all(b"little").unwrap();
+        zip.start_file("archive/data/0", stored).unwrap();
+        zip.write_all(&[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn convert_test() {
+        let dir = std::env::temp_dir().join("vorpal_convert_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (src, dest) = (dir.join("model.ckpt"), dir.join("model.safetensors"));
+        write_checkpoint(&src, CHECKPOINT_PICKLE);
+        let metadata = [("civitai_version_id".to_string(), "7".to_string())].into();
+        assert_eq!(convert::convert_checkpoint(&src, &dest, &metadata).unwrap(), 1);
+
+        let bytes = std::fs::read(&dest).unwrap();
+        let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+        assert_eq!(header_len % 8, 0);
+        let header: serde_json::Value = serde_json::from_slice(&bytes[8..8 + header_len]).unwrap();
+        assert_eq!(header["__metadata__"]["format"], "pt");
+        assert_eq!(header["__metadata__"]["civitai_version_id"], "7");
+        let tensor: safetensors::TensorInfo = serde_json::from_value(header["a.weight"].clone()).unwrap();
+        assert_eq!(tensor, safetensors::TensorInfo { dtype: "F16".to_string(), shape: vec![2, 2], data_offsets: [0, 8] });
+        // Written back in row major order
+        assert_eq!(&bytes[8 + header_len..], &[0, 1, 4, 5, 2, 3, 6, 7]);
+
+        // Converting onto the checkpoint itself is refused before it is touched
+        let before = std::fs::read(&src).unwrap();
+        let same = dir.join(".").join(src.file_name().unwrap());
+        let result = convert::convert_checkpoint(&src, &same, &Default::default());
+        assert!(matches!(result, Err(VorpalError::Convert { .. })));
+        assert_eq!(std::fs::read(&src).unwrap(), before);
+
+        // Anything that would run code is refused, and nothing is written
+        std::fs::remove_file(&dest).unwrap();
+        write_checkpoint(&src, DANGEROUS_PICKLE);
+        let result = convert::convert_checkpoint(&src, &dest, &Default::default());
+        assert!(matches!(result, Err(VorpalError::Convert { .. })));
+        assert!(!dest.exists());
+
+        // Long strings, like docstrings, are skipped rather than refused
+        let mut long_string = CHECKPOINT_PICKLE[..CHECKPOINT_PICKLE.len() - 2].to_vec();
+        long_string.extend(b"X\x03\x00\x00\x00docX");
+        long_string.extend(2000u32.to_le_bytes());
+        long_string.extend([b'x'; 2000]);
+        long_string.extend(b"u.");
+        write_checkpoint(&src, &long_string);
+        assert_eq!(convert::convert_checkpoint(&src, &dest, &Default::default()).unwrap(), 1);
+        std::fs::remove_file(&dest).unwrap();
+
+        // A dict memoized before it is filled is filled wherever it is used
+        write_checkpoint(&src, SHARED_DICT_PICKLE);
+        assert_eq!(convert::convert_checkpoint(&src, &dest, &Default::default()).unwrap(), 2);
+        let header = safetensors::read_header(&dest).unwrap();
+        assert_eq!(header.tensors.keys().collect::<Vec<_>>(), ["a.w", "b.w"]);
+        std::fs::remove_file(&dest).unwrap();
+        write_checkpoint(&src, CYCLIC_DICT_PICKLE);
+        assert_eq!(convert::convert_checkpoint(&src, &dest, &Default::default()).unwrap(), 1);
+        std::fs::remove_file(&dest).unwrap();
+
+        // Sizes that overflow are an error too, not a panic or a wrapped around size
+        write_checkpoint(&src, OVERFLOW_PICKLE);
+        let result = convert::convert_checkpoint(&src, &dest, &Default::default());
+        assert!(matches!(result, Err(VorpalError::Convert { message, .. }) if message.contains("too large")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    // A converted checkpoint goes through the collision policy like a download does
+    fn replace_with_safetensors_test() {
+        let dir = std::env::temp_dir().join("vorpal_replace_with_safetensors_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let version: ModelVersion = serde_json::from_str(r#"{"id":1,"modelId":1,"name":"v1","trainedWords":[],"files":[]}"#).unwrap();
+        let src = dir.join("model.ckpt");
+        std::fs::write(dir.join("model.safetensors"), b"someone else's model").unwrap();
+
+        write_checkpoint(&src, CHECKPOINT_PICKLE);
+        let result = crate::replace_with_safetensors(&src, &version, OnCollision::Fail);
+        assert!(matches!(result.unwrap_err().downcast_ref(), Some(VorpalError::FileExists { .. })));
+        assert!(src.exists());
+
+        let converted = crate::replace_with_safetensors(&src, &version, OnCollision::Rename).unwrap();
+        assert_eq!(converted, dir.join("model_1.safetensors"));
+        assert!(!src.exists());
+        assert_eq!(std::fs::read(dir.join("model.safetensors")).unwrap(), b"someone else's model");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn safetensors_header_test() {
+        let path = std::env::temp_dir().join("vorpal_safetensors_header_test.safetensors");
+        let metadata = [
+            ("ss_network_dim".to_string(), "32".to_string()),
+            ("ss_tag_frequency".to_string(), r#"{"1_cat": {"cat": 10, " sitting": 3}, "2_dog": {"sitting": 5}}"#.to_string()),
+            ("modelspec.title".to_string(), "Cat".to_string()),
+        ].into();
+        let tensors = [
+            ("lora_up.weight".to_string(), safetensors::TensorInfo { dtype: "F16".to_string(), shape: vec![4, 2], data_offsets: [0, 16] }),
+            ("lora_down.weight".to_string(), safetensors::TensorInfo { dtype: "F32".to_string(), shape: vec![2], data_offsets: [16, 24] }),
+        ];
+        let mut bytes = safetensors::encode_header(&tensors, &metadata).unwrap();
+        bytes.extend([0u8; 24]);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let header = safetensors::read_header(&path).unwrap();
+        assert_eq!(header.get_tensor_count(), 2);
+        assert_eq!(header.get_parameter_count(), Some(10));
+        assert_eq!(header.get_data_size(), 24);
+        assert_eq!(header.get_training_metadata().len(), 2);
+        assert_eq!(header.get_tag_frequency(), [("cat".to_string(), 10), ("sitting".to_string(), 8)]);
+        let report = header.generate_report();
+        assert!(report.contains(&"Data Types: F16 (1), F32 (1)".to_string()));
+        assert!(report.contains(&"    Network Dim: 32".to_string()));
+        assert!(report.contains(&"Top Tags: cat (10), sitting (8)".to_string()));
+        assert!(report.contains(&"    modelspec.title: Cat".to_string()));
+
+        // Shapes too large to count are left out of the report instead of overflowing
+        let huge = [("a".to_string(), safetensors::TensorInfo { dtype: "F16".to_string(), shape: vec![u64::MAX, 2], data_offsets: [0, 0] })];
+        std::fs::write(&path, safetensors::encode_header(&huge, &Default::default()).unwrap()).unwrap();
+        let huge_header = safetensors::read_header(&path).unwrap();
+        assert_eq!(huge_header.get_parameter_count(), None);
+        assert!(!huge_header.generate_report().iter().any(|field| field.starts_with("Parameters")));
+
+        // Missing tensor data is caught from the header alone
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(safetensors::read_header(&path), Err(VorpalError::InvalidSafetensors { .. })));
+        std::fs::write(&path, b"not a safetensors file").unwrap();
+        assert!(matches!(safetensors::read_header(&path), Err(VorpalError::InvalidSafetensors { .. })));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    // A downloaded pickle that would run code must not end up under its real name
+    async fn dangerous_pickle_download_test() {
+        let base = mock_server(|_| http_response(200, "application/octet-stream", DANGEROUS_PICKLE));
+        let dir = std::env::temp_dir().join("vorpal_dangerous_pickle_download_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.ckpt");
+        let file: ModelFile = serde_json::from_str(&format!(
+            r#"{{"id":1,"sizeKB":0.1,"name":"model.ckpt","downloadUrl":"{}/api/download/models/1"}}"#, base)).unwrap();
+
+        let client = CivitaiClient::builder().base_url(base).build().unwrap();
+        let result = client.download_model_file(&file, path.display().to_string(), &DownloadOptions::new()).await;
+        assert!(matches!(result, Err(VorpalError::DangerousPickle { path: Some(_), .. })));
+        assert!(!path.exists());
+        assert!(download::quarantine_path(&path).exists());
+
+        // Keep only applies to hash, size and format mismatches
+        std::fs::remove_file(download::quarantine_path(&path)).unwrap();
+        let keep = DownloadOptions::new().on_mismatch(MismatchAction::Keep);
+        let result = client.download_model_file(&file, path.display().to_string(), &keep).await;
+        assert!(matches!(result, Err(VorpalError::DangerousPickle { path: Some(_), .. })));
+        assert!(!path.exists());
+        assert!(download::quarantine_path(&path).exists());
+
+        let allowed = DownloadOptions::new().allow_dangerous_pickle(true);
+        client.download_model_file(&file, path.display().to_string(), &allowed).await.unwrap();
+        assert!(path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn progress_bar_test() {
+        let progress = Progress {
+            done: 512 * 1024 * 1024,
+            total: Some(1024 * 1024 * 1024),
+            bytes_per_sec: 1024.0 * 1024.0,
+            elapsed: std::time::Duration::from_secs(512),
+        };
+        let expected = "[###############---------------]  50% 512.00 MB / 1.00 GB  1.00 MB/s  ETA 8m 32s";
+        assert_eq!(crate::bar::render_bar(&progress), expected);
+    }
+
+    #[tokio::test]
+    async fn progress_observer_test() {
+        use std::sync::{Arc, Mutex};
+        let base = mock_server(|_| http_response(200, "application/octet-stream", &[7u8; 4096]));
+        let dir = std::env::temp_dir().join("vorpal_progress_observer_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.safetensors");
+
+        let last = Arc::new(Mutex::new(None));
+        let seen = last.clone();
+        let options = DownloadOptions::new()
+            .progress(Arc::new(move |p: &Progress| *seen.lock().unwrap() = Some(*p)));
+        let client = CivitaiClient::builder().base_url(base.clone()).build().unwrap();
+        let url = format!("{}/api/download/models/1", base);
+        client.download_file_with_options(url, path.display().to_string(), &options).await.unwrap();
+
+        let last = last.lock().unwrap().unwrap();
+        assert_eq!(last.done, 4096);
+        assert_eq!(last.total, Some(4096));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
     //TODO separate main/cli tests and lib tests
     #[cfg(test)]
     use crate::Args;
-    fn download_first_test() -> Result<(), Error> {
+    #[allow(dead_code)] // Reads from stdin, so it cannot run unattended
+    async fn download_first_test() -> Result<(), Error> {
         let model_name = "cat".to_string();
         let args = Args {
             model_name: Some(
@@ -104,15 +1204,40 @@ mod tests {
             ),
             get_first: false,
             directory: None,
+            create_dir: false,
             only_model: false,
             meta: false,
             query: None,
             count: 15,
-            safe: false,
+            safe: None,
             full: false,
             url: None,
+            types: vec![],
+            sort: None,
+            period: None,
+            base_model: vec![],
+            tag: None,
+            username: None,
+            favorites: false,
+            token: None,
+            on_mismatch: MismatchAction::Quarantine,
+            print_version: None,
+            version: None,
+            format: None,
+            precision: None,
+            size: None,
+            file_type: None,
+            allow_pickle: false,
+            allow_unsafe: false,
+            to_safetensors: false,
+            filename_template: None,
+            on_collision: None,
+            layout: None,
+            profile: None,
+            segments: 1,
+            command: None,
         };
-        run(args)
+        run(args).await
         //assert_eq!(result, Ok(()));
         //let query = download(model_name, count, safe);
         //let len = query.len();